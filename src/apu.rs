@@ -0,0 +1,74 @@
+//! The sound registers, NR10 to NR52 and wave RAM.
+//!
+//! No audio is produced yet. This keeps the register file behaving the way
+//! software expects: write-only bits read back as 1, and powering the APU off
+//! through NR52 clears every register and ignores writes until it is powered
+//! on again.
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Bits that always read as 1, for 0xFF10 to 0xFF2F.
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+#[derive(Clone, Debug, Default)]
+pub struct Apu {
+    registers: [u8; 0x20],
+    wave_ram: [u8; 0x10],
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu::default()
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF10..=0xFF2F => {
+                let index = (addr - 0xFF10) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            _ => self.wave_ram[(addr - 0xFF30) as usize],
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF26 => {
+                if value & 0x80 == 0 {
+                    self.registers = [0; 0x20];
+                } else {
+                    // The low bits report channel status and are read-only.
+                    // No channel ever runs, so they stay clear.
+                    self.registers[0x16] = 0x80;
+                }
+            }
+            0xFF10..=0xFF2F => {
+                if self.powered() {
+                    self.registers[(addr - 0xFF10) as usize] = value;
+                }
+            }
+            _ => self.wave_ram[(addr - 0xFF30) as usize] = value,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.registers);
+        w.bytes(&self.wave_ram);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.registers)?;
+        r.bytes(&mut self.wave_ram)
+    }
+
+    fn powered(&self) -> bool {
+        self.registers[0x16] & 0x80 != 0
+    }
+}
@@ -0,0 +1,230 @@
+//! Battery-backed saves on disk.
+//!
+//! A [`BatterySave`] ties a cartridge's battery data to a `.sav` file next to
+//! the ROM. The file is only rewritten when the game has written to the
+//! cartridge since and the data differs, so a running clock alone, whose
+//! trailer changes every second, does not rewrite it. Writes always go
+//! through a temporary file that is renamed over the old one, so a crash
+//! mid-write leaves the previous save intact.
+//!
+//! Files written by other emulators are accepted as long as the cartridge's
+//! controller can make sense of them: short files fill RAM from the start,
+//! padding past the end of RAM is dropped, and clock trailers are found
+//! from the end of the file.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::Cartridge;
+
+pub struct BatterySave {
+    path: PathBuf,
+    /// What the file on disk holds, as far as we know.
+    written: Option<Vec<u8>>,
+    /// The cartridge's [`Cartridge::save_generation`] as of `written`.
+    generation: u64,
+}
+
+impl BatterySave {
+    /// The save file conventionally used for the ROM at `rom`.
+    pub fn path_for<P: AsRef<Path>>(rom: P) -> PathBuf {
+        rom.as_ref().with_extension("sav")
+    }
+
+    /// Loads `path` into `cartridge`, if the file exists. Returns `None` for
+    /// cartridges without a battery.
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        cartridge: &mut Cartridge,
+    ) -> io::Result<Option<BatterySave>> {
+        if cartridge.save_data().is_none() {
+            return Ok(None);
+        }
+        let path = path.into();
+        let written = match fs::read(&path) {
+            Ok(data) => {
+                cartridge.load_save_data(&data);
+                Some(data)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        Ok(Some(BatterySave {
+            path,
+            written,
+            generation: cartridge.save_generation(),
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the cartridge's battery data out if the game has written to
+    /// the cartridge since it was last loaded or written, and the data
+    /// differs. Without a file yet, any data is written. Returns whether
+    /// the file was rewritten.
+    pub fn flush(&mut self, cartridge: &Cartridge) -> io::Result<bool> {
+        let generation = cartridge.save_generation();
+        if self.written.is_some() && generation == self.generation {
+            return Ok(false);
+        }
+        let data = match cartridge.save_data() {
+            Some(data) => data,
+            None => return Ok(false),
+        };
+        if self.written.as_ref() == Some(&data) {
+            self.generation = generation;
+            return Ok(false);
+        }
+        write_atomic(&self.path, &data)?;
+        self.written = Some(data);
+        self.generation = generation;
+        Ok(true)
+    }
+}
+
+/// Replaces the contents of `path` with `data` in a single step: the data
+/// goes to a temporary file in the same directory, which is synced and then
+/// renamed over `path`.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp_name = path.file_name().map(OsString::from).unwrap_or_default();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::rtc::ManualClock;
+    use crate::test_rom;
+
+    /// A directory of its own under the system's temporary directory,
+    /// removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("rustboy-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn files(&self) -> usize {
+            fs::read_dir(&self.0).unwrap().count()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// An 8 KiB RAM cartridge of `cartridge_type`, with RAM enabled.
+    fn cartridge(cartridge_type: u8, clock: ManualClock) -> Cartridge {
+        let rom = test_rom::build(cartridge_type, 0x02, &[], &[]);
+        let mut cartridge = Cartridge::with_clock(rom, Box::new(clock)).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge
+    }
+
+    #[test]
+    fn write_atomic_replaces_the_file() {
+        let dir = TempDir::new("write-atomic");
+        let path = dir.0.join("game.sav");
+        fs::write(&path, b"old").unwrap();
+        write_atomic(&path, b"new data").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new data");
+        assert_eq!(dir.files(), 1);
+
+        let missing = dir.0.join("missing").join("game.sav");
+        assert!(write_atomic(&missing, b"data").is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"new data");
+        assert_eq!(dir.files(), 1);
+    }
+
+    #[test]
+    fn open_loads_saves_of_any_size() {
+        let dir = TempDir::new("open");
+        let path = dir.0.join("game.sav");
+        let mut rom_only = cartridge(0x00, ManualClock::new(0));
+        assert!(BatterySave::open(&path, &mut rom_only).unwrap().is_none());
+
+        // Without a file, the first flush creates one.
+        let mut cart = cartridge(0x03, ManualClock::new(0));
+        let mut save = BatterySave::open(&path, &mut cart).unwrap().unwrap();
+        assert_eq!(save.path(), path);
+        assert!(save.flush(&cart).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), vec![0; 0x2000]);
+
+        // A short file fills RAM from the start and stays as it is until
+        // the game writes.
+        fs::write(&path, [1, 2, 3]).unwrap();
+        let mut cart = cartridge(0x03, ManualClock::new(0));
+        let mut save = BatterySave::open(&path, &mut cart).unwrap().unwrap();
+        assert_eq!(
+            [cart.read(0xA000), cart.read(0xA002), cart.read(0xA003)],
+            [1, 3, 0]
+        );
+        assert!(!save.flush(&cart).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), [1, 2, 3]);
+        cart.write(0xA001, 0x20);
+        assert!(save.flush(&cart).unwrap());
+        let data = fs::read(&path).unwrap();
+        assert_eq!((data.len(), &data[..4]), (0x2000, &[1, 0x20, 3, 0][..]));
+        assert_eq!(dir.files(), 1);
+    }
+
+    #[test]
+    fn flush_skips_unchanged_ram() {
+        let dir = TempDir::new("flush");
+        let path = dir.0.join("game.sav");
+        let mut cart = cartridge(0x03, ManualClock::new(0));
+        let mut save = BatterySave::open(&path, &mut cart).unwrap().unwrap();
+        assert!(save.flush(&cart).unwrap());
+        assert!(!save.flush(&cart).unwrap());
+        // Writing what RAM already holds changes nothing.
+        cart.write(0xA000, 0x00);
+        assert!(!save.flush(&cart).unwrap());
+        cart.write(0xA000, 0x42);
+        assert!(save.flush(&cart).unwrap());
+        assert_eq!(fs::read(&path).unwrap()[0], 0x42);
+    }
+
+    #[test]
+    fn a_running_clock_does_not_rewrite_the_save() {
+        let dir = TempDir::new("clock");
+        let path = dir.0.join("game.sav");
+        let clock = ManualClock::new(1000);
+        let mut cart = cartridge(0x10, clock.clone());
+        let mut save = BatterySave::open(&path, &mut cart).unwrap().unwrap();
+        assert!(save.flush(&cart).unwrap());
+        clock.advance(10);
+        assert!(!save.flush(&cart).unwrap());
+
+        // Setting the clock is a change like any RAM write.
+        cart.write(0x4000, 0x08);
+        cart.write(0xA000, 30);
+        assert!(save.flush(&cart).unwrap());
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 0x2000 + 48);
+        assert_eq!(data[0x2000], 30);
+        assert_eq!(data[0x2000 + 40..], 1010u64.to_le_bytes());
+    }
+}
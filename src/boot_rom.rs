@@ -0,0 +1,80 @@
+//! Boot ROM images, for running the real startup sequence.
+//!
+//! At power-on every model runs a small program from its own ROM: it
+//! scrolls the logo, checks the cartridge header and sets up the hardware,
+//! then unmaps itself by writing FF50 and falls through to the cartridge
+//! entry point at 0x0100. The DMG, MGB and SGB ROMs are 256 bytes at
+//! 0x0000-0x00FF. The CGB and AGB ones are 2304 bytes, mapped at
+//! 0x0000-0x00FF and 0x0200-0x08FF with the cartridge header showing
+//! through in between.
+//!
+//! rustboy does not ship any boot ROM. Without one, a machine starts in the
+//! state the boot ROM would have left it in; see [`crate::model`].
+
+use std::fmt;
+
+use crate::model::Model;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BootRomError {
+    /// The image is not the size of `model`'s boot ROM.
+    WrongSize { model: Model, len: usize },
+}
+
+impl fmt::Display for BootRomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootRomError::WrongSize { model, len } => write!(
+                f,
+                "{:?} boot ROMs are {} bytes, not {}",
+                model,
+                BootRom::size(*model),
+                len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BootRomError {}
+
+#[derive(Clone)]
+pub struct BootRom {
+    model: Model,
+    data: Vec<u8>,
+}
+
+impl BootRom {
+    /// Checks that `data` is the size of `model`'s boot ROM.
+    pub fn new(model: Model, data: Vec<u8>) -> Result<BootRom, BootRomError> {
+        if data.len() != BootRom::size(model) {
+            return Err(BootRomError::WrongSize {
+                model,
+                len: data.len(),
+            });
+        }
+        Ok(BootRom { model, data })
+    }
+
+    /// The size of `model`'s boot ROM in bytes.
+    pub fn size(model: Model) -> usize {
+        if model.is_cgb() {
+            0x0900
+        } else {
+            0x0100
+        }
+    }
+
+    /// The model this boot ROM belongs to.
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// The byte at `addr` while the boot ROM is mapped, or `None` where the
+    /// cartridge shows through.
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0100..=0x01FF => None,
+            _ => self.data.get(usize::from(addr)).copied(),
+        }
+    }
+}
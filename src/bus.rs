@@ -0,0 +1,769 @@
+//! The memory bus: routes CPU accesses to the region behind each address and
+//! advances the rest of the hardware one machine cycle per access.
+//!
+//! CGB models can double the CPU clock through KEY1 and `STOP`. The timer,
+//! serial port and OAM DMA run off the CPU clock and speed up with it; the
+//! PPU, sound and VRAM DMA keep their pace, and see a machine cycle as half
+//! as long.
+//!
+//! A machine started from power-on has its boot ROM over the start of the
+//! cartridge until the boot ROM writes FF50.
+//!
+//! | Range         | Region                   |
+//! |---------------|--------------------------|
+//! | 0x0000-0x3FFF | ROM bank 0               |
+//! | 0x4000-0x7FFF | Switchable ROM bank      |
+//! | 0x8000-0x9FFF | VRAM                     |
+//! | 0xA000-0xBFFF | External (cartridge) RAM |
+//! | 0xC000-0xCFFF | WRAM bank 0              |
+//! | 0xD000-0xDFFF | WRAM bank 1 (1-7 on CGB) |
+//! | 0xE000-0xFDFF | Echo of 0xC000-0xDDFF    |
+//! | 0xFE00-0xFE9F | OAM                      |
+//! | 0xFEA0-0xFEFF | Unusable                 |
+//! | 0xFF00-0xFF7F | I/O registers            |
+//! | 0xFF80-0xFFFE | HRAM                     |
+//! | 0xFFFF        | IE                       |
+
+use crate::apu::Apu;
+use crate::boot_rom::BootRom;
+use crate::cartridge::Cartridge;
+use crate::cpu::Memory;
+use crate::hdma::{Hdma, BLOCK_SIZE};
+use crate::interrupt::Interrupt;
+use crate::joypad::{Button, Joypad};
+use crate::model::Model;
+use crate::ppu::{CompatPalettes, Ppu};
+use crate::serial::Serial;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::timer::Timer;
+
+/// Machine cycles between a write to DMA and the first byte moving.
+const DMA_STARTUP: u8 = 1;
+/// Bytes an OAM DMA transfer copies, one per machine cycle.
+const DMA_LENGTH: u8 = 0xA0;
+
+/// An OAM DMA transfer in progress.
+#[derive(Clone, Copy, Debug)]
+struct OamDma {
+    source: u16,
+    /// Bytes copied so far.
+    index: u8,
+}
+
+pub struct Bus {
+    model: Model,
+    cartridge: Cartridge,
+    boot_rom: Option<BootRom>,
+    /// The boot ROM covers the start of the cartridge until FF50 is
+    /// written.
+    boot_rom_mapped: bool,
+    /// Eight 4 KiB banks. DMG models only have the first two.
+    wram: [u8; 0x8000],
+    /// SVBK, which picks the WRAM bank at 0xD000 on CGB models.
+    svbk: u8,
+    hram: [u8; 0x7F],
+    /// The last value written to DMA.
+    dma: u8,
+    dma_transfer: Option<OamDma>,
+    /// A transfer asked for by writing DMA, with the cycles until it
+    /// starts. It replaces any transfer still running.
+    dma_request: Option<(u16, u8)>,
+    /// The byte the transfer moved last, which the CPU sees in place of
+    /// whatever it tries to read.
+    dma_byte: u8,
+    ie: u8,
+    /// IF. Only the low five bits exist.
+    int_flags: u8,
+    hdma: Hdma,
+    /// The CPU runs at double speed.
+    double_speed: bool,
+    /// KEY1 bit 0: the next `STOP` switches speed.
+    speed_switch_armed: bool,
+    pub timer: Timer,
+    pub serial: Serial,
+    pub joypad: Joypad,
+    pub apu: Apu,
+    pub ppu: Ppu,
+}
+
+impl Bus {
+    pub fn new(model: Model, cartridge: Cartridge) -> Bus {
+        Bus {
+            model,
+            cartridge,
+            boot_rom: None,
+            boot_rom_mapped: false,
+            wram: [0; 0x8000],
+            svbk: 0,
+            hram: [0; 0x7F],
+            dma: 0,
+            dma_transfer: None,
+            dma_request: None,
+            dma_byte: 0xFF,
+            ie: 0,
+            int_flags: 0,
+            hdma: Hdma::new(),
+            double_speed: false,
+            speed_switch_armed: false,
+            timer: Timer::new(),
+            serial: Serial::new(),
+            joypad: Joypad::new(),
+            apu: Apu::new(),
+            ppu: Ppu::new(model),
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    /// Maps `boot_rom` over the start of the cartridge, to run from
+    /// power-on.
+    pub fn map_boot_rom(&mut self, boot_rom: BootRom) {
+        self.boot_rom = Some(boot_rom);
+        self.boot_rom_mapped = true;
+    }
+
+    /// Whether the boot ROM is still running.
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    /// Puts the I/O registers and the divider in the state the boot ROM
+    /// leaves them in. A CGB model given a DMG cartridge also goes into
+    /// DMG compatibility mode, with the palettes the boot ROM picks for it.
+    pub fn apply_post_boot(&mut self) {
+        for (addr, value) in self.model.io_registers() {
+            self.write_byte(addr, value);
+        }
+        self.timer.set_counter(self.model.div_counter());
+        if self.model.is_cgb() && !self.cartridge.header().supports_cgb() {
+            let palettes = CompatPalettes::for_header(self.cartridge.header());
+            self.ppu.enter_dmg_compat();
+            self.ppu.set_compat_palettes(&palettes);
+        }
+    }
+
+    /// Advances the hardware by one machine cycle.
+    pub fn tick(&mut self) {
+        self.tick_dma();
+        if self.timer.tick() {
+            self.request_interrupt(Interrupt::Timer);
+        }
+        if self.serial.tick() {
+            self.request_interrupt(Interrupt::Serial);
+        }
+        self.ppu.tick(if self.double_speed { 2 } else { 4 });
+        self.int_flags |= self.ppu.take_interrupts();
+        if self.ppu.take_hblank_started() {
+            self.hdma.hblank_started();
+        }
+    }
+
+    /// Moves the VRAM DMA blocks that are due, which stalls the CPU for 8
+    /// machine cycles each, or 16 at double speed. Returns the number of
+    /// clocks taken, 0 if there was nothing to move.
+    pub fn run_hdma(&mut self) -> u32 {
+        let (bytes_per_cycle, clocks_per_cycle) = if self.double_speed { (1, 2) } else { (2, 4) };
+        let mut clocks = 0;
+        while let Some((source, dest)) = self.hdma.next_block() {
+            for i in (0..BLOCK_SIZE).step_by(bytes_per_cycle) {
+                self.tick();
+                clocks += clocks_per_cycle;
+                for offset in i..i + bytes_per_cycle as u16 {
+                    let value = self.read_byte(source.wrapping_add(offset));
+                    self.ppu.write_vram(0x8000 + dest + offset, value);
+                }
+            }
+        }
+        clocks
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.int_flags |= interrupt.mask();
+    }
+
+    pub fn press(&mut self, button: Button) {
+        if self.joypad.press(button) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.joypad.release(button);
+    }
+
+    /// Presses and releases buttons to match a [`Joypad::pressed`] mask.
+    pub fn set_pressed(&mut self, mask: u8) {
+        for (i, &button) in Button::ALL.iter().enumerate() {
+            if mask & (1 << i) != 0 {
+                self.press(button);
+            } else {
+                self.release(button);
+            }
+        }
+    }
+
+    /// Tilts the console for cartridges with an accelerometer, in g along
+    /// each axis. Positive X is right and positive Y is down.
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.cartridge.set_tilt(x, y);
+    }
+
+    /// Writes the memory and registers the bus owns itself. The components
+    /// it holds are saved separately.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.wram);
+        w.bytes(&self.hram);
+        w.u8(self.dma);
+        match self.dma_transfer {
+            Some(transfer) => {
+                w.bool(true);
+                w.u16(transfer.source);
+                w.u8(transfer.index);
+            }
+            None => w.bool(false),
+        }
+        match self.dma_request {
+            Some((source, delay)) => {
+                w.bool(true);
+                w.u16(source);
+                w.u8(delay);
+            }
+            None => w.bool(false),
+        }
+        w.u8(self.dma_byte);
+        w.u8(self.ie);
+        w.u8(self.int_flags);
+        w.u8(self.svbk);
+        self.hdma.save_state(w);
+        w.bool(self.double_speed);
+        w.bool(self.speed_switch_armed);
+        w.bool(self.boot_rom_mapped);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.wram)?;
+        r.bytes(&mut self.hram)?;
+        self.dma = r.u8()?;
+        self.dma_transfer = if r.bool()? {
+            let source = r.u16()?;
+            let index = r.u8()?;
+            if index > DMA_LENGTH {
+                return Err(StateError::Invalid("OAM DMA position"));
+            }
+            Some(OamDma { source, index })
+        } else {
+            None
+        };
+        self.dma_request = if r.bool()? {
+            Some((r.u16()?, r.u8()?))
+        } else {
+            None
+        };
+        self.dma_byte = r.u8()?;
+        self.ie = r.u8()?;
+        self.int_flags = r.u8()? & 0x1F;
+        self.svbk = r.u8()? & 0x07;
+        self.hdma.load_state(r)?;
+        self.double_speed = r.bool()?;
+        self.speed_switch_armed = r.bool()?;
+        self.boot_rom_mapped = r.bool()?;
+        if self.boot_rom_mapped && self.boot_rom.is_none() {
+            return Err(StateError::Invalid("boot ROM not supplied"));
+        }
+        Ok(())
+    }
+
+    /// Reads a byte without advancing the hardware.
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => self.read_rom(addr),
+            0x8000..=0x9FFF => self.ppu.read_vram(addr),
+            0xA000..=0xBFFF => self.cartridge.read(addr),
+            0xC000..=0xFDFF => self.wram[self.wram_index(addr)],
+            0xFE00..=0xFE9F => self.ppu.read_oam(addr),
+            0xFEA0..=0xFEFF => self.read_unusable(addr),
+            0xFF00..=0xFF7F => self.read_io(addr),
+            0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize],
+            0xFFFF => self.ie,
+        }
+    }
+
+    /// Writes a byte without advancing the hardware.
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x7FFF => self.cartridge.write(addr, value),
+            0x8000..=0x9FFF => self.ppu.write_vram(addr, value),
+            0xA000..=0xBFFF => self.cartridge.write(addr, value),
+            0xC000..=0xFDFF => self.wram[self.wram_index(addr)] = value,
+            0xFE00..=0xFE9F => self.ppu.write_oam(addr, value),
+            0xFEA0..=0xFEFF => {}
+            0xFF00..=0xFF7F => self.write_io(addr, value),
+            0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize] = value,
+            0xFFFF => self.ie = value,
+        }
+    }
+
+    /// The boot ROM while it is mapped and covers `addr`, the cartridge
+    /// otherwise.
+    fn read_rom(&self, addr: u16) -> u8 {
+        match &self.boot_rom {
+            Some(boot_rom) if self.boot_rom_mapped => boot_rom
+                .read(addr)
+                .unwrap_or_else(|| self.cartridge.read(addr)),
+            _ => self.cartridge.read(addr),
+        }
+    }
+
+    /// Where `addr` in 0xC000-0xFDFF is in WRAM.
+    fn wram_index(&self, addr: u16) -> usize {
+        let bank = if addr & 0x1000 == 0 {
+            0
+        } else if self.model.is_cgb() {
+            // Bank 0 cannot be mapped here; asking for it gets bank 1.
+            usize::from(self.svbk).max(1)
+        } else {
+            1
+        };
+        bank * 0x1000 + (addr & 0x0FFF) as usize
+    }
+
+    /// DMG models read zero here. CGB models repeat the high nibble of the
+    /// low address byte.
+    fn read_unusable(&self, addr: u16) -> u8 {
+        if self.model.is_cgb() {
+            let nibble = (addr as u8) >> 4;
+            nibble << 4 | nibble
+        } else {
+            0x00
+        }
+    }
+
+    fn read_io(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF00 => self.joypad.read(),
+            0xFF01..=0xFF02 => self.serial.read(addr),
+            0xFF04..=0xFF07 => self.timer.read(addr),
+            0xFF0F => 0xE0 | self.int_flags,
+            0xFF10..=0xFF3F => self.apu.read(addr),
+            0xFF46 => self.dma,
+            0xFF4D if self.ppu.cgb_mode() => {
+                (self.double_speed as u8) << 7 | 0x7E | self.speed_switch_armed as u8
+            }
+            0xFF40..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => self.ppu.read(addr),
+            0xFF51..=0xFF55 if self.ppu.cgb_mode() => self.hdma.read(addr),
+            0xFF70 if self.ppu.cgb_mode() => 0xF8 | self.svbk,
+            _ => 0xFF,
+        }
+    }
+
+    fn write_io(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF00 => self.joypad.write(value),
+            0xFF01..=0xFF02 => self.serial.write(addr, value),
+            0xFF04..=0xFF07 => self.timer.write(addr, value),
+            0xFF0F => self.int_flags = value & 0x1F,
+            0xFF10..=0xFF3F => self.apu.write(addr, value),
+            0xFF46 => {
+                self.dma = value;
+                // Sources at 0xE000 and up read WRAM, as echo RAM does.
+                let page = if value >= 0xE0 { value - 0x20 } else { value };
+                self.dma_request = Some((u16::from(page) << 8, DMA_STARTUP));
+            }
+            0xFF40..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => {
+                self.ppu.write(addr, value);
+                self.int_flags |= self.ppu.take_interrupts();
+            }
+            0xFF51..=0xFF55 if self.ppu.cgb_mode() => {
+                let hblank = self.ppu.in_hblank();
+                self.hdma.write(addr, value, hblank);
+            }
+            // KEY0, which only the CGB boot ROM can write, puts DMG
+            // cartridges in compatibility mode.
+            0xFF4C if self.model.is_cgb() && self.boot_rom_mapped && value & 0x04 != 0 => {
+                self.ppu.enter_dmg_compat();
+            }
+            0xFF4D if self.ppu.cgb_mode() => self.speed_switch_armed = value & 0x01 != 0,
+            // Once unmapped, the boot ROM stays out of reach until reset.
+            0xFF50 if value != 0 => self.boot_rom_mapped = false,
+            0xFF70 if self.ppu.cgb_mode() => self.svbk = value & 0x07,
+            _ => {}
+        }
+    }
+
+    /// Moves one byte of a running OAM DMA transfer, and starts a
+    /// requested one once its startup delay is over. A transfer is seen as
+    /// running for the 160 cycles it moves bytes in.
+    fn tick_dma(&mut self) {
+        if let Some((source, delay)) = self.dma_request {
+            if delay == 0 {
+                self.dma_transfer = Some(OamDma { source, index: 0 });
+                self.dma_request = None;
+            } else {
+                self.dma_request = Some((source, delay - 1));
+            }
+        }
+        if let Some(transfer) = &mut self.dma_transfer {
+            if transfer.index == DMA_LENGTH {
+                self.dma_transfer = None;
+                return;
+            }
+            let index = transfer.index;
+            let source = transfer.source + u16::from(index);
+            transfer.index += 1;
+            self.dma_byte = self.read_byte(source);
+            self.ppu.dma_write_oam(index, self.dma_byte);
+        }
+    }
+
+    /// Whether a running OAM DMA transfer keeps the CPU from `addr`. Only
+    /// the I/O registers, HRAM and IE stay in reach.
+    fn dma_blocks(&self, addr: u16) -> bool {
+        self.dma_transfer.is_some() && addr < 0xFF00
+    }
+}
+
+impl Memory for Bus {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.tick();
+        if self.dma_blocks(addr) {
+            // OAM is busy, and everything else is on the bus the transfer
+            // is using.
+            return match addr {
+                0xFE00..=0xFEFF => 0xFF,
+                _ => self.dma_byte,
+            };
+        }
+        self.read_byte(addr)
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.tick();
+        if !self.dma_blocks(addr) {
+            self.write_byte(addr, value);
+        }
+    }
+
+    fn idle(&mut self) {
+        self.tick();
+    }
+
+    fn stop(&mut self) -> bool {
+        // STOP resets DIV whatever else it does.
+        self.timer.write(0xFF04, 0);
+        if !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.ie & self.int_flags & 0x1F
+    }
+
+    fn acknowledge_interrupt(&mut self, bit: u8) {
+        self.int_flags &= !(1 << bit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::GameBoy;
+    use crate::ppu::Mode;
+    use crate::test_rom;
+
+    /// Turns the LCD off so the PPU leaves OAM alone, fills 0xC100-0xC19F
+    /// with 0x40, 0x41, ..., copies the routine at 0x0300 to HRAM and calls
+    /// it with HL = 0xFE00, then spins. Everything the routine does has to
+    /// happen from HRAM, the only memory a transfer leaves the CPU, and it
+    /// has to outlast the transfer before returning.
+    fn run_routine(routine: &[u8]) -> GameBoy {
+        assert!(routine.len() <= 0x70);
+        let code = [
+            0xAF, 0xE0, 0x40, // XOR A; LDH (LCDC),A
+            0x21, 0x00, 0xC1, // LD HL,0xC100
+            0x06, 0xA0, // LD B,0xA0
+            0x3E, 0x40, // LD A,0x40
+            0x22, 0x3C, 0x05, 0x20, 0xFB, // loop: LD (HL+),A; INC A; DEC B; JR NZ,loop
+            0x21, 0x00, 0x03, // LD HL,0x0300
+            0x0E, 0x80, // LD C,0x80
+            0x06, 0x70, // LD B,0x70
+            0x2A, 0xE2, 0x0C, 0x05, 0x20,
+            0xFA, // loop: LD A,(HL+); LDH (C),A; INC C; DEC B; JR NZ,loop
+            0x21, 0x00, 0xFE, // LD HL,0xFE00
+            0xCD, 0x80, 0xFF, // CALL 0xFF80
+            0x18, 0xFE, // JR -2
+        ];
+        let rom = test_rom::build(0x00, 0x00, &code, &[(0x0300, routine)]);
+        let mut gb = GameBoy::new(Model::Dmg, Cartridge::new(rom).unwrap());
+        for _ in 0..5000 {
+            gb.step();
+        }
+        gb
+    }
+
+    /// Starts a transfer from 0xC100 on cycle W and reads OAM on cycle
+    /// W + 159 + `nops`.
+    fn read_oam_after(nops: usize) -> u8 {
+        let mut routine = vec![
+            0x3E, 0xC1, 0xE0, 0x46, // LD A,0xC1; LDH (DMA),A
+            0x06, 0x27, 0x05, 0x20, 0xFD, // LD B,39; loop: DEC B; JR NZ,loop
+        ];
+        routine.resize(routine.len() + nops, 0x00);
+        routine.extend(&[
+            0x7E, // LD A,(HL)
+            0x0E, 0x28, 0x0D, 0x20, 0xFD, // LD C,40; loop: DEC C; JR NZ,loop
+            0xC9, // RET
+        ]);
+        run_routine(&routine).cpu.regs.a
+    }
+
+    #[test]
+    fn oam_is_busy_until_the_transfer_ends() {
+        // Bytes move on W + 2 through W + 161, and OAM is back on W + 162.
+        assert_eq!(read_oam_after(2), 0xFF);
+        assert_eq!(read_oam_after(3), 0x40);
+    }
+
+    #[test]
+    fn reads_see_the_byte_being_moved() {
+        let routine = [
+            0x3E, 0xC1, 0xE0, 0x46, // LD A,0xC1; LDH (DMA),A
+            0xFA, 0x50, 0x01, // LD A,(0x0150)
+            0x0E, 0x28, 0x0D, 0x20, 0xFD, // LD C,40; loop: DEC C; JR NZ,loop
+            0xC9, // RET
+        ];
+        // The read of ROM lands on W + 4, as the third byte moves.
+        assert_eq!(run_routine(&routine).cpu.regs.a, 0x42);
+    }
+
+    #[test]
+    fn hram_stays_in_reach() {
+        let routine = [
+            0x3E, 0x99, 0xE0, 0xF0, // LD A,0x99; LDH (0xF0),A
+            0x3E, 0xC1, 0xE0, 0x46, // LD A,0xC1; LDH (DMA),A
+            0xAF, 0xF0, 0xF0, // XOR A; LDH A,(0xF0)
+            0x0E, 0x28, 0x0D, 0x20, 0xFD, // LD C,40; loop: DEC C; JR NZ,loop
+            0xC9, // RET
+        ];
+        let gb = run_routine(&routine);
+        assert_eq!(gb.cpu.regs.a, 0x99);
+        // And the whole transfer made it into OAM.
+        for i in 0..0xA0 {
+            assert_eq!(gb.bus.ppu.read_oam(0xFE00 + i), 0x40 + i as u8);
+        }
+    }
+
+    fn dmg_bus() -> Bus {
+        let rom = test_rom::build(0x00, 0x00, &[], &[]);
+        Bus::new(Model::Dmg, Cartridge::new(rom).unwrap())
+    }
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut bus = dmg_bus();
+        bus.write_byte(0xC123, 0x11);
+        bus.write_byte(0xFDFF, 0x22);
+        assert_eq!(bus.read_byte(0xE123), 0x11);
+        assert_eq!(bus.read_byte(0xDDFF), 0x22);
+    }
+
+    #[test]
+    fn the_unusable_region_ignores_writes() {
+        let mut bus = dmg_bus();
+        for addr in 0xFEA0..=0xFEFF {
+            bus.write_byte(addr, 0x5A);
+            assert_eq!(bus.read_byte(addr), 0x00, "{:#06x}", addr);
+        }
+        // The CGB repeats the upper address nibble.
+        let bus = cgb_bus();
+        assert_eq!(bus.read_byte(0xFEA0), 0xAA);
+        assert_eq!(bus.read_byte(0xFEC7), 0xCC);
+        assert_eq!(bus.read_byte(0xFEFF), 0xFF);
+    }
+
+    #[test]
+    fn ie_is_a_full_byte_apart_from_if() {
+        let mut bus = dmg_bus();
+        bus.write_byte(0xFFFF, 0xFF);
+        bus.write_byte(0xFF0F, 0x00);
+        assert_eq!(bus.read_byte(0xFFFF), 0xFF);
+        assert_eq!(bus.read_byte(0xFF0F), 0xE0);
+        bus.write_byte(0xFFFF, 0x05);
+        assert_eq!(bus.read_byte(0xFFFF), 0x05);
+        assert_eq!(bus.read_byte(0xFFFE), 0x00);
+    }
+
+    #[test]
+    fn unmapped_io_reads_open_bus() {
+        let mut bus = dmg_bus();
+        // Including the CGB registers, which a DMG does not have.
+        #[rustfmt::skip]
+        let unmapped = [
+            0xFF03, 0xFF08, 0xFF0E, 0xFF4C, 0xFF4D, 0xFF4F,
+            0xFF50, 0xFF55, 0xFF68, 0xFF69, 0xFF70, 0xFF7F,
+        ];
+        for addr in unmapped {
+            bus.write_byte(addr, 0x00);
+            assert_eq!(bus.read_byte(addr), 0xFF, "{:#06x}", addr);
+        }
+    }
+
+    fn cgb_bus() -> Bus {
+        let rom = test_rom::build(0x00, 0x00, &[], &[]);
+        Bus::new(Model::Cgb, Cartridge::new(rom).unwrap())
+    }
+
+    #[test]
+    fn wram_and_vram_banks() {
+        let mut bus = cgb_bus();
+        for bank in 0..8 {
+            bus.write_byte(0xFF70, bank);
+            bus.write_byte(0xD000, 0x10 + bank);
+        }
+        // Bank 0 at 0xD000 is bank 1, and the echo follows SVBK.
+        bus.write_byte(0xFF70, 0);
+        assert_eq!(bus.read_byte(0xD000), 0x11);
+        bus.write_byte(0xFF70, 5);
+        assert_eq!(bus.read_byte(0xF000), 0x15);
+        assert_eq!(bus.read_byte(0xFF70), 0xFD);
+
+        bus.write_byte(0xFF4F, 1);
+        bus.write_byte(0x8000, 0xAA);
+        bus.write_byte(0xFF4F, 0);
+        bus.write_byte(0x8000, 0xBB);
+        bus.write_byte(0xFF4F, 1);
+        assert_eq!(bus.read_byte(0x8000), 0xAA);
+        assert_eq!(bus.read_byte(0xFF4F), 0xFF);
+    }
+
+    #[test]
+    fn boot_rom_covers_the_cartridge_until_ff50_is_written() {
+        let mut bus = cgb_bus();
+        let boot_rom = BootRom::new(Model::Cgb, vec![0xB0; 0x0900]).unwrap();
+        bus.map_boot_rom(boot_rom);
+        // The cartridge header shows through the middle, and the
+        // cartridge takes over after the end.
+        let read = |bus: &Bus| {
+            [0x0000, 0x00FF, 0x0104, 0x0200, 0x08FF, 0x0900].map(|addr| bus.read_byte(addr))
+        };
+        assert_eq!(read(&bus), [0xB0, 0xB0, 0xCE, 0xB0, 0xB0, 0x00]);
+
+        // Writing zero leaves it in place, anything else takes it away for
+        // good.
+        bus.write_byte(0xFF50, 0x00);
+        assert!(bus.boot_rom_mapped());
+        bus.write_byte(0xFF50, 0x01);
+        assert_eq!(read(&bus), [0x00, 0x00, 0xCE, 0x00, 0x00, 0x00]);
+        bus.write_byte(0xFF50, 0x00);
+        assert!(!bus.boot_rom_mapped());
+
+        // KEY0 is locked along with it.
+        bus.write_byte(0xFF4C, 0x04);
+        assert!(bus.ppu.cgb_mode());
+    }
+
+    #[test]
+    fn key0_picks_dmg_compatibility_during_boot() {
+        let mut bus = cgb_bus();
+        bus.map_boot_rom(BootRom::new(Model::Cgb, vec![0; 0x0900]).unwrap());
+        bus.write_byte(0xFF4C, 0x04);
+        assert!(!bus.ppu.cgb_mode());
+        assert_eq!(bus.read_byte(0xFF70), 0xFF);
+    }
+
+    /// Fills 0xC000-0xC0FF with its own low address byte and points HDMA
+    /// from there to 0x8800.
+    fn hdma_bus() -> Bus {
+        let mut bus = cgb_bus();
+        for i in 0..0x100 {
+            bus.write_byte(0xC000 + i, i as u8);
+        }
+        for (addr, value) in [
+            (0xFF51, 0xC0),
+            (0xFF52, 0x00),
+            (0xFF53, 0x08),
+            (0xFF54, 0x00),
+        ] {
+            bus.write_byte(addr, value);
+        }
+        bus
+    }
+
+    #[test]
+    fn general_purpose_hdma_copies_everything_at_once() {
+        let mut bus = hdma_bus();
+        bus.write_byte(0xFF55, 0x02);
+        // Three blocks at 8 machine cycles each, all before the CPU runs.
+        assert_eq!(bus.run_hdma(), 3 * 8 * 4);
+        assert_eq!(bus.run_hdma(), 0);
+        assert_eq!(bus.read_byte(0xFF55), 0xFF);
+        for i in 0..0x30 {
+            assert_eq!(bus.read_byte(0x8800 + i), i as u8);
+        }
+        assert_eq!(bus.read_byte(0x8830), 0x00);
+    }
+
+    #[test]
+    fn hblank_hdma_copies_a_block_per_hblank() {
+        let mut bus = hdma_bus();
+        bus.write_byte(0xFF40, 0x91);
+        while bus.ppu.mode() != Mode::OamScan {
+            bus.tick();
+        }
+        bus.write_byte(0xFF55, 0x81);
+        assert_eq!(bus.read_byte(0xFF55), 0x01);
+        assert_eq!(bus.run_hdma(), 0);
+
+        for remaining in [0x00, 0xFF] {
+            while bus.ppu.mode() != Mode::HBlank {
+                bus.tick();
+            }
+            assert_eq!(bus.run_hdma(), 8 * 4);
+            assert_eq!(bus.read_byte(0xFF55), remaining);
+            while bus.ppu.mode() == Mode::HBlank {
+                bus.tick();
+            }
+        }
+        while bus.ppu.mode() != Mode::HBlank {
+            bus.tick();
+        }
+        assert_eq!(bus.run_hdma(), 0);
+        for i in 0..0x20 {
+            assert_eq!(bus.read_byte(0x8800 + i), i as u8);
+        }
+        assert_eq!(bus.read_byte(0x8820), 0x00);
+    }
+
+    #[test]
+    fn hblank_hdma_can_be_stopped() {
+        let mut bus = hdma_bus();
+        bus.write_byte(0xFF40, 0x91);
+        while bus.ppu.mode() != Mode::OamScan {
+            bus.tick();
+        }
+        bus.write_byte(0xFF55, 0x85);
+        bus.write_byte(0xFF55, 0x00);
+        assert_eq!(bus.read_byte(0xFF55), 0x85);
+        while bus.ppu.mode() != Mode::HBlank {
+            bus.tick();
+        }
+        assert_eq!(bus.run_hdma(), 0);
+    }
+}
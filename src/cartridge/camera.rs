@@ -0,0 +1,255 @@
+//! The Pocket Camera: MBC5-like banking, 128 KiB of RAM and a Mitsubishi
+//! M64282FP image sensor.
+//!
+//! Writing a RAM bank with bit 4 set maps the sensor registers over
+//! 0xA000-0xBFFF, mirrored every 0x80 bytes:
+//!
+//! | Register  | Function                                                  |
+//! |-----------|-----------------------------------------------------------|
+//! | 0x00      | Bit 0 starts a capture and reads 1 while busy             |
+//! | 0x01      | Edge enhancement mode (bits 5-7), gain (bits 0-4)         |
+//! | 0x02-0x03 | Exposure time, high byte first                            |
+//! | 0x04      | Edge ratio (bits 4-6), invert (bit 3), reference voltage  |
+//! | 0x05      | Zero point and output reference                           |
+//! | 0x06-0x35 | 4×4 dithering matrix, three thresholds per cell           |
+//!
+//! Only register 0x00 can be read back; the rest read 0x00.
+//!
+//! The sensor sees a [`SensorImage`] supplied by the host rather than a
+//! live feed. A capture completes as soon as it is started: the frame is
+//! scaled by the exposure, edge-enhanced, and then each pixel is compared
+//! against the thresholds of its matrix cell to pick one of four shades.
+//! The result lands in RAM bank 0 at 0x0100 as 16×14 tiles. Gain and the
+//! analog reference voltages are not modelled.
+
+use super::image::{SensorImage, SENSOR_HEIGHT, SENSOR_WIDTH};
+use super::{read_rom_bank, Mbc, RAM_BANK_SIZE};
+use crate::state::{StateError, StateReader, StateWriter};
+
+const RAM_SIZE: usize = 0x20000;
+const REGISTER_COUNT: usize = 0x36;
+/// Where captured tiles are written in RAM bank 0.
+const IMAGE_OFFSET: usize = 0x0100;
+/// Exposure at which the image is passed through unchanged.
+const EXPOSURE_UNITY: i32 = 0x1000;
+/// Edge enhancement ratios, in quarters.
+const EDGE_RATIOS: [i32; 8] = [2, 3, 4, 5, 8, 12, 16, 20];
+
+pub struct PocketCamera {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+    registers_mapped: bool,
+    registers: [u8; REGISTER_COUNT],
+    image: SensorImage,
+}
+
+impl PocketCamera {
+    pub fn new(rom: Vec<u8>) -> PocketCamera {
+        PocketCamera {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            registers_mapped: false,
+            registers: [0; REGISTER_COUNT],
+            image: SensorImage::solid(0x80),
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> usize {
+        self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize & (RAM_BANK_SIZE - 1))
+    }
+
+    /// Sensor output for (`x`, `y`) after exposure, before edge enhancement.
+    fn exposed(&self, x: isize, y: isize) -> i32 {
+        let exposure = i32::from(self.registers[0x02]) << 8 | i32::from(self.registers[0x03]);
+        i32::from(self.image.pixel(x, y)) * exposure / EXPOSURE_UNITY
+    }
+
+    /// Runs a capture and stores the dithered frame as tiles.
+    fn capture(&mut self) {
+        let edge_enhance = self.registers[0x01] & 0xE0 != 0;
+        let ratio = EDGE_RATIOS[(self.registers[0x04] >> 4) as usize & 0x07];
+        let invert = self.registers[0x04] & 0x08 != 0;
+
+        for y in 0..SENSOR_HEIGHT {
+            for x in 0..SENSOR_WIDTH {
+                let (sx, sy) = (x as isize, y as isize);
+                let mut value = self.exposed(sx, sy);
+                if edge_enhance {
+                    let neighbours = self.exposed(sx - 1, sy)
+                        + self.exposed(sx + 1, sy)
+                        + self.exposed(sx, sy - 1)
+                        + self.exposed(sx, sy + 1);
+                    value += (4 * value - neighbours) * ratio / 4;
+                }
+                let mut value = value.clamp(0, 0xFF) as u8;
+                if invert {
+                    value = !value;
+                }
+
+                let cell = 0x06 + ((y % 4) * 4 + x % 4) * 3;
+                let thresholds = &self.registers[cell..cell + 3];
+                let shade = thresholds
+                    .iter()
+                    .position(|&threshold| value < threshold)
+                    .map_or(0, |i| 3 - i as u8);
+
+                let tile = (y / 8) * (SENSOR_WIDTH / 8) + x / 8;
+                let offset = IMAGE_OFFSET + tile * 16 + (y % 8) * 2;
+                let bit = 0x80 >> (x % 8);
+                for plane in 0..2 {
+                    if shade >> plane & 1 != 0 {
+                        self.ram[offset + plane] |= bit;
+                    } else {
+                        self.ram[offset + plane] &= !bit;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Mbc for PocketCamera {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_bank(&self.rom, bank as usize, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = value & 0x3F,
+            0x4000..=0x5FFF => {
+                self.registers_mapped = value & 0x10 != 0;
+                self.ram_bank = value & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if self.registers_mapped {
+            return match addr & 0x7F {
+                0x00 => self.registers[0] & 0x07,
+                _ => 0x00,
+            };
+        }
+        // RAM reads work even while writes are disabled.
+        self.ram[self.ram_offset(addr)]
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if self.registers_mapped {
+            let register = (addr & 0x7F) as usize;
+            if register < REGISTER_COUNT {
+                self.registers[register] = value;
+            }
+            if register == 0x00 && value & 0x01 != 0 {
+                self.capture();
+                self.registers[0] &= !0x01;
+            }
+        } else if self.ram_enabled {
+            let offset = self.ram_offset(addr);
+            self.ram[offset] = value;
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = RAM_SIZE.min(data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    /// The sensor image is host input and is left as it is.
+    fn save_state(&self, w: &mut StateWriter) {
+        w.block(&self.ram);
+        w.bool(self.ram_enabled);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.registers_mapped);
+        w.bytes(&self.registers);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.block(&mut self.ram)?;
+        self.ram_enabled = r.bool()?;
+        self.rom_bank = r.u8()? & 0x3F;
+        self.ram_bank = r.u8()? & 0x0F;
+        self.registers_mapped = r.bool()?;
+        r.bytes(&mut self.registers)
+    }
+
+    fn set_camera_image(&mut self, image: SensorImage) {
+        self.image = image;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A camera with unity exposure and thresholds 0x40, 0x80 and 0xC0 in
+    /// every matrix cell, looking at `image`.
+    fn camera(image: SensorImage) -> PocketCamera {
+        let mut camera = PocketCamera::new(vec![0; 0x8000]);
+        camera.set_camera_image(image);
+        camera.write_rom(0x0000, 0x0A);
+        camera.write_rom(0x4000, 0x10);
+        camera.write_ram(0xA002, 0x10);
+        camera.write_ram(0xA003, 0x00);
+        for cell in 0..16 {
+            for (i, &threshold) in [0x40, 0x80, 0xC0].iter().enumerate() {
+                camera.write_ram(0xA006 + cell * 3 + i as u16, threshold);
+            }
+        }
+        camera
+    }
+
+    /// Starts a capture and returns the two bytes of the first row of
+    /// tiles 0 and 8, left and right of the middle.
+    fn capture(camera: &mut PocketCamera) -> [[u8; 2]; 2] {
+        camera.write_ram(0xA000, 0x01);
+        assert_eq!(camera.read_ram(0xA000), 0x00);
+        camera.write_rom(0x4000, 0x00);
+        let row = |tile: u16| {
+            let addr = 0xA000 + IMAGE_OFFSET as u16 + tile * 16;
+            [camera.read_ram(addr), camera.read_ram(addr + 1)]
+        };
+        [row(0), row(8)]
+    }
+
+    #[test]
+    fn capture_dithers_into_tiles() {
+        let image = SensorImage::from_luminance(2, 1, &[0x10, 0xF0]).unwrap();
+        let mut split = camera(image);
+        assert_eq!(capture(&mut split), [[0xFF, 0xFF], [0x00, 0x00]]);
+        // The frame fills 16×14 tiles and nothing around them.
+        assert_eq!(split.read_ram(0xA000 + 0x0100 + 13 * 16 * 16 + 15), 0xFF);
+        assert_eq!(split.read_ram(0xA000 + 0x0100 + 16 * 14 * 16), 0x00);
+        assert_eq!(split.read_ram(0xA000 + 0x0100 - 1), 0x00);
+
+        let mut gray = camera(SensorImage::solid(0x90));
+        assert_eq!(capture(&mut gray), [[0xFF, 0x00], [0xFF, 0x00]]);
+    }
+
+    #[test]
+    fn capture_applies_invert_and_exposure() {
+        let image = SensorImage::from_luminance(2, 1, &[0x10, 0xF0]).unwrap();
+        let mut inverted = camera(image.clone());
+        inverted.write_ram(0xA004, 0x08);
+        assert_eq!(capture(&mut inverted), [[0x00, 0x00], [0xFF, 0xFF]]);
+
+        // Half the exposure turns 0xF0 into 0x78.
+        let mut dark = camera(image);
+        dark.write_ram(0xA002, 0x08);
+        assert_eq!(capture(&mut dark), [[0xFF, 0xFF], [0x00, 0xFF]]);
+    }
+}
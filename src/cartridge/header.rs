@@ -0,0 +1,591 @@
+//! The cartridge header at 0x0100-0x014F.
+
+use std::fmt;
+
+/// The logo the boot ROM compares against 0x0104-0x0133.
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// Smallest image that holds a complete header.
+pub const HEADER_END: usize = 0x0150;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mapper {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+}
+
+/// The decoded cartridge type byte at 0x0147.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mapper: Mapper,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+    pub sensor: bool,
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> Option<CartridgeType> {
+        use self::Mapper::*;
+        let (mapper, ram, battery, timer, rumble, sensor) = match code {
+            0x00 => (RomOnly, false, false, false, false, false),
+            0x01 => (Mbc1, false, false, false, false, false),
+            0x02 => (Mbc1, true, false, false, false, false),
+            0x03 => (Mbc1, true, true, false, false, false),
+            0x05 => (Mbc2, false, false, false, false, false),
+            0x06 => (Mbc2, false, true, false, false, false),
+            0x08 => (RomOnly, true, false, false, false, false),
+            0x09 => (RomOnly, true, true, false, false, false),
+            0x0B => (Mmm01, false, false, false, false, false),
+            0x0C => (Mmm01, true, false, false, false, false),
+            0x0D => (Mmm01, true, true, false, false, false),
+            0x0F => (Mbc3, false, true, true, false, false),
+            0x10 => (Mbc3, true, true, true, false, false),
+            0x11 => (Mbc3, false, false, false, false, false),
+            0x12 => (Mbc3, true, false, false, false, false),
+            0x13 => (Mbc3, true, true, false, false, false),
+            0x19 => (Mbc5, false, false, false, false, false),
+            0x1A => (Mbc5, true, false, false, false, false),
+            0x1B => (Mbc5, true, true, false, false, false),
+            0x1C => (Mbc5, false, false, false, true, false),
+            0x1D => (Mbc5, true, false, false, true, false),
+            0x1E => (Mbc5, true, true, false, true, false),
+            0x20 => (Mbc6, true, true, false, false, false),
+            0x22 => (Mbc7, true, true, false, true, true),
+            0xFC => (PocketCamera, true, true, false, false, false),
+            0xFD => (Tama5, true, true, true, false, false),
+            0xFE => (HuC3, true, true, true, false, false),
+            0xFF => (HuC1, true, true, false, false, false),
+            _ => return None,
+        };
+        Some(CartridgeType {
+            code,
+            mapper,
+            ram,
+            battery,
+            timer,
+            rumble,
+            sensor,
+        })
+    }
+}
+
+impl fmt::Display for CartridgeType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mapper = match self.mapper {
+            Mapper::RomOnly => "ROM",
+            Mapper::Mbc1 => "MBC1",
+            Mapper::Mbc2 => "MBC2",
+            Mapper::Mmm01 => "MMM01",
+            Mapper::Mbc3 => "MBC3",
+            Mapper::Mbc5 => "MBC5",
+            Mapper::Mbc6 => "MBC6",
+            Mapper::Mbc7 => "MBC7",
+            Mapper::PocketCamera => "POCKET CAMERA",
+            Mapper::Tama5 => "BANDAI TAMA5",
+            Mapper::HuC3 => "HuC3",
+            Mapper::HuC1 => "HuC1",
+        };
+        write!(f, "{}", mapper)?;
+        for (present, name) in [
+            (self.timer, "TIMER"),
+            (self.rumble, "RUMBLE"),
+            (self.sensor, "SENSOR"),
+            (self.ram, "RAM"),
+            (self.battery, "BATTERY"),
+        ] {
+            if present {
+                write!(f, "+{}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// CGB support, from 0x0143.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgbFlag {
+    /// Runs on DMG hardware only (or in compatibility mode on CGB).
+    None,
+    /// Uses CGB features but also runs on DMG (0x80).
+    Compatible,
+    /// Requires CGB hardware (0xC0).
+    Only,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Destination {
+    Japan,
+    Overseas,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The image ends before 0x0150.
+    Truncated {
+        len: usize,
+    },
+    UnknownCartridgeType(u8),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+    /// 0x0104-0x0133 does not match the Nintendo logo. The boot ROM locks up
+    /// on such cartridges.
+    BadLogo,
+    /// The boot ROM locks up on a header checksum mismatch too.
+    HeaderChecksum {
+        expected: u8,
+        actual: u8,
+    },
+    /// Never checked by hardware, but a good sign of a bad dump.
+    GlobalChecksum {
+        expected: u16,
+        actual: u16,
+    },
+    /// The image size does not match the ROM size code.
+    RomSizeMismatch {
+        declared: usize,
+        actual: usize,
+    },
+    /// The RAM size code contradicts the cartridge type.
+    RamSizeMismatch {
+        code: u8,
+        cartridge_type: u8,
+    },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderError::Truncated { len } => write!(
+                f,
+                "ROM is {} bytes long, too short for a cartridge header",
+                len
+            ),
+            HeaderError::UnknownCartridgeType(code) => {
+                write!(f, "unknown cartridge type {:#04x}", code)
+            }
+            HeaderError::UnknownRomSize(code) => write!(f, "unknown ROM size code {:#04x}", code),
+            HeaderError::UnknownRamSize(code) => write!(f, "unknown RAM size code {:#04x}", code),
+            HeaderError::BadLogo => write!(f, "Nintendo logo does not match"),
+            HeaderError::HeaderChecksum { expected, actual } => write!(
+                f,
+                "header checksum is {:#04x}, computed {:#04x}",
+                expected, actual
+            ),
+            HeaderError::GlobalChecksum { expected, actual } => write!(
+                f,
+                "global checksum is {:#06x}, computed {:#06x}",
+                expected, actual
+            ),
+            HeaderError::RomSizeMismatch { declared, actual } => write!(
+                f,
+                "header declares {} bytes of ROM, image has {}",
+                declared, actual
+            ),
+            HeaderError::RamSizeMismatch {
+                code,
+                cartridge_type,
+            } => write!(
+                f,
+                "RAM size code {:#04x} does not fit cartridge type {:#04x}",
+                code, cartridge_type
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub title: String,
+    /// The sum of the 16 title bytes at 0x0134-0x0143, by which the CGB
+    /// boot ROM recognises DMG games.
+    pub title_checksum: u8,
+    /// Four-letter code on later cartridges, inside the old title area.
+    pub manufacturer_code: Option<String>,
+    pub cgb_flag: CgbFlag,
+    /// Two ASCII characters at 0x0144, meaningful when the old licensee code
+    /// is 0x33.
+    pub new_licensee_code: [u8; 2],
+    pub sgb_flag: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    pub destination: Destination,
+    pub old_licensee_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl Header {
+    /// Decodes the header. Only fails when a field cannot be interpreted; use
+    /// [`Header::validate`] to check the logo and checksums.
+    pub fn parse(rom: &[u8]) -> Result<Header, HeaderError> {
+        if rom.len() < HEADER_END {
+            return Err(HeaderError::Truncated { len: rom.len() });
+        }
+
+        let cartridge_type = CartridgeType::from_code(rom[0x0147])
+            .ok_or(HeaderError::UnknownCartridgeType(rom[0x0147]))?;
+        let rom_size_code = rom[0x0148];
+        if rom_size(rom_size_code).is_none() {
+            return Err(HeaderError::UnknownRomSize(rom_size_code));
+        }
+        let ram_size_code = rom[0x0149];
+        if ram_size(ram_size_code).is_none() {
+            return Err(HeaderError::UnknownRamSize(ram_size_code));
+        }
+
+        let cgb_flag = match rom[0x0143] {
+            0xC0 => CgbFlag::Only,
+            flag if flag & 0x80 != 0 => CgbFlag::Compatible,
+            _ => CgbFlag::None,
+        };
+
+        // The manufacturer code only exists on cartridges that also set the
+        // CGB flag; older titles use all 16 bytes for the name.
+        let code = &rom[0x013F..0x0143];
+        let manufacturer_code = if cgb_flag != CgbFlag::None
+            && code
+                .iter()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        {
+            Some(String::from_utf8_lossy(code).into_owned())
+        } else {
+            None
+        };
+        let title_end = match (cgb_flag, &manufacturer_code) {
+            (_, Some(_)) => 0x013F,
+            (CgbFlag::None, None) => 0x0144,
+            (_, None) => 0x0143,
+        };
+        let title = rom[0x0134..title_end]
+            .iter()
+            .take_while(|&&b| b != 0)
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '?'
+                }
+            })
+            .collect::<String>();
+
+        Ok(Header {
+            title,
+            title_checksum: rom[0x0134..0x0144]
+                .iter()
+                .fold(0u8, |sum, &b| sum.wrapping_add(b)),
+            manufacturer_code,
+            cgb_flag,
+            new_licensee_code: [rom[0x0144], rom[0x0145]],
+            sgb_flag: rom[0x0146] == 0x03,
+            cartridge_type,
+            rom_size_code,
+            ram_size_code,
+            destination: if rom[0x014A] == 0x00 {
+                Destination::Japan
+            } else {
+                Destination::Overseas
+            },
+            old_licensee_code: rom[0x014B],
+            version: rom[0x014C],
+            header_checksum: rom[0x014D],
+            global_checksum: u16::from_be_bytes([rom[0x014E], rom[0x014F]]),
+        })
+    }
+
+    /// Every problem found with the logo, the checksums and the declared
+    /// sizes. An empty list means the header is consistent with `rom`.
+    pub fn validate(&self, rom: &[u8]) -> Vec<HeaderError> {
+        let mut problems = Vec::new();
+
+        if rom[0x0104..0x0134] != NINTENDO_LOGO[..] {
+            problems.push(HeaderError::BadLogo);
+        }
+
+        let actual = header_checksum(rom);
+        if actual != self.header_checksum {
+            problems.push(HeaderError::HeaderChecksum {
+                expected: self.header_checksum,
+                actual,
+            });
+        }
+
+        let actual = global_checksum(rom);
+        if actual != self.global_checksum {
+            problems.push(HeaderError::GlobalChecksum {
+                expected: self.global_checksum,
+                actual,
+            });
+        }
+
+        let declared = self.rom_size();
+        if declared != rom.len() {
+            problems.push(HeaderError::RomSizeMismatch {
+                declared,
+                actual: rom.len(),
+            });
+        }
+
+        // MBC2 has its own RAM and must declare none; the other RAM-less types
+        // should not declare any either.
+        let has_ram = self.ram_size() != 0;
+        let expects_ram = self.cartridge_type.ram && self.cartridge_type.mapper != Mapper::Mbc2;
+        if has_ram && !expects_ram {
+            problems.push(HeaderError::RamSizeMismatch {
+                code: self.ram_size_code,
+                cartridge_type: self.cartridge_type.code,
+            });
+        }
+
+        problems
+    }
+
+    /// ROM size in bytes, as declared.
+    pub fn rom_size(&self) -> usize {
+        rom_size(self.rom_size_code).unwrap_or(0)
+    }
+
+    /// External RAM size in bytes, as declared.
+    pub fn ram_size(&self) -> usize {
+        ram_size(self.ram_size_code).unwrap_or(0)
+    }
+
+    /// Whether Nintendo is the licensee, by either licensee code. The CGB
+    /// boot ROM only recognises Nintendo's DMG games.
+    pub fn nintendo_licensed(&self) -> bool {
+        match self.old_licensee_code {
+            0x33 => self.new_licensee_code == *b"01",
+            code => code == 0x01,
+        }
+    }
+
+    /// Whether the game expects CGB hardware features.
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag != CgbFlag::None
+    }
+}
+
+fn rom_size(code: u8) -> Option<usize> {
+    match code {
+        0x00..=0x08 => Some(0x8000 << code),
+        // Sizes listed in some documents but not seen on any cartridge.
+        0x52 => Some(72 * 0x4000),
+        0x53 => Some(80 * 0x4000),
+        0x54 => Some(96 * 0x4000),
+        _ => None,
+    }
+}
+
+fn ram_size(code: u8) -> Option<usize> {
+    match code {
+        0x00 => Some(0),
+        0x01 => Some(0x800),
+        0x02 => Some(0x2000),
+        0x03 => Some(0x8000),
+        0x04 => Some(0x20000),
+        0x05 => Some(0x10000),
+        _ => None,
+    }
+}
+
+/// The checksum the boot ROM verifies, over 0x0134-0x014C.
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x0134..0x014D]
+        .iter()
+        .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1))
+}
+
+/// Sum of every byte except the two checksum bytes themselves.
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 0x014E && i != 0x014F)
+        .fold(0u16, |sum, (_, &b)| sum.wrapping_add(u16::from(b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_rom;
+
+    fn rom(patches: &[(usize, &[u8])]) -> Vec<u8> {
+        test_rom::build(0x00, 0x00, &[], patches)
+    }
+
+    #[test]
+    fn titles_depend_on_the_cgb_flag() {
+        let dmg = Header::parse(&rom(&[(0x0134, b"SIXTEEN LETTERS!")])).unwrap();
+        assert_eq!(dmg.title, "SIXTEEN LETTERS!");
+        assert_eq!((dmg.cgb_flag, dmg.manufacturer_code), (CgbFlag::None, None));
+
+        let coded = rom(&[(0x0134, b"POKEMON CRYAXEJ"), (0x0143, &[0x80])]);
+        let coded = Header::parse(&coded).unwrap();
+        assert_eq!(coded.title, "POKEMON CRY");
+        assert_eq!(coded.manufacturer_code.as_deref(), Some("AXEJ"));
+        assert_eq!(coded.cgb_flag, CgbFlag::Compatible);
+
+        let uncoded = rom(&[(0x0134, b"LOWERCASE\x01code"), (0x0143, &[0xC0])]);
+        let uncoded = Header::parse(&uncoded).unwrap();
+        assert_eq!(uncoded.title, "LOWERCASE?code");
+        assert!(uncoded.supports_cgb());
+        assert_eq!(
+            (uncoded.cgb_flag, uncoded.manufacturer_code),
+            (CgbFlag::Only, None)
+        );
+
+        // Any flag with bit 7 set other than 0xC0 counts as compatible.
+        let odd = Header::parse(&rom(&[(0x0143, &[0x84])])).unwrap();
+        assert_eq!(odd.cgb_flag, CgbFlag::Compatible);
+        assert_eq!(odd.title, "TEST");
+        // 0x54 + 0x45 + 0x53 + 0x54, plus the flag.
+        assert_eq!(odd.title_checksum, 0xC4);
+    }
+
+    #[test]
+    fn size_codes_decode() {
+        for (code, size) in [
+            (0x00, 0x8000),
+            (0x05, 0x100000),
+            (0x08, 0x800000),
+            (0x52, 0x120000),
+        ] {
+            assert_eq!(rom_size(code), Some(size), "{:#04x}", code);
+        }
+        for (code, size) in [
+            (0x00, 0),
+            (0x01, 0x800),
+            (0x02, 0x2000),
+            (0x03, 0x8000),
+            (0x04, 0x20000),
+            (0x05, 0x10000),
+        ] {
+            assert_eq!(ram_size(code), Some(size), "{:#04x}", code);
+        }
+
+        let header =
+            Header::parse(&test_rom::build(0x1B, 0x03, &[], &[(0x0148, &[0x02])])).unwrap();
+        assert_eq!((header.rom_size(), header.ram_size()), (0x20000, 0x8000));
+        assert_eq!(header.cartridge_type.to_string(), "MBC5+RAM+BATTERY");
+        assert_eq!(
+            CartridgeType::from_code(0x10).unwrap().to_string(),
+            "MBC3+TIMER+RAM+BATTERY"
+        );
+    }
+
+    #[test]
+    fn unknown_codes_are_rejected() {
+        for &code in &[0x09, 0x51, 0x55, 0xFF] {
+            assert_eq!(
+                Header::parse(&rom(&[(0x0148, &[code])])),
+                Err(HeaderError::UnknownRomSize(code))
+            );
+        }
+        for &code in &[0x06, 0x80, 0xFF] {
+            assert_eq!(
+                Header::parse(&rom(&[(0x0149, &[code])])),
+                Err(HeaderError::UnknownRamSize(code))
+            );
+        }
+        for &code in &[0x04, 0x14, 0x21, 0xFB] {
+            assert_eq!(
+                Header::parse(&rom(&[(0x0147, &[code])])),
+                Err(HeaderError::UnknownCartridgeType(code))
+            );
+        }
+        assert_eq!(
+            Header::parse(&[0; 0x014F]),
+            Err(HeaderError::Truncated { len: 0x014F })
+        );
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let good = rom(&[]);
+        assert_eq!(Header::parse(&good).unwrap().validate(&good), []);
+
+        // A blank header sums to minus one per byte.
+        assert_eq!(header_checksum(&[0; HEADER_END]), 0xE7);
+
+        let mut bad = good.clone();
+        bad[0x0134] ^= 0x01;
+        bad[0x0104] ^= 0x01;
+        let header = Header::parse(&bad).unwrap();
+        let global = global_checksum(&good);
+        assert_eq!(
+            header.validate(&bad),
+            [
+                HeaderError::BadLogo,
+                HeaderError::HeaderChecksum {
+                    expected: header_checksum(&good),
+                    actual: header_checksum(&good).wrapping_sub(1),
+                },
+                HeaderError::GlobalChecksum {
+                    expected: global,
+                    actual: global_checksum(&bad),
+                },
+            ]
+        );
+
+        let mut truncated = good;
+        truncated.truncate(0x4000);
+        let header = Header::parse(&truncated).unwrap();
+        assert!(header
+            .validate(&truncated)
+            .contains(&HeaderError::RomSizeMismatch {
+                declared: 0x8000,
+                actual: 0x4000,
+            }));
+    }
+
+    #[test]
+    fn ram_size_must_fit_the_cartridge_type() {
+        for &(cartridge_type, code, fits) in &[
+            (0x03, 0x02, true),
+            (0x01, 0x02, false),
+            (0x06, 0x00, true),
+            (0x06, 0x01, false),
+        ] {
+            let rom = test_rom::build(cartridge_type, code, &[], &[]);
+            let problems = Header::parse(&rom).unwrap().validate(&rom);
+            assert_eq!(
+                problems.is_empty(),
+                fits,
+                "{:#04x} {:#04x}",
+                cartridge_type,
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn licensee_codes() {
+        let licensed = |old: u8, new: &[u8]| {
+            let rom = rom(&[(0x014B, &[old]), (0x0144, new)]);
+            Header::parse(&rom).unwrap().nintendo_licensed()
+        };
+        assert!(licensed(0x01, b"00"));
+        assert!(licensed(0x33, b"01"));
+        assert!(!licensed(0x33, b"08"));
+        assert!(!licensed(0x08, b"01"));
+    }
+}
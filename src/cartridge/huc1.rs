@@ -0,0 +1,114 @@
+//! Hudson's HuC1: MBC1-style banking plus an infrared LED and receiver.
+//!
+//! 0x0000-0x1FFF switches 0xA000-0xBFFF between RAM and the infrared port
+//! rather than enabling RAM. No link partner is emulated, so the receiver
+//! never sees light and the LED output is dropped.
+
+use super::{ram_offset, read_rom_bank, Mbc};
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Value of the infrared port when no light is received.
+const IR_DARK: u8 = 0xC0;
+
+pub struct HuC1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ir_mode: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+}
+
+impl HuC1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> HuC1 {
+        HuC1 {
+            rom,
+            ram: vec![0; ram_size],
+            ir_mode: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+}
+
+impl Mbc for HuC1 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_bank(&self.rom, bank as usize, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ir_mode = value == 0x0E,
+            0x2000..=0x3FFF => self.rom_bank = value & 0x3F,
+            0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if self.ir_mode {
+            return IR_DARK;
+        }
+        match ram_offset(&self.ram, self.ram_bank as usize, addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        // In infrared mode this drives the LED, which nothing is watching.
+        if self.ir_mode {
+            return;
+        }
+        if let Some(offset) = ram_offset(&self.ram, self.ram_bank as usize, addr) {
+            self.ram[offset] = value;
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = self.ram.len().min(data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.block(&self.ram);
+        w.bool(self.ir_mode);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.block(&mut self.ram)?;
+        self.ir_mode = r.bool()?;
+        self.rom_bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_ram_window_switches_to_infrared() {
+        let mut mbc = HuC1::new(vec![0; 0x8000], 0x8000);
+        // RAM needs no enable.
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+
+        mbc.write_rom(0x0000, 0x0E);
+        assert_eq!(mbc.read_ram(0xA000), IR_DARK);
+        mbc.write_ram(0xA000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), IR_DARK);
+
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+    }
+}
@@ -0,0 +1,365 @@
+//! Hudson's HuC3: ROM/RAM banking plus a real-time clock, an infrared port
+//! and a speaker, all reached through 0xA000-0xBFFF.
+//!
+//! The value written to 0x0000-0x1FFF picks what that area does:
+//!
+//! | Mode | 0xA000-0xBFFF                       |
+//! |------|-------------------------------------|
+//! | 0x0  | RAM, read-only                      |
+//! | 0xA  | RAM                                 |
+//! | 0xB  | Write a command to the clock chip   |
+//! | 0xC  | Read the result of the last command |
+//! | 0xD  | Command status; always ready        |
+//! | 0xE  | Infrared port                       |
+//!
+//! Commands are a 3-bit opcode in bits 4-6 and a nibble argument. They read
+//! and write a nibble-wide register file through an auto-incrementing index:
+//! 0-2 hold the minute of the day, 3-6 the day counter and 0x58-0x5F the
+//! alarm.
+//!
+//! The clock state is persisted after RAM as a little-endian `u64` UNIX
+//! timestamp followed by the minutes, days, alarm minutes and alarm days as
+//! `u16` and the alarm enable as a byte.
+
+use super::rtc::Clock;
+use super::{ram_offset, read_rom_bank, split_trailer, Mbc};
+use crate::state::{StateError, StateReader, StateWriter};
+
+const TRAILER_SIZE: usize = 17;
+const MINUTES_PER_DAY: u64 = 1440;
+
+pub struct HuC3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    clock: Box<dyn Clock>,
+    mode: u8,
+    rom_bank: u8,
+    ram_bank: u8,
+
+    /// Minute of the day, 0-1439.
+    minutes: u16,
+    days: u16,
+    alarm_minutes: u16,
+    alarm_days: u16,
+    alarm_enabled: bool,
+    /// Clock time the counters were last brought up to date, rounded down to
+    /// a whole minute of counting.
+    updated_at: u64,
+
+    index: u8,
+    /// Nibble returned in mode 0xC.
+    result: u8,
+    access_flags: u8,
+}
+
+impl HuC3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, clock: Box<dyn Clock>) -> HuC3 {
+        let updated_at = clock.now();
+        HuC3 {
+            rom,
+            ram: vec![0; ram_size],
+            clock,
+            mode: 0,
+            rom_bank: 1,
+            ram_bank: 0,
+            minutes: 0,
+            days: 0,
+            alarm_minutes: 0,
+            alarm_days: 0,
+            alarm_enabled: false,
+            updated_at,
+            index: 0,
+            result: 0,
+            access_flags: 0,
+        }
+    }
+
+    fn update(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.saturating_sub(self.updated_at) / 60;
+        self.updated_at += elapsed * 60;
+
+        let total = u64::from(self.minutes) + elapsed;
+        self.minutes = (total % MINUTES_PER_DAY) as u16;
+        self.days = self.days.wrapping_add((total / MINUTES_PER_DAY) as u16);
+    }
+
+    fn command(&mut self, value: u8) {
+        let argument = value & 0x0F;
+        match (value >> 4) & 0x07 {
+            0x1 => {
+                self.update();
+                self.result = self.read_register(self.index);
+                self.index = self.index.wrapping_add(1);
+            }
+            0x2 => {
+                self.update();
+                self.write_register(self.index, argument);
+            }
+            0x3 => {
+                self.update();
+                self.write_register(self.index, argument);
+                self.index = self.index.wrapping_add(1);
+            }
+            0x4 => self.index = (self.index & 0xF0) | argument,
+            0x5 => self.index = (self.index & 0x0F) | (argument << 4),
+            0x6 => self.access_flags = argument,
+            _ => {}
+        }
+    }
+
+    fn read_register(&self, index: u8) -> u8 {
+        match index {
+            0..=2 => (self.minutes >> (index * 4)) as u8 & 0x0F,
+            3..=6 => (self.days >> ((index - 3) * 4)) as u8 & 0x0F,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, index: u8, nibble: u8) {
+        fn set_nibble(value: &mut u16, shift: u8, nibble: u8) {
+            *value = (*value & !(0x0F << shift)) | (u16::from(nibble) << shift);
+        }
+        match index {
+            0..=2 => set_nibble(&mut self.minutes, index * 4, nibble),
+            3..=6 => set_nibble(&mut self.days, (index - 3) * 4, nibble),
+            0x58..=0x5A => set_nibble(&mut self.alarm_minutes, (index - 0x58) * 4, nibble),
+            0x5B..=0x5E => set_nibble(&mut self.alarm_days, (index - 0x5B) * 4, nibble),
+            0x5F => self.alarm_enabled = nibble & 0x01 != 0,
+            _ => {}
+        }
+    }
+}
+
+impl Mbc for HuC3 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_bank(&self.rom, bank as usize, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.mode = value & 0x0F,
+            0x2000..=0x3FFF => self.rom_bank = value & 0x7F,
+            0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        match self.mode {
+            0x0 | 0xA => match ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                Some(offset) => self.ram[offset],
+                None => 0xFF,
+            },
+            0xC => {
+                if self.access_flags == 0x2 {
+                    0x01
+                } else {
+                    self.result
+                }
+            }
+            0xD => 0x01,
+            // No light on the infrared receiver.
+            0xE => 0xC0,
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        match self.mode {
+            0xA => {
+                if let Some(offset) = ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                    self.ram[offset] = value;
+                }
+            }
+            0xB => self.command(value),
+            _ => {}
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        // Catch up on a copy so saving does not need `&mut self`.
+        let elapsed = self.clock.now().saturating_sub(self.updated_at) / 60;
+        let total = u64::from(self.minutes) + elapsed;
+        let minutes = (total % MINUTES_PER_DAY) as u16;
+        let days = self.days.wrapping_add((total / MINUTES_PER_DAY) as u16);
+        let timestamp = self.updated_at + elapsed * 60;
+
+        let mut data = self.ram.clone();
+        data.extend_from_slice(&timestamp.to_le_bytes());
+        data.extend_from_slice(&minutes.to_le_bytes());
+        data.extend_from_slice(&days.to_le_bytes());
+        data.extend_from_slice(&self.alarm_minutes.to_le_bytes());
+        data.extend_from_slice(&self.alarm_days.to_le_bytes());
+        data.push(self.alarm_enabled as u8);
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let (ram, trailer) = split_trailer(data);
+        let ram_len = self.ram.len().min(ram.len());
+        self.ram[..ram_len].copy_from_slice(&ram[..ram_len]);
+
+        if trailer.len() != TRAILER_SIZE {
+            return;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([trailer[i], trailer[i + 1]]);
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&trailer[..8]);
+        self.updated_at = u64::from_le_bytes(timestamp);
+        self.minutes = u16_at(8) % MINUTES_PER_DAY as u16;
+        self.days = u16_at(10);
+        self.alarm_minutes = u16_at(12);
+        self.alarm_days = u16_at(14);
+        self.alarm_enabled = trailer[16] & 0x01 != 0;
+        self.update();
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.block(&self.ram);
+        w.u8(self.mode);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.u16(self.minutes);
+        w.u16(self.days);
+        w.u16(self.alarm_minutes);
+        w.u16(self.alarm_days);
+        w.bool(self.alarm_enabled);
+        w.u64(self.updated_at);
+        w.u8(self.index);
+        w.u8(self.result);
+        w.u8(self.access_flags);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.block(&mut self.ram)?;
+        self.mode = r.u8()?;
+        self.rom_bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.minutes = r.u16()? % MINUTES_PER_DAY as u16;
+        self.days = r.u16()?;
+        self.alarm_minutes = r.u16()?;
+        self.alarm_days = r.u16()?;
+        self.alarm_enabled = r.bool()?;
+        self.updated_at = r.u64()?;
+        self.index = r.u8()?;
+        self.result = r.u8()?;
+        self.access_flags = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::rtc::ManualClock;
+
+    fn huc3(now: u64) -> (HuC3, ManualClock) {
+        let clock = ManualClock::new(now);
+        let huc3 = HuC3::new(vec![0; 0x8000], 0x8000, Box::new(clock.clone()));
+        (huc3, clock)
+    }
+
+    /// Sends `commands` to the clock chip.
+    fn send(huc3: &mut HuC3, commands: &[u8]) {
+        huc3.write_rom(0x0000, 0x0B);
+        for &command in commands {
+            huc3.write_ram(0xA000, command);
+        }
+    }
+
+    /// Reads `count` nibbles of the register file from `index` on.
+    fn read_registers(huc3: &mut HuC3, index: u8, count: usize) -> Vec<u8> {
+        send(huc3, &[0x40 | (index & 0x0F), 0x50 | (index >> 4)]);
+        (0..count)
+            .map(|_| {
+                send(huc3, &[0x10]);
+                huc3.write_rom(0x0000, 0x0C);
+                huc3.read_ram(0xA000)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_mode_picks_what_the_ram_window_does() {
+        let (mut huc3, _) = huc3(0);
+        huc3.write_rom(0x0000, 0x0A);
+        huc3.write_ram(0xA000, 0x42);
+        huc3.write_rom(0x0000, 0x00);
+        huc3.write_ram(0xA000, 0x24);
+        assert_eq!(huc3.read_ram(0xA000), 0x42);
+        huc3.write_rom(0x0000, 0x0D);
+        assert_eq!(huc3.read_ram(0xA000), 0x01);
+        huc3.write_rom(0x0000, 0x0E);
+        assert_eq!(huc3.read_ram(0xA000), 0xC0);
+    }
+
+    #[test]
+    fn commands_walk_the_register_file() {
+        let (mut huc3, _) = huc3(0);
+        // Index 0, then write 0x3A5 minutes and a day count of 0x1234,
+        // incrementing after each nibble but the last.
+        send(&mut huc3, &[0x40, 0x50, 0x35, 0x3A, 0x33, 0x34, 0x33, 0x32]);
+        send(&mut huc3, &[0x21]);
+        assert_eq!((huc3.minutes, huc3.days), (0x3A5, 0x1234));
+        assert_eq!(huc3.index, 6);
+        assert_eq!(read_registers(&mut huc3, 0, 7), [5, 0xA, 3, 4, 3, 2, 1]);
+        assert_eq!(huc3.index, 7);
+
+        // The alarm sits at 0x58 and reads back as zero.
+        send(
+            &mut huc3,
+            &[0x48, 0x55, 0x39, 0x38, 0x37, 0x36, 0x35, 0x34, 0x33, 0x31],
+        );
+        assert_eq!((huc3.alarm_minutes, huc3.alarm_days), (0x789, 0x3456));
+        assert!(huc3.alarm_enabled);
+        assert_eq!(read_registers(&mut huc3, 0x58, 2), [0, 0]);
+
+        // Access flag 2 makes results read as ready.
+        send(&mut huc3, &[0x62]);
+        huc3.write_rom(0x0000, 0x0C);
+        assert_eq!(huc3.read_ram(0xA000), 0x01);
+    }
+
+    #[test]
+    fn the_clock_counts_whole_minutes() {
+        let (mut huc3, clock) = huc3(0);
+        send(&mut huc3, &[0x40, 0x50, 0x3F, 0x39, 0x35]); // 0x59F = 1439
+        clock.advance(59);
+        assert_eq!(read_registers(&mut huc3, 0, 4), [0xF, 0x9, 0x5, 0x0]);
+        clock.advance(1);
+        assert_eq!(read_registers(&mut huc3, 0, 4), [0, 0, 0, 1]);
+        // The second left over from a partial minute is not lost.
+        clock.advance(30);
+        huc3.update();
+        clock.advance(30);
+        assert_eq!(read_registers(&mut huc3, 0, 1), [1]);
+    }
+
+    #[test]
+    fn the_clock_survives_in_the_save_trailer() {
+        let (mut huc3, clock) = huc3(10_000);
+        send(&mut huc3, &[0x40, 0x50, 0x35, 0x30, 0x30, 0x32]);
+        send(&mut huc3, &[0x48, 0x55, 0x31, 0x4F, 0x31]);
+        clock.advance(150);
+        let data = huc3.save_data();
+        assert_eq!(data.len(), 0x8000 + TRAILER_SIZE);
+        #[rustfmt::skip]
+        assert_eq!(data[0x8000..], [
+            0x88, 0x27, 0, 0, 0, 0, 0, 0, // 10120
+            7, 0, 2, 0, 1, 0, 0, 0, 1,
+        ]);
+
+        let (mut loaded, later) = self::huc3(10_120);
+        later.advance(24 * 60 * 60);
+        loaded.load_save_data(&data);
+        assert_eq!(read_registers(&mut loaded, 0, 4), [7, 0, 0, 3]);
+        assert_eq!((loaded.alarm_minutes, loaded.alarm_enabled), (1, true));
+
+        // Saves without a trailer leave the clock alone.
+        let (mut bare, _) = self::huc3(10_120);
+        bare.load_save_data(&data[..0x8000]);
+        assert_eq!(bare.minutes, 0);
+    }
+}
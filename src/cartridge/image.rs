@@ -0,0 +1,272 @@
+//! Still images fed to the Pocket Camera sensor in place of a live feed.
+//!
+//! Images are converted to 8-bit luminance, where 0 is black and 255 is
+//! white, and resampled to the sensor's 128×112 pixels. Binary (P5) and
+//! plain (P2) PGM files are always supported; PNG needs the `png` feature.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const SENSOR_WIDTH: usize = 128;
+pub const SENSOR_HEIGHT: usize = 112;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    /// Neither a PGM nor, with the `png` feature, a PNG file.
+    UnknownFormat,
+    /// The file claims to be PGM but does not parse as one.
+    BadPgm,
+    #[cfg(feature = "png")]
+    Png(png::DecodingError),
+    /// The buffer length is not `width * height`.
+    SizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => err.fmt(f),
+            ImageError::UnknownFormat => write!(f, "not a PGM or PNG image"),
+            ImageError::BadPgm => write!(f, "malformed PGM image"),
+            #[cfg(feature = "png")]
+            ImageError::Png(err) => err.fmt(f),
+            ImageError::SizeMismatch { expected, actual } => {
+                write!(f, "image buffer is {} bytes, expected {}", actual, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> ImageError {
+        ImageError::Io(err)
+    }
+}
+
+#[cfg(feature = "png")]
+impl From<png::DecodingError> for ImageError {
+    fn from(err: png::DecodingError) -> ImageError {
+        ImageError::Png(err)
+    }
+}
+
+/// A grayscale frame at the sensor's resolution.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SensorImage {
+    pixels: Vec<u8>,
+}
+
+impl SensorImage {
+    /// A uniform frame of the given luminance.
+    pub fn solid(luminance: u8) -> SensorImage {
+        SensorImage {
+            pixels: vec![luminance; SENSOR_WIDTH * SENSOR_HEIGHT],
+        }
+    }
+
+    /// Builds a frame from row-major luminance of any size, resampling it
+    /// to the sensor's resolution.
+    pub fn from_luminance(
+        width: usize,
+        height: usize,
+        pixels: &[u8],
+    ) -> Result<SensorImage, ImageError> {
+        let expected = width.checked_mul(height);
+        if width == 0 || height == 0 || expected != Some(pixels.len()) {
+            return Err(ImageError::SizeMismatch {
+                expected: expected.unwrap_or(usize::MAX),
+                actual: pixels.len(),
+            });
+        }
+        let mut resampled = Vec::with_capacity(SENSOR_WIDTH * SENSOR_HEIGHT);
+        for y in 0..SENSOR_HEIGHT {
+            let src_y = y * height / SENSOR_HEIGHT;
+            for x in 0..SENSOR_WIDTH {
+                let src_x = x * width / SENSOR_WIDTH;
+                resampled.push(pixels[src_y * width + src_x]);
+            }
+        }
+        Ok(SensorImage { pixels: resampled })
+    }
+
+    /// Decodes a PGM or PNG image held in memory.
+    pub fn decode(data: &[u8]) -> Result<SensorImage, ImageError> {
+        if data.starts_with(b"P5") || data.starts_with(b"P2") {
+            let (width, height, pixels) = decode_pgm(data).ok_or(ImageError::BadPgm)?;
+            return SensorImage::from_luminance(width, height, &pixels);
+        }
+        #[cfg(feature = "png")]
+        {
+            if data.starts_with(b"\x89PNG") {
+                let (width, height, pixels) = decode_png(data)?;
+                return SensorImage::from_luminance(width, height, &pixels);
+            }
+        }
+        Err(ImageError::UnknownFormat)
+    }
+
+    /// Reads and decodes a PGM or PNG file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SensorImage, ImageError> {
+        SensorImage::decode(&fs::read(path)?)
+    }
+
+    /// Luminance at (`x`, `y`), clamped to the edges of the frame.
+    pub fn pixel(&self, x: isize, y: isize) -> u8 {
+        let x = x.clamp(0, SENSOR_WIDTH as isize - 1) as usize;
+        let y = y.clamp(0, SENSOR_HEIGHT as isize - 1) as usize;
+        self.pixels[y * SENSOR_WIDTH + x]
+    }
+}
+
+/// Splits a PGM file into its header fields and the raster that follows.
+fn decode_pgm(data: &[u8]) -> Option<(usize, usize, Vec<u8>)> {
+    let binary = data.starts_with(b"P5");
+    let mut pos = 2;
+    let mut fields = [0usize; 3];
+    for field in fields.iter_mut() {
+        *field = next_number(data, &mut pos)?;
+    }
+    let [width, height, max] = fields;
+    if max == 0 || max > 0xFFFF {
+        return None;
+    }
+    let scale = |value: usize| (value.min(max) * 255 / max) as u8;
+    let count = width.checked_mul(height)?;
+
+    let pixels = if binary {
+        // Exactly one whitespace byte separates the header from the raster.
+        let raster = data.get(pos + 1..)?;
+        if max < 0x100 {
+            raster
+                .get(..count)?
+                .iter()
+                .map(|&v| scale(v.into()))
+                .collect()
+        } else {
+            raster
+                .get(..count.checked_mul(2)?)?
+                .chunks_exact(2)
+                .map(|v| scale(usize::from(v[0]) << 8 | usize::from(v[1])))
+                .collect()
+        }
+    } else {
+        (0..count)
+            .map(|_| next_number(data, &mut pos).map(scale))
+            .collect::<Option<Vec<u8>>>()?
+    };
+    Some((width, height, pixels))
+}
+
+/// Parses the next decimal number, skipping whitespace and `#` comments.
+fn next_number(data: &[u8], pos: &mut usize) -> Option<usize> {
+    loop {
+        match data.get(*pos)? {
+            b'#' => {
+                while *data.get(*pos)? != b'\n' {
+                    *pos += 1;
+                }
+            }
+            c if c.is_ascii_whitespace() => *pos += 1,
+            _ => break,
+        }
+    }
+    let start = *pos;
+    while data.get(*pos).is_some_and(u8::is_ascii_digit) {
+        *pos += 1;
+    }
+    std::str::from_utf8(&data[start..*pos]).ok()?.parse().ok()
+}
+
+#[cfg(feature = "png")]
+fn decode_png(data: &[u8]) -> Result<(usize, usize, Vec<u8>), ImageError> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let channels = info.color_type.samples();
+    let luminance = buffer[..info.buffer_size()]
+        .chunks_exact(channels)
+        .map(|p| match info.color_type {
+            png::ColorType::Rgb | png::ColorType::Rgba => {
+                // Rec. 601 weights, in 1/256ths.
+                ((77 * u32::from(p[0]) + 150 * u32::from(p[1]) + 29 * u32::from(p[2])) >> 8) as u8
+            }
+            _ => p[0],
+        })
+        .collect();
+    Ok((info.width as usize, info.height as usize, luminance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pgm_decodes_binary_and_plain() {
+        let binary = b"P5\n# comment\n2 1\n255\n\x00\xFF";
+        assert_eq!(decode_pgm(binary), Some((2, 1, vec![0x00, 0xFF])));
+        let wide = b"P5 2 1 1023 \x03\xFF\x01\xFF";
+        assert_eq!(decode_pgm(wide), Some((2, 1, vec![0xFF, 0x7F])));
+        let plain = b"P2\n2 2\n# max\n15\n0 15\n5 20\n";
+        assert_eq!(decode_pgm(plain), Some((2, 2, vec![0, 255, 85, 255])));
+    }
+
+    #[test]
+    fn malformed_pgm_is_rejected() {
+        let too_large = b"P5 4294967296 4294967296 255 \x00";
+        assert!(matches!(
+            SensorImage::decode(too_large),
+            Err(ImageError::BadPgm)
+        ));
+        let overflowing = format!("P5 {} 1 65535 \x00", usize::MAX / 2 + 1);
+        assert!(matches!(
+            SensorImage::decode(overflowing.as_bytes()),
+            Err(ImageError::BadPgm)
+        ));
+        for data in [
+            &b"P5 2 2 255 \x00\x00\x00"[..],
+            b"P2 2 1 0 0 0",
+            b"P2 2 1 255 7",
+        ] {
+            assert!(matches!(SensorImage::decode(data), Err(ImageError::BadPgm)));
+        }
+        assert!(matches!(
+            SensorImage::decode(b"GIF89a"),
+            Err(ImageError::UnknownFormat)
+        ));
+    }
+
+    #[test]
+    fn luminance_must_fill_the_frame() {
+        assert!(matches!(
+            SensorImage::from_luminance(2, 2, &[0; 3]),
+            Err(ImageError::SizeMismatch {
+                expected: 4,
+                actual: 3
+            })
+        ));
+        assert!(matches!(
+            SensorImage::from_luminance(usize::MAX, 2, &[0; 2]),
+            Err(ImageError::SizeMismatch {
+                expected: usize::MAX,
+                actual: 2
+            })
+        ));
+        assert!(SensorImage::from_luminance(0, 0, &[]).is_err());
+
+        let image = SensorImage::from_luminance(2, 1, &[0x10, 0xF0]).unwrap();
+        assert_eq!(image.pixel(0, 0), 0x10);
+        assert_eq!(image.pixel(63, 111), 0x10);
+        assert_eq!(image.pixel(64, 0), 0xF0);
+        assert_eq!(image.pixel(500, -3), 0xF0);
+    }
+}
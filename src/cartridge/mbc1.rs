@@ -0,0 +1,218 @@
+//! MBC1, and the MBC1M wiring used by multicart compilations.
+//!
+//! MBC1 has a 5-bit register for the low ROM bank bits (BANK1) and a 2-bit
+//! register (BANK2) that either extends the ROM bank number or selects the
+//! RAM bank. In mode 1, BANK2 also applies to the 0x0000-0x3FFF area and to
+//! RAM; in mode 0 both use bank 0.
+//!
+//! MBC1M boards connect only four of the BANK1 lines, so BANK2 starts at bit
+//! 4 of the bank number and each 256 KiB quarter of the ROM holds one game.
+
+use super::header::NINTENDO_LOGO;
+use super::{ram_offset, read_rom_bank, Mbc, ROM_BANK_SIZE};
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank1: u8,
+    bank2: u8,
+    mode: bool,
+    /// Bit position of BANK2 in the ROM bank number: 5, or 4 on MBC1M.
+    bank2_shift: u8,
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Mbc1 {
+        Mbc1::with_bank2_shift(rom, ram_size, 5)
+    }
+
+    pub fn new_multicart(rom: Vec<u8>, ram_size: usize) -> Mbc1 {
+        Mbc1::with_bank2_shift(rom, ram_size, 4)
+    }
+
+    fn with_bank2_shift(rom: Vec<u8>, ram_size: usize, bank2_shift: u8) -> Mbc1 {
+        Mbc1 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: false,
+            bank2_shift,
+        }
+    }
+
+    /// MBC1M carts are 1 MiB and every game in them carries its own header,
+    /// so the Nintendo logo shows up again at the start of bank 0x10.
+    pub fn is_multicart(rom: &[u8]) -> bool {
+        if rom.len() != 64 * ROM_BANK_SIZE {
+            return false;
+        }
+        let logo = 0x10 * ROM_BANK_SIZE + 0x0104;
+        rom[logo..logo + NINTENDO_LOGO.len()] == NINTENDO_LOGO[..]
+    }
+
+    fn bank1_bits(&self) -> u8 {
+        if self.bank2_shift == 4 {
+            self.bank1 & 0x0F
+        } else {
+            self.bank1
+        }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.mode {
+            self.bank2 as usize
+        } else {
+            0
+        }
+    }
+}
+
+impl Mbc for Mbc1 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let high = (self.bank2 as usize) << self.bank2_shift;
+        let bank = if addr < 0x4000 {
+            if self.mode {
+                high
+            } else {
+                0
+            }
+        } else {
+            high | self.bank1_bits() as usize
+        };
+        read_rom_bank(&self.rom, bank, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            // A zero in BANK1 reads as 1. The check covers all five bits, so
+            // banks 0x20, 0x40 and 0x60 can't be reached through 0x4000.
+            0x2000..=0x3FFF => self.bank1 = (value & 0x1F).max(1),
+            0x4000..=0x5FFF => self.bank2 = value & 0x03,
+            _ => self.mode = value & 0x01 != 0,
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match ram_offset(&self.ram, self.ram_bank(), addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        if let Some(offset) = ram_offset(&self.ram, self.ram_bank(), addr) {
+            self.ram[offset] = value;
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = self.ram.len().min(data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.block(&self.ram);
+        w.bool(self.ram_enabled);
+        w.u8(self.bank1);
+        w.u8(self.bank2);
+        w.bool(self.mode);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.block(&mut self.ram)?;
+        self.ram_enabled = r.bool()?;
+        self.bank1 = r.u8()?;
+        self.bank2 = r.u8()?;
+        self.mode = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ROM of `banks` banks, each starting with its own number.
+    fn rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn mode_1_applies_bank2_to_the_bank_0_area() {
+        let mut mbc = Mbc1::new(rom(128), 0);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_rom(0x0000), 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x41);
+
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x40);
+        assert_eq!(mbc.read_rom(0x4000), 0x41);
+    }
+
+    #[test]
+    fn a_zero_in_bank1_selects_the_next_bank() {
+        let mut mbc = Mbc1::new(rom(128), 0);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x01);
+        for bank2 in 1..4 {
+            mbc.write_rom(0x4000, bank2);
+            // 0x20 lands on the same five zero bits.
+            mbc.write_rom(0x2000, 0x20);
+            assert_eq!(mbc.read_rom(0x4000), bank2 << 5 | 0x01);
+        }
+    }
+
+    #[test]
+    fn ram_banks_switch_only_in_mode_1() {
+        let mut mbc = Mbc1::new(rom(4), 0x8000);
+        mbc.write_ram(0xA000, 0x11);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x02);
+        mbc.write_ram(0xA000, 0x11);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        mbc.write_ram(0xA000, 0x22);
+        mbc.write_rom(0x6000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0x11);
+        assert_eq!(mbc.save_data()[2 * 0x2000], 0x22);
+    }
+
+    #[test]
+    fn multicarts_wire_bank2_from_bit_4() {
+        let mut rom = rom(64);
+        assert!(!Mbc1::is_multicart(&rom));
+        let logo = 0x10 * ROM_BANK_SIZE + 0x0104;
+        rom[logo..logo + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        assert!(Mbc1::is_multicart(&rom));
+
+        let mut mbc = Mbc1::new_multicart(rom, 0);
+        // Only four BANK1 lines are connected.
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(0x4000), 0x02);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x12);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x10);
+    }
+}
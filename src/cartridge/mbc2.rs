@@ -0,0 +1,137 @@
+//! MBC2, with 512 half-bytes of RAM built into the controller.
+//!
+//! Both registers live in 0x0000-0x3FFF and address bit 8 picks one: clear
+//! for RAM enable, set for the ROM bank. Only the low nibble of each RAM
+//! byte exists; the upper nibble reads as ones. The RAM repeats across the
+//! whole of 0xA000-0xBFFF.
+
+use super::{read_rom_bank, Mbc};
+use crate::state::{StateError, StateReader, StateWriter};
+
+const RAM_SIZE: usize = 512;
+
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: [u8; RAM_SIZE],
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            rom,
+            ram: [0; RAM_SIZE],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_bank(&self.rom, bank as usize, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        if addr >= 0x4000 {
+            return;
+        }
+        if addr & 0x0100 == 0 {
+            self.ram_enabled = value & 0x0F == 0x0A;
+        } else {
+            self.rom_bank = (value & 0x0F).max(1);
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        0xF0 | self.ram[addr as usize & (RAM_SIZE - 1)]
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if self.ram_enabled {
+            self.ram[addr as usize & (RAM_SIZE - 1)] = value & 0x0F;
+        }
+    }
+
+    /// One byte per half-byte cell, upper nibble clear.
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    /// Accepts files with either nibble layout; only the low nibble is used.
+    fn load_save_data(&mut self, data: &[u8]) {
+        for (cell, &byte) in self.ram.iter_mut().zip(data) {
+            *cell = byte & 0x0F;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.bool(self.ram_enabled);
+        w.u8(self.rom_bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.ram)?;
+        self.ram_enabled = r.bool()?;
+        self.rom_bank = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::ROM_BANK_SIZE;
+
+    /// 16 banks, each starting with its number.
+    fn mbc2() -> Mbc2 {
+        let mut rom = vec![0; 16 * ROM_BANK_SIZE];
+        for bank in 0..16 {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        Mbc2::new(rom)
+    }
+
+    #[test]
+    fn address_bit_8_picks_the_register() {
+        let mut mbc = mbc2();
+        // Bit 8 set: the ROM bank, with 0 reading as 1.
+        mbc.write_rom(0x2100, 0x05);
+        assert_eq!(mbc.read_rom(0x4000), 0x05);
+        mbc.write_rom(0x0100, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x01);
+        // Bit 8 clear: RAM enable, anywhere in 0x0000-0x3FFF.
+        mbc.write_rom(0x3E00, 0x0A);
+        assert_eq!(mbc.read_rom(0x4000), 0x01);
+        mbc.write_ram(0xA000, 0x03);
+        assert_eq!(mbc.read_ram(0xA000), 0xF3);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn ram_holds_half_bytes_and_repeats() {
+        let mut mbc = mbc2();
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA000, 0xAB);
+        mbc.write_ram(0xA1FF, 0x5C);
+        assert_eq!(mbc.read_ram(0xA000), 0xFB);
+        assert_eq!(mbc.read_ram(0xA1FF), 0xFC);
+        for mirror in (0xA000..0xC000).step_by(RAM_SIZE) {
+            assert_eq!(mbc.read_ram(mirror), 0xFB, "{:#06x}", mirror);
+            assert_eq!(mbc.read_ram(mirror + 0x1FF), 0xFC, "{:#06x}", mirror);
+        }
+        mbc.write_ram(0xBE00, 0x07);
+        assert_eq!(mbc.read_ram(0xA000), 0xF7);
+
+        let data = mbc.save_data();
+        assert_eq!(data.len(), RAM_SIZE);
+        assert_eq!((data[0], data[0x1FF]), (0x07, 0x0C));
+    }
+}
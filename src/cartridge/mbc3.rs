@@ -0,0 +1,241 @@
+//! MBC3, with or without the real-time clock.
+//!
+//! Also covers MBC30, the variant in the Japanese Pokémon Crystal with an
+//! 8-bit ROM bank register and eight RAM banks. It is told apart by a ROM
+//! larger than 2 MiB or a RAM larger than 32 KiB.
+
+use super::rtc::{Clock, Rtc, SHORT_TRAILER_SIZE, TRAILER_SIZE};
+use super::{ram_offset, read_rom_bank, split_trailer, Mbc};
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<Rtc>,
+    /// Enables both RAM and the RTC registers.
+    ram_enabled: bool,
+    rom_bank: u8,
+    rom_bank_mask: u8,
+    /// 0x00-0x07 selects a RAM bank, 0x08-0x0C an RTC register.
+    select: u8,
+}
+
+impl Mbc3 {
+    /// `clock` drives the RTC; pass `None` for carts without one.
+    pub fn new(rom: Vec<u8>, ram_size: usize, clock: Option<Box<dyn Clock>>) -> Mbc3 {
+        let mbc30 = rom.len() > 0x200000 || ram_size > 0x8000;
+        Mbc3 {
+            rom,
+            ram: vec![0; ram_size],
+            rtc: clock.map(Rtc::new),
+            ram_enabled: false,
+            rom_bank: 1,
+            rom_bank_mask: if mbc30 { 0xFF } else { 0x7F },
+            select: 0,
+        }
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_bank(&self.rom, bank as usize, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = (value & self.rom_bank_mask).max(1),
+            0x4000..=0x5FFF => self.select = value & 0x0F,
+            _ => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match self.select {
+            0x00..=0x07 => match ram_offset(&self.ram, self.select as usize, addr) {
+                Some(offset) => self.ram[offset],
+                None => 0xFF,
+            },
+            0x08..=0x0C => self.rtc.as_ref().map_or(0xFF, |rtc| rtc.read(self.select)),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match self.select {
+            0x00..=0x07 => {
+                if let Some(offset) = ram_offset(&self.ram, self.select as usize, addr) {
+                    self.ram[offset] = value;
+                }
+            }
+            0x08..=0x0C => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write(self.select, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = &self.rtc {
+            data.extend_from_slice(&rtc.save());
+        }
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        // Find the trailer from the end, so saves whose RAM is padded or cut
+        // short by other emulators still keep the clock.
+        let (ram, trailer) = split_trailer(data);
+        let ram_len = self.ram.len().min(ram.len());
+        self.ram[..ram_len].copy_from_slice(&ram[..ram_len]);
+
+        if let Some(rtc) = &mut self.rtc {
+            if trailer.len() == TRAILER_SIZE || trailer.len() == SHORT_TRAILER_SIZE {
+                rtc.load(trailer);
+            }
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.block(&self.ram);
+        w.bool(self.ram_enabled);
+        w.u8(self.rom_bank);
+        w.u8(self.select);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.block(&mut self.ram)?;
+        self.ram_enabled = r.bool()?;
+        self.rom_bank = r.u8()?;
+        self.select = r.u8()?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::rtc::ManualClock;
+
+    /// 32 KiB of RAM and a clock, with RAM and the clock registers enabled.
+    fn mbc3(now: u64) -> (Mbc3, ManualClock) {
+        let clock = ManualClock::new(now);
+        let mut mbc = Mbc3::new(vec![0; 0x8000], 0x8000, Some(Box::new(clock.clone())));
+        mbc.write_rom(0x0000, 0x0A);
+        (mbc, clock)
+    }
+
+    /// Latches and returns S, M, H, DL, DH through the register window.
+    fn latched(mbc: &mut Mbc3) -> [u8; 5] {
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        let mut registers = [0; 5];
+        for (select, value) in (0x08..).zip(registers.iter_mut()) {
+            mbc.write_rom(0x4000, select);
+            *value = mbc.read_ram(0xA000);
+        }
+        registers
+    }
+
+    /// A 48-byte trailer, or the 44-byte one if `short`.
+    fn trailer(live: [u8; 5], latched: [u8; 5], timestamp: u64, short: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &value in live.iter().chain(latched.iter()) {
+            data.extend_from_slice(&u32::from(value).to_le_bytes());
+        }
+        if short {
+            data.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        } else {
+            data.extend_from_slice(&timestamp.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn clock_registers_share_the_ram_window() {
+        let (mut mbc, clock) = mbc3(0);
+        mbc.write_ram(0xA000, 0x11);
+        mbc.write_rom(0x4000, 0x09);
+        mbc.write_ram(0xA000, 30);
+        clock.advance(90);
+        assert_eq!(latched(&mut mbc), [30, 31, 0, 0, 0]);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0x11);
+
+        mbc.write_rom(0x0000, 0x00);
+        mbc.write_rom(0x4000, 0x08);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn save_data_ends_with_the_trailer() {
+        let (mut mbc, clock) = mbc3(5000);
+        mbc.write_ram(0xA000, 0x42);
+        clock.advance(61);
+        let data = mbc.save_data();
+        assert_eq!(data.len(), 0x8000 + TRAILER_SIZE);
+        assert_eq!(data[0], 0x42);
+        assert_eq!(
+            data[0x8000..],
+            trailer([1, 1, 0, 0, 0], [0; 5], 5061, false)[..]
+        );
+    }
+
+    #[test]
+    fn both_trailer_sizes_load() {
+        for &short in &[false, true] {
+            let (mut mbc, _) = mbc3(100_000 + 3600);
+            let mut data = vec![0x42; 0x8000];
+            data.extend(trailer([0, 0, 5, 7, 0], [1, 2, 3, 4, 0], 100_000, short));
+            mbc.load_save_data(&data);
+            assert_eq!(mbc.read_ram(0xA000), 0x42);
+            mbc.write_rom(0x4000, 0x08);
+            assert_eq!(mbc.read_ram(0xA000), 1, "short: {}", short);
+            assert_eq!(latched(&mut mbc), [0, 0, 6, 7, 0], "short: {}", short);
+        }
+    }
+
+    #[test]
+    fn trailers_are_found_past_short_or_padded_ram() {
+        for &ram_len in &[0x2000, 0x10000] {
+            let (mut mbc, _) = mbc3(1000);
+            let mut data = vec![0x42; ram_len];
+            data.extend(trailer([9, 0, 0, 0, 0], [0; 5], 1000, false));
+            mbc.load_save_data(&data);
+            assert_eq!(latched(&mut mbc)[0], 9, "{:#x} bytes of RAM", ram_len);
+            mbc.write_rom(0x4000, 0x03);
+            assert_eq!(
+                mbc.read_ram(0xBFFF),
+                if ram_len > 0x2000 { 0x42 } else { 0x00 }
+            );
+        }
+
+        // Anything else after RAM is not a trailer.
+        let (mut mbc, _) = mbc3(1000);
+        let mut data = vec![0; 0x8000];
+        data.extend(trailer([9, 0, 0, 0, 0], [0; 5], 1000, false));
+        data.pop();
+        mbc.load_save_data(&data);
+        assert_eq!(latched(&mut mbc)[0], 0);
+    }
+}
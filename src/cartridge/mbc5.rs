@@ -0,0 +1,194 @@
+//! MBC5, with a 9-bit ROM bank number and up to 16 RAM banks.
+//!
+//! On rumble cartridges bit 3 of the RAM bank register drives the motor
+//! instead of a RAM address line. Changes to the motor state are passed to
+//! the callback installed with [`Mbc::set_rumble_callback`].
+
+use super::{ram_offset, read_rom_bank, Mbc, RumbleCallback};
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    rumble: bool,
+    motor: bool,
+    on_rumble: Option<RumbleCallback>,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, rumble: bool) -> Mbc5 {
+        Mbc5 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rumble,
+            motor: false,
+            on_rumble: None,
+        }
+    }
+
+    fn set_motor(&mut self, on: bool) {
+        if on == self.motor {
+            return;
+        }
+        self.motor = on;
+        if let Some(callback) = &mut self.on_rumble {
+            callback(on);
+        }
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_bank(&self.rom, bank as usize, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            // Unlike earlier controllers, bank 0 can be mapped at 0x4000.
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | u16::from(value),
+            0x3000..=0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0xFF) | (u16::from(value & 0x01) << 8)
+            }
+            0x4000..=0x5FFF => {
+                if self.rumble {
+                    self.ram_bank = value & 0x07;
+                    self.set_motor(value & 0x08 != 0);
+                } else {
+                    self.ram_bank = value & 0x0F;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match ram_offset(&self.ram, self.ram_bank as usize, addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        if let Some(offset) = ram_offset(&self.ram, self.ram_bank as usize, addr) {
+            self.ram[offset] = value;
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = self.ram.len().min(data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.block(&self.ram);
+        w.bool(self.ram_enabled);
+        w.u16(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.motor);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.block(&mut self.ram)?;
+        self.ram_enabled = r.bool()?;
+        self.rom_bank = r.u16()? & 0x1FF;
+        self.ram_bank = r.u8()?;
+        // Goes through the callback so the host's motor follows.
+        let motor = r.bool()?;
+        self.set_motor(motor);
+        Ok(())
+    }
+
+    fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.on_rumble = Some(callback);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::cartridge::ROM_BANK_SIZE;
+
+    /// The full 512 banks, each starting with its number.
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 512 * ROM_BANK_SIZE];
+        for bank in 0..512 {
+            let start = bank * ROM_BANK_SIZE;
+            rom[start..start + 2].copy_from_slice(&(bank as u16).to_le_bytes());
+        }
+        rom
+    }
+
+    fn bank_at_0x4000(mbc: &Mbc5) -> u16 {
+        u16::from_le_bytes([mbc.read_rom(0x4000), mbc.read_rom(0x4001)])
+    }
+
+    #[test]
+    fn the_ninth_rom_bank_bit_is_written_at_0x3000() {
+        let mut mbc = Mbc5::new(rom(), 0, false);
+        assert_eq!(bank_at_0x4000(&mbc), 1);
+        mbc.write_rom(0x2000, 0x34);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(bank_at_0x4000(&mbc), 0x134);
+        mbc.write_rom(0x2000, 0xFF);
+        assert_eq!(bank_at_0x4000(&mbc), 0x1FF);
+        // Only bit 0 of the high register is used.
+        mbc.write_rom(0x3000, 0xFE);
+        assert_eq!(bank_at_0x4000(&mbc), 0x0FF);
+    }
+
+    #[test]
+    fn bank_0_can_be_mapped_at_0x4000() {
+        let mut mbc = Mbc5::new(rom(), 0, false);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at_0x4000(&mbc), 0);
+    }
+
+    #[test]
+    fn ram_bank_bit_3_drives_the_rumble_motor() {
+        let mut mbc = Mbc5::new(rom(), 0x8000, true);
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let seen = changes.clone();
+        mbc.set_rumble_callback(Box::new(move |on| seen.borrow_mut().push(on)));
+        mbc.write_rom(0x0000, 0x0A);
+
+        mbc.write_rom(0x4000, 0x09);
+        mbc.write_ram(0xA000, 0x42);
+        mbc.write_rom(0x4000, 0x0B);
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(*changes.borrow(), [true, false]);
+        // Bit 3 is not a RAM address line, so bank 9 was bank 1.
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+        assert_eq!(mbc.save_data()[0x2000], 0x42);
+
+        // Without rumble, bit 3 selects RAM and leaves the callback alone.
+        let mut mbc = Mbc5::new(rom(), 0x20000, false);
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let seen = changes.clone();
+        mbc.set_rumble_callback(Box::new(move |on| seen.borrow_mut().push(on)));
+        mbc.write_rom(0x4000, 0x08);
+        assert!(changes.borrow().is_empty());
+    }
+}
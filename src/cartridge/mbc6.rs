@@ -0,0 +1,335 @@
+//! MBC6, used only by Net de Get: Minigame @ 100.
+//!
+//! The switchable areas are split in two halves with their own bank
+//! registers: 8 KiB at 0x4000 and 0x6000, each mapping ROM or the 1 MiB flash
+//! chip, and 4 KiB of RAM at 0xA000 and 0xB000.
+//!
+//! | Address | Register                                    |
+//! |---------|---------------------------------------------|
+//! | 0x0000  | RAM enable (0x0A)                           |
+//! | 0x0400  | RAM bank A                                  |
+//! | 0x0800  | RAM bank B                                  |
+//! | 0x0C00  | Flash enable (bit 0)                        |
+//! | 0x1000  | Flash write enable (bit 0)                  |
+//! | 0x2000  | ROM/flash bank A                            |
+//! | 0x2800  | Area A source: 0x00 for ROM, 0x08 for flash |
+//! | 0x3000  | ROM/flash bank B                            |
+//! | 0x3800  | Area B source                               |
+//!
+//! The flash accepts the usual JEDEC command sequences: byte program, sector
+//! and chip erase, and the ID read. Operations complete instantly. Its
+//! contents are saved after the RAM.
+
+use super::Mbc;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const HALF_ROM_BANK: usize = 0x2000;
+const HALF_RAM_BANK: usize = 0x1000;
+const RAM_SIZE: usize = 0x8000;
+const FLASH_SIZE: usize = 0x100000;
+const FLASH_SECTOR: usize = 0x10000;
+
+/// Macronix MX29F008.
+const FLASH_MANUFACTURER_ID: u8 = 0xC2;
+const FLASH_DEVICE_ID: u8 = 0x81;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FlashState {
+    Read,
+    /// First unlock byte (0xAA at 0x5555) seen.
+    Unlock1,
+    /// Second unlock byte (0x55 at 0x2AAA) seen; a command comes next.
+    Unlock2,
+    /// Next write programs a byte.
+    Program,
+    /// 0x80 received; an erase needs another unlock sequence.
+    EraseUnlock0,
+    EraseUnlock1,
+    EraseUnlock2,
+    /// Reads return the chip IDs.
+    Id,
+}
+
+impl FlashState {
+    const ALL: [FlashState; 8] = [
+        FlashState::Read,
+        FlashState::Unlock1,
+        FlashState::Unlock2,
+        FlashState::Program,
+        FlashState::EraseUnlock0,
+        FlashState::EraseUnlock1,
+        FlashState::EraseUnlock2,
+        FlashState::Id,
+    ];
+}
+
+pub struct Mbc6 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    flash: Vec<u8>,
+    ram_enabled: bool,
+    ram_banks: [u8; 2],
+    rom_banks: [u8; 2],
+    flash_selected: [bool; 2],
+    flash_enabled: bool,
+    flash_write_enabled: bool,
+    flash_state: FlashState,
+}
+
+impl Mbc6 {
+    pub fn new(rom: Vec<u8>) -> Mbc6 {
+        Mbc6 {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            flash: vec![0xFF; FLASH_SIZE],
+            ram_enabled: false,
+            ram_banks: [0; 2],
+            rom_banks: [0; 2],
+            flash_selected: [false; 2],
+            flash_enabled: false,
+            flash_write_enabled: false,
+            flash_state: FlashState::Read,
+        }
+    }
+
+    fn flash_offset(&self, area: usize, addr: u16) -> usize {
+        let bank = self.rom_banks[area] as usize % (FLASH_SIZE / HALF_ROM_BANK);
+        bank * HALF_ROM_BANK + (addr as usize & (HALF_ROM_BANK - 1))
+    }
+
+    fn ram_offset(&self, addr: u16) -> usize {
+        let area = ((addr >> 12) & 1) as usize;
+        let bank = self.ram_banks[area] as usize % (RAM_SIZE / HALF_RAM_BANK);
+        bank * HALF_RAM_BANK + (addr as usize & (HALF_RAM_BANK - 1))
+    }
+
+    fn write_flash(&mut self, offset: usize, value: u8) {
+        // Commands are decoded from the low 15 address bits.
+        let command_addr = offset & 0x7FFF;
+        self.flash_state = match (self.flash_state, command_addr, value) {
+            (FlashState::Program, _, _) => {
+                // Programming can only clear bits.
+                if self.flash_write_enabled {
+                    self.flash[offset] &= value;
+                }
+                FlashState::Read
+            }
+            (_, _, 0xF0) => FlashState::Read,
+            (FlashState::Read, 0x5555, 0xAA) | (FlashState::Id, 0x5555, 0xAA) => {
+                FlashState::Unlock1
+            }
+            (FlashState::Unlock1, 0x2AAA, 0x55) => FlashState::Unlock2,
+            (FlashState::Unlock2, 0x5555, 0xA0) => FlashState::Program,
+            (FlashState::Unlock2, 0x5555, 0x80) => FlashState::EraseUnlock0,
+            (FlashState::Unlock2, 0x5555, 0x90) => FlashState::Id,
+            (FlashState::EraseUnlock0, 0x5555, 0xAA) => FlashState::EraseUnlock1,
+            (FlashState::EraseUnlock1, 0x2AAA, 0x55) => FlashState::EraseUnlock2,
+            (FlashState::EraseUnlock2, 0x5555, 0x10) => {
+                if self.flash_write_enabled {
+                    self.flash.fill(0xFF);
+                }
+                FlashState::Read
+            }
+            (FlashState::EraseUnlock2, _, 0x30) => {
+                if self.flash_write_enabled {
+                    let start = offset / FLASH_SECTOR * FLASH_SECTOR;
+                    self.flash[start..start + FLASH_SECTOR].fill(0xFF);
+                }
+                FlashState::Read
+            }
+            (FlashState::Id, _, _) => FlashState::Id,
+            _ => FlashState::Read,
+        };
+    }
+}
+
+impl Mbc for Mbc6 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        if addr < 0x4000 {
+            return self.rom.get(addr as usize).copied().unwrap_or(0xFF);
+        }
+        let area = ((addr >> 13) & 1) as usize;
+        if self.flash_selected[area] {
+            if !self.flash_enabled {
+                return 0xFF;
+            }
+            let offset = self.flash_offset(area, addr);
+            if self.flash_state == FlashState::Id {
+                return match offset & 0x01 {
+                    0 => FLASH_MANUFACTURER_ID,
+                    _ => FLASH_DEVICE_ID,
+                };
+            }
+            return self.flash[offset];
+        }
+        let banks = (self.rom.len() / HALF_ROM_BANK).max(1);
+        let bank = self.rom_banks[area] as usize % banks;
+        let offset = bank * HALF_ROM_BANK + (addr as usize & (HALF_ROM_BANK - 1));
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x03FF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x0400..=0x07FF => self.ram_banks[0] = value & 0x07,
+            0x0800..=0x0BFF => self.ram_banks[1] = value & 0x07,
+            0x0C00..=0x0FFF => self.flash_enabled = value & 0x01 != 0,
+            0x1000 => self.flash_write_enabled = value & 0x01 != 0,
+            0x2000..=0x27FF => self.rom_banks[0] = value & 0x7F,
+            0x2800..=0x2FFF => self.flash_selected[0] = value == 0x08,
+            0x3000..=0x37FF => self.rom_banks[1] = value & 0x7F,
+            0x3800..=0x3FFF => self.flash_selected[1] = value == 0x08,
+            0x4000..=0x7FFF => {
+                let area = ((addr >> 13) & 1) as usize;
+                if self.flash_selected[area] && self.flash_enabled {
+                    let offset = self.flash_offset(area, addr);
+                    self.write_flash(offset, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        self.ram[self.ram_offset(addr)]
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if self.ram_enabled {
+            let offset = self.ram_offset(addr);
+            self.ram[offset] = value;
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        data.extend_from_slice(&self.flash);
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let ram_len = RAM_SIZE.min(data.len());
+        self.ram[..ram_len].copy_from_slice(&data[..ram_len]);
+        let flash = &data[ram_len..];
+        let flash_len = FLASH_SIZE.min(flash.len());
+        self.flash[..flash_len].copy_from_slice(&flash[..flash_len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.block(&self.ram);
+        w.block(&self.flash);
+        w.bool(self.ram_enabled);
+        w.bytes(&self.ram_banks);
+        w.bytes(&self.rom_banks);
+        w.bool(self.flash_selected[0]);
+        w.bool(self.flash_selected[1]);
+        w.bool(self.flash_enabled);
+        w.bool(self.flash_write_enabled);
+        w.u8(self.flash_state as u8);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.block(&mut self.ram)?;
+        r.block(&mut self.flash)?;
+        self.ram_enabled = r.bool()?;
+        r.bytes(&mut self.ram_banks)?;
+        r.bytes(&mut self.rom_banks)?;
+        self.flash_selected = [r.bool()?, r.bool()?];
+        self.flash_enabled = r.bool()?;
+        self.flash_write_enabled = r.bool()?;
+        self.flash_state = *FlashState::ALL
+            .get(r.u8()? as usize)
+            .ok_or(StateError::Invalid("flash state"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An MBC6 with the flash enabled and mapped in both areas: bank 2 in
+    /// area A, where 0x5555 is command address 0x5555, and bank 1 in area B,
+    /// where 0x6AAA is command address 0x2AAA.
+    fn mbc6() -> Mbc6 {
+        let mut mbc = Mbc6::new(vec![0; 0x8000]);
+        mbc.write_rom(0x0C00, 0x01);
+        mbc.write_rom(0x2000, 0x02);
+        mbc.write_rom(0x2800, 0x08);
+        mbc.write_rom(0x3000, 0x01);
+        mbc.write_rom(0x3800, 0x08);
+        mbc
+    }
+
+    fn unlock(mbc: &mut Mbc6) {
+        mbc.write_rom(0x5555, 0xAA);
+        mbc.write_rom(0x6AAA, 0x55);
+    }
+
+    fn command(mbc: &mut Mbc6, command: u8) {
+        unlock(mbc);
+        mbc.write_rom(0x5555, command);
+    }
+
+    fn program(mbc: &mut Mbc6, addr: u16, value: u8) {
+        command(mbc, 0xA0);
+        mbc.write_rom(addr, value);
+    }
+
+    #[test]
+    fn programming_needs_write_enable_and_only_clears_bits() {
+        let mut mbc = mbc6();
+        program(&mut mbc, 0x4010, 0x5A);
+        assert_eq!(mbc.read_rom(0x4010), 0xFF);
+
+        mbc.write_rom(0x1000, 0x01);
+        program(&mut mbc, 0x4010, 0x5A);
+        assert_eq!(mbc.read_rom(0x4010), 0x5A);
+        program(&mut mbc, 0x4010, 0x0F);
+        assert_eq!(mbc.read_rom(0x4010), 0x0A);
+        // Bank 2, offset 0x10, saved after the RAM.
+        assert_eq!(mbc.save_data()[RAM_SIZE + 0x4010], 0x0A);
+
+        // With flash disabled the areas read open bus.
+        mbc.write_rom(0x0C00, 0x00);
+        assert_eq!(mbc.read_rom(0x4010), 0xFF);
+    }
+
+    #[test]
+    fn the_id_read_lasts_until_reset() {
+        let mut mbc = mbc6();
+        command(&mut mbc, 0x90);
+        assert_eq!(mbc.read_rom(0x4000), FLASH_MANUFACTURER_ID);
+        assert_eq!(mbc.read_rom(0x4001), FLASH_DEVICE_ID);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_rom(0x4001), FLASH_DEVICE_ID);
+        mbc.write_rom(0x4000, 0xF0);
+        assert_eq!(mbc.read_rom(0x4001), 0xFF);
+    }
+
+    #[test]
+    fn erase_clears_a_sector_or_the_whole_chip() {
+        let mut mbc = mbc6();
+        mbc.write_rom(0x1000, 0x01);
+        program(&mut mbc, 0x4010, 0x00);
+        // Bank 8 starts the second sector.
+        command(&mut mbc, 0xA0);
+        mbc.write_rom(0x3000, 0x08);
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x3000, 0x01);
+
+        command(&mut mbc, 0x80);
+        unlock(&mut mbc);
+        mbc.write_rom(0x4010, 0x30);
+        let flash = &mbc.save_data()[RAM_SIZE..];
+        assert_eq!(flash[0x4010], 0xFF);
+        assert_eq!(flash[FLASH_SECTOR], 0x00);
+
+        command(&mut mbc, 0x80);
+        command(&mut mbc, 0x10);
+        assert!(mbc.save_data()[RAM_SIZE..].iter().all(|&byte| byte == 0xFF));
+    }
+}
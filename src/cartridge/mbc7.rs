@@ -0,0 +1,514 @@
+//! MBC7: ROM banking, a two-axis accelerometer and a 93LC56 serial EEPROM
+//! in place of RAM.
+//!
+//! 0xA000-0xAFFF only responds once both enable registers are set, and
+//! decodes address bits 4-7 into sixteen registers:
+//!
+//! | Register | Function                                      |
+//! |----------|-----------------------------------------------|
+//! | 0x0      | Write 0x55 to reset the accelerometer latch   |
+//! | 0x1      | Write 0xAA to latch the accelerometer         |
+//! | 0x2-0x3  | Latched X, low and high byte                  |
+//! | 0x4-0x5  | Latched Y, low and high byte                  |
+//! | 0x6      | Always 0x00                                   |
+//! | 0x7      | Always 0xFF                                   |
+//! | 0x8      | EEPROM lines: CS (7), CLK (6), DI (1), DO (0) |
+//!
+//! The tilt comes from [`Mbc::set_tilt`], in units of g where ±1.0 is about
+//! as far as the game expects the console to be turned.
+
+use super::{read_rom_bank, Mbc};
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Accelerometer reading when level.
+const ACCEL_CENTER: f32 = 0x81D0 as f32;
+/// Change in the reading per g.
+const ACCEL_SCALE: f32 = 0x70 as f32;
+/// Reading while the latch is reset.
+const ACCEL_UNLATCHED: u16 = 0x8000;
+
+pub struct Mbc7 {
+    rom: Vec<u8>,
+    rom_bank: u8,
+    ram_enable1: bool,
+    ram_enable2: bool,
+    tilt: (f32, f32),
+    latch_ready: bool,
+    x_latch: u16,
+    y_latch: u16,
+    eeprom: Eeprom,
+}
+
+impl Mbc7 {
+    pub fn new(rom: Vec<u8>) -> Mbc7 {
+        Mbc7 {
+            rom,
+            rom_bank: 1,
+            ram_enable1: false,
+            ram_enable2: false,
+            tilt: (0.0, 0.0),
+            latch_ready: false,
+            x_latch: ACCEL_UNLATCHED,
+            y_latch: ACCEL_UNLATCHED,
+            eeprom: Eeprom::new(),
+        }
+    }
+
+    fn registers_enabled(&self) -> bool {
+        self.ram_enable1 && self.ram_enable2
+    }
+}
+
+impl Mbc for Mbc7 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_bank(&self.rom, bank as usize, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enable1 = value == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = value & 0x7F,
+            0x4000..=0x5FFF => self.ram_enable2 = value == 0x40,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.registers_enabled() || addr >= 0xB000 {
+            return 0xFF;
+        }
+        match (addr >> 4) & 0x0F {
+            0x2 => self.x_latch as u8,
+            0x3 => (self.x_latch >> 8) as u8,
+            0x4 => self.y_latch as u8,
+            0x5 => (self.y_latch >> 8) as u8,
+            0x6 => 0x00,
+            0x8 => self.eeprom.read(),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if !self.registers_enabled() || addr >= 0xB000 {
+            return;
+        }
+        match (addr >> 4) & 0x0F {
+            0x0 if value == 0x55 => {
+                self.latch_ready = true;
+                self.x_latch = ACCEL_UNLATCHED;
+                self.y_latch = ACCEL_UNLATCHED;
+            }
+            0x1 if value == 0xAA && self.latch_ready => {
+                self.latch_ready = false;
+                let (x, y) = self.tilt;
+                self.x_latch = (ACCEL_CENTER + x * ACCEL_SCALE) as u16;
+                self.y_latch = (ACCEL_CENTER + y * ACCEL_SCALE) as u16;
+            }
+            0x8 => self.eeprom.write(value),
+            _ => {}
+        }
+    }
+
+    /// The EEPROM contents, as 128 little-endian words.
+    fn save_data(&self) -> Vec<u8> {
+        self.eeprom
+            .words
+            .iter()
+            .flat_map(|word| word.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        for (word, bytes) in self.eeprom.words.iter_mut().zip(data.chunks_exact(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+    }
+
+    /// The tilt is host input and is left as it is.
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.rom_bank);
+        w.bool(self.ram_enable1);
+        w.bool(self.ram_enable2);
+        w.bool(self.latch_ready);
+        w.u16(self.x_latch);
+        w.u16(self.y_latch);
+        self.eeprom.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.rom_bank = r.u8()?;
+        self.ram_enable1 = r.bool()?;
+        self.ram_enable2 = r.bool()?;
+        self.latch_ready = r.bool()?;
+        self.x_latch = r.u16()?;
+        self.y_latch = r.u16()?;
+        self.eeprom.load_state(r)
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x.clamp(-2.0, 2.0), y.clamp(-2.0, 2.0));
+    }
+}
+
+const EEPROM_WORDS: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EepromState {
+    /// Waiting for a start bit.
+    Idle,
+    /// Shifting in the two opcode bits and eight address bits.
+    Command { bits: u16, count: u8 },
+    /// Shifting out words, most significant bit first. Reads continue into
+    /// the following words for as long as CS stays high.
+    Reading { addr: u8, word: u16, count: u8 },
+    /// Shifting in a data word for WRITE, or for WRAL when `addr` is `None`.
+    Writing {
+        addr: Option<u8>,
+        word: u16,
+        count: u8,
+    },
+    /// The command is complete; wait for CS to drop.
+    Done,
+}
+
+/// A 93LC56 in 16-bit organisation, driven bit by bit over its Microwire
+/// lines. Programming is instant, so the chip always reports ready.
+struct Eeprom {
+    words: [u16; EEPROM_WORDS],
+    state: EepromState,
+    write_enabled: bool,
+    cs: bool,
+    clk: bool,
+    di: bool,
+    dout: bool,
+}
+
+impl Eeprom {
+    fn new() -> Eeprom {
+        Eeprom {
+            words: [0xFFFF; EEPROM_WORDS],
+            state: EepromState::Idle,
+            write_enabled: false,
+            cs: false,
+            clk: false,
+            di: false,
+            dout: true,
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        for &word in self.words.iter() {
+            w.u16(word);
+        }
+        // Every state as a kind, two 16-bit fields and a count.
+        let (kind, a, b, count) = match self.state {
+            EepromState::Idle => (0, 0, 0, 0),
+            EepromState::Command { bits, count } => (1, bits, 0, count),
+            EepromState::Reading { addr, word, count } => (2, u16::from(addr), word, count),
+            EepromState::Writing { addr, word, count } => {
+                (3, addr.map_or(0xFFFF, u16::from), word, count)
+            }
+            EepromState::Done => (4, 0, 0, 0),
+        };
+        w.u8(kind);
+        w.u16(a);
+        w.u16(b);
+        w.u8(count);
+        w.bool(self.write_enabled);
+        w.bool(self.cs);
+        w.bool(self.clk);
+        w.bool(self.di);
+        w.bool(self.dout);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for word in self.words.iter_mut() {
+            *word = r.u16()?;
+        }
+        let (kind, a, b, count) = (r.u8()?, r.u16()?, r.u16()?, r.u8()?);
+        let addr = (a as u8) % EEPROM_WORDS as u8;
+        self.state = match kind {
+            0 => EepromState::Idle,
+            1 if count < 10 => EepromState::Command { bits: a, count },
+            2 if count < 16 => EepromState::Reading {
+                addr,
+                word: b,
+                count,
+            },
+            3 if count < 16 => EepromState::Writing {
+                addr: if a == 0xFFFF { None } else { Some(addr) },
+                word: b,
+                count,
+            },
+            4 => EepromState::Done,
+            1..=3 => return Err(StateError::Invalid("EEPROM bit count")),
+            _ => return Err(StateError::Invalid("EEPROM state")),
+        };
+        self.write_enabled = r.bool()?;
+        self.cs = r.bool()?;
+        self.clk = r.bool()?;
+        self.di = r.bool()?;
+        self.dout = r.bool()?;
+        Ok(())
+    }
+
+    fn read(&self) -> u8 {
+        (self.cs as u8) << 7 | (self.clk as u8) << 6 | (self.di as u8) << 1 | self.dout as u8
+    }
+
+    fn write(&mut self, value: u8) {
+        let cs = value & 0x80 != 0;
+        let clk = value & 0x40 != 0;
+        self.di = value & 0x02 != 0;
+
+        if !cs {
+            self.state = EepromState::Idle;
+            self.dout = true;
+        } else if !self.clk && clk {
+            self.clock_in(self.di);
+        }
+        self.cs = cs;
+        self.clk = clk;
+    }
+
+    /// Handles a rising edge on CLK.
+    fn clock_in(&mut self, bit: bool) {
+        self.state = match self.state {
+            EepromState::Idle if bit => EepromState::Command { bits: 0, count: 0 },
+            EepromState::Idle => EepromState::Idle,
+            EepromState::Command { bits, count } => {
+                let bits = bits << 1 | bit as u16;
+                if count + 1 < 10 {
+                    EepromState::Command {
+                        bits,
+                        count: count + 1,
+                    }
+                } else {
+                    self.decode(bits)
+                }
+            }
+            EepromState::Reading { addr, word, count } => {
+                self.dout = word & 0x8000 != 0;
+                if count + 1 < 16 {
+                    EepromState::Reading {
+                        addr,
+                        word: word << 1,
+                        count: count + 1,
+                    }
+                } else {
+                    let addr = (addr + 1) % EEPROM_WORDS as u8;
+                    EepromState::Reading {
+                        addr,
+                        word: self.words[addr as usize],
+                        count: 0,
+                    }
+                }
+            }
+            EepromState::Writing { addr, word, count } => {
+                let word = word << 1 | bit as u16;
+                if count + 1 < 16 {
+                    EepromState::Writing {
+                        addr,
+                        word,
+                        count: count + 1,
+                    }
+                } else {
+                    if self.write_enabled {
+                        match addr {
+                            Some(addr) => self.words[addr as usize] = word,
+                            None => self.words = [word; EEPROM_WORDS],
+                        }
+                    }
+                    self.dout = true;
+                    EepromState::Done
+                }
+            }
+            EepromState::Done => EepromState::Done,
+        };
+    }
+
+    fn decode(&mut self, bits: u16) -> EepromState {
+        // The top address bit is a don't-care in 16-bit mode.
+        let addr = (bits & 0x7F) as u8;
+        match bits >> 8 {
+            0b10 => {
+                // A dummy zero comes out before the data.
+                self.dout = false;
+                EepromState::Reading {
+                    addr,
+                    word: self.words[addr as usize],
+                    count: 0,
+                }
+            }
+            0b01 => EepromState::Writing {
+                addr: Some(addr),
+                word: 0,
+                count: 0,
+            },
+            0b11 => {
+                if self.write_enabled {
+                    self.words[addr as usize] = 0xFFFF;
+                }
+                EepromState::Done
+            }
+            _ => match (bits >> 6) & 0x03 {
+                0b00 => {
+                    self.write_enabled = false;
+                    EepromState::Done
+                }
+                0b01 => EepromState::Writing {
+                    addr: None,
+                    word: 0,
+                    count: 0,
+                },
+                0b10 => {
+                    if self.write_enabled {
+                        self.words = [0xFFFF; EEPROM_WORDS];
+                    }
+                    EepromState::Done
+                }
+                _ => {
+                    self.write_enabled = true;
+                    EepromState::Done
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An MBC7 with both enable registers set.
+    fn mbc7() -> Mbc7 {
+        let mut mbc = Mbc7::new(vec![0; 0x8000]);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x40);
+        mbc
+    }
+
+    /// Clocks `count` bits of `value`, most significant first, into the
+    /// EEPROM with CS high, and returns what DO showed after each.
+    fn shift(mbc: &mut Mbc7, value: u32, count: u32) -> u32 {
+        let mut out = 0;
+        for i in (0..count).rev() {
+            let di = if value >> i & 1 != 0 { 0x02 } else { 0x00 };
+            mbc.write_ram(0xA080, 0x80 | di);
+            mbc.write_ram(0xA080, 0xC0 | di);
+            out = out << 1 | u32::from(mbc.read_ram(0xA080) & 0x01);
+        }
+        out
+    }
+
+    /// Sends a start bit and a 10-bit command, then `data_bits` of `data`.
+    fn command(mbc: &mut Mbc7, bits: u32, data: u32, data_bits: u32) -> u32 {
+        mbc.write_ram(0xA080, 0x00);
+        shift(mbc, 1 << 10 | bits, 11);
+        let out = shift(mbc, data, data_bits);
+        mbc.write_ram(0xA080, 0x00);
+        out
+    }
+
+    fn read_word(mbc: &mut Mbc7, addr: u32) -> u16 {
+        // The dummy zero comes out on the last address bit.
+        command(mbc, 0b10 << 8 | addr, 0, 16) as u16
+    }
+
+    #[test]
+    fn eeprom_writes_need_ewen() {
+        let mut mbc = mbc7();
+        assert_eq!(read_word(&mut mbc, 0x05), 0xFFFF);
+        command(&mut mbc, 0b01 << 8 | 0x05, 0x1234, 16);
+        assert_eq!(read_word(&mut mbc, 0x05), 0xFFFF);
+
+        command(&mut mbc, 0b00_11 << 6, 0, 0); // EWEN
+        command(&mut mbc, 0b01 << 8 | 0x05, 0x1234, 16);
+        command(&mut mbc, 0b01 << 8 | 0x06, 0xBEEF, 16);
+        assert_eq!(read_word(&mut mbc, 0x05), 0x1234);
+        assert_eq!(read_word(&mut mbc, 0x06), 0xBEEF);
+        // The top address bit is ignored.
+        assert_eq!(read_word(&mut mbc, 0x85), 0x1234);
+        assert_eq!(&mbc.save_data()[0x0A..0x0E], &[0x34, 0x12, 0xEF, 0xBE]);
+
+        command(&mut mbc, 0b00_00 << 6, 0, 0); // EWDS
+        command(&mut mbc, 0b11 << 8 | 0x05, 0, 0); // ERASE
+        assert_eq!(read_word(&mut mbc, 0x05), 0x1234);
+    }
+
+    #[test]
+    fn eeprom_erases_words_and_the_whole_chip() {
+        let mut mbc = mbc7();
+        command(&mut mbc, 0b00_11 << 6, 0, 0); // EWEN
+        command(&mut mbc, 0b00_01 << 6, 0x5A5A, 16); // WRAL
+        assert_eq!(read_word(&mut mbc, 0x00), 0x5A5A);
+        assert_eq!(read_word(&mut mbc, 0x7F), 0x5A5A);
+
+        command(&mut mbc, 0b11 << 8 | 0x10, 0, 0); // ERASE
+        assert_eq!(read_word(&mut mbc, 0x10), 0xFFFF);
+        assert_eq!(read_word(&mut mbc, 0x11), 0x5A5A);
+
+        command(&mut mbc, 0b00_10 << 6, 0, 0); // ERAL
+        assert!(mbc.save_data().iter().all(|&byte| byte == 0xFF));
+    }
+
+    #[test]
+    fn reads_run_on_into_the_next_word() {
+        let mut mbc = mbc7();
+        mbc.load_save_data(&[0x11, 0x11, 0x22, 0x22]);
+        mbc.write_ram(0xA080, 0x00);
+        shift(&mut mbc, 0b110 << 8, 11); // READ from word 0
+        assert_eq!(shift(&mut mbc, 0, 32), 0x1111_2222);
+    }
+
+    #[test]
+    fn the_accelerometer_latches_on_0x55_then_0xaa() {
+        let mut mbc = mbc7();
+        mbc.set_tilt(1.0, -0.5);
+        let latched = |mbc: &Mbc7| {
+            let x = u16::from_le_bytes([mbc.read_ram(0xA020), mbc.read_ram(0xA030)]);
+            let y = u16::from_le_bytes([mbc.read_ram(0xA040), mbc.read_ram(0xA050)]);
+            (x, y)
+        };
+        assert_eq!(latched(&mbc), (0x8000, 0x8000));
+        // 0xAA alone does nothing.
+        mbc.write_ram(0xA010, 0xAA);
+        assert_eq!(latched(&mbc), (0x8000, 0x8000));
+
+        mbc.write_ram(0xA000, 0x55);
+        mbc.write_ram(0xA010, 0xAA);
+        assert_eq!(latched(&mbc), (0x81D0 + 0x70, 0x81D0 - 0x38));
+        // The latch holds until it is reset.
+        mbc.set_tilt(0.0, 0.0);
+        mbc.write_ram(0xA010, 0xAA);
+        assert_eq!(latched(&mbc), (0x81D0 + 0x70, 0x81D0 - 0x38));
+        mbc.write_ram(0xA000, 0x55);
+        assert_eq!(latched(&mbc), (0x8000, 0x8000));
+
+        // Nothing answers until both enables are set.
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xA020), 0xFF);
+    }
+
+    #[test]
+    fn states_with_too_many_bits_shifted_are_rejected() {
+        let mut w = StateWriter::new();
+        mbc7().save_state(&mut w);
+        let mut state = w.into_inner();
+        // The EEPROM state follows the bank, three flags, the latches and
+        // the words: a kind, two 16-bit fields and the count.
+        let kind = 1 + 3 + 4 + 2 * EEPROM_WORDS;
+        for (kind_value, count) in [(1, 10), (2, 16), (3, 16)] {
+            state[kind] = kind_value;
+            state[kind + 5] = count;
+            let mut mbc = mbc7();
+            assert_eq!(
+                mbc.load_state(&mut StateReader::new(&state)),
+                Err(StateError::Invalid("EEPROM bit count"))
+            );
+            state[kind + 5] = count - 1;
+            assert_eq!(mbc.load_state(&mut StateReader::new(&state)), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Everything that lives on the cartridge.
+//!
+//! A [`Cartridge`] pairs the parsed header with the memory bank controller
+//! that decodes 0x0000-0x7FFF and 0xA000-0xBFFF. Each controller implements
+//! [`Mbc`] in its own module.
+
+mod camera;
+pub mod header;
+mod huc1;
+mod huc3;
+pub mod image;
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod mbc6;
+mod mbc7;
+mod rom_only;
+pub mod rtc;
+
+use std::fmt;
+
+pub use self::camera::PocketCamera;
+pub use self::header::{CartridgeType, Header, HeaderError, Mapper};
+pub use self::huc1::HuC1;
+pub use self::huc3::HuC3;
+pub use self::image::{ImageError, SensorImage};
+pub use self::mbc1::Mbc1;
+pub use self::mbc2::Mbc2;
+pub use self::mbc3::Mbc3;
+pub use self::mbc5::Mbc5;
+pub use self::mbc6::Mbc6;
+pub use self::mbc7::Mbc7;
+pub use self::rom_only::RomOnly;
+
+use self::rtc::{Clock, SystemClock};
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+/// Called with the new motor state whenever a rumble cartridge turns its
+/// motor on or off.
+pub type RumbleCallback = Box<dyn FnMut(bool)>;
+
+/// A memory bank controller and the memory behind it.
+pub trait Mbc {
+    /// Reads from 0x0000-0x7FFF.
+    fn read_rom(&self, addr: u16) -> u8;
+
+    /// Writes to 0x0000-0x7FFF, which land in the controller's registers.
+    fn write_rom(&mut self, addr: u16, value: u8);
+
+    /// Reads from 0xA000-0xBFFF.
+    fn read_ram(&self, addr: u16) -> u8;
+
+    /// Writes to 0xA000-0xBFFF.
+    fn write_ram(&mut self, addr: u16, value: u8);
+
+    /// What a battery would keep alive, laid out the way `.sav` files store
+    /// it: RAM first, then any controller-specific trailer.
+    fn save_data(&self) -> Vec<u8>;
+
+    /// Restores data produced by [`Mbc::save_data`] or by another emulator.
+    fn load_save_data(&mut self, data: &[u8]);
+
+    /// Writes the controller's registers and memory, but not the ROM, to a
+    /// save state.
+    fn save_state(&self, w: &mut StateWriter);
+
+    /// Restores what [`Mbc::save_state`] wrote.
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+
+    /// Controllers without a motor ignore the callback.
+    fn set_rumble_callback(&mut self, _callback: RumbleCallback) {}
+
+    /// Feeds the accelerometer, in g along each axis. Controllers without
+    /// one ignore it.
+    fn set_tilt(&mut self, _x: f32, _y: f32) {}
+
+    /// Replaces what the image sensor sees. Controllers without one ignore
+    /// it.
+    fn set_camera_image(&mut self, _image: SensorImage) {}
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Header(HeaderError),
+    /// The header is fine but rustboy has no controller for it.
+    UnsupportedMapper(CartridgeType),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Header(err) => err.fmt(f),
+            CartridgeError::UnsupportedMapper(cartridge_type) => {
+                write!(f, "unsupported cartridge type {}", cartridge_type)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<HeaderError> for CartridgeError {
+    fn from(err: HeaderError) -> CartridgeError {
+        CartridgeError::Header(err)
+    }
+}
+
+pub struct Cartridge {
+    header: Header,
+    mbc: Box<dyn Mbc>,
+    /// Bumped by everything that may change the battery data; see
+    /// [`Cartridge::save_generation`].
+    save_generation: u64,
+}
+
+impl Cartridge {
+    /// Parses the header of `rom` and builds the controller it asks for.
+    pub fn new(rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        Cartridge::with_clock(rom, Box::new(SystemClock))
+    }
+
+    /// Like [`Cartridge::new`], with `clock` driving the real-time clock of
+    /// cartridges that have one.
+    pub fn with_clock(rom: Vec<u8>, clock: Box<dyn Clock>) -> Result<Cartridge, CartridgeError> {
+        let header = Header::parse(&rom)?;
+        let cartridge_type = header.cartridge_type;
+        let ram_size = header.ram_size();
+
+        let mbc: Box<dyn Mbc> = match cartridge_type.mapper {
+            Mapper::RomOnly => Box::new(RomOnly::new(rom, ram_size)),
+            Mapper::Mbc1 => {
+                if Mbc1::is_multicart(&rom) {
+                    Box::new(Mbc1::new_multicart(rom, ram_size))
+                } else {
+                    Box::new(Mbc1::new(rom, ram_size))
+                }
+            }
+            Mapper::Mbc2 => Box::new(Mbc2::new(rom)),
+            Mapper::Mbc3 => {
+                let clock = if cartridge_type.timer {
+                    Some(clock)
+                } else {
+                    None
+                };
+                Box::new(Mbc3::new(rom, ram_size, clock))
+            }
+            Mapper::Mbc5 => Box::new(Mbc5::new(rom, ram_size, cartridge_type.rumble)),
+            Mapper::Mbc6 => Box::new(Mbc6::new(rom)),
+            Mapper::Mbc7 => Box::new(Mbc7::new(rom)),
+            Mapper::HuC1 => Box::new(HuC1::new(rom, ram_size)),
+            Mapper::HuC3 => Box::new(HuC3::new(rom, ram_size, clock)),
+            Mapper::PocketCamera => Box::new(PocketCamera::new(rom)),
+            _ => return Err(CartridgeError::UnsupportedMapper(cartridge_type)),
+        };
+
+        Ok(Cartridge {
+            header,
+            mbc,
+            save_generation: 0,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => self.mbc.read_rom(addr),
+            _ => self.mbc.read_ram(addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x7FFF => self.mbc.write_rom(addr, value),
+            _ => {
+                self.mbc.write_ram(addr, value);
+                self.save_generation += 1;
+            }
+        }
+    }
+
+    /// Battery-backed contents, or `None` if the cartridge has no battery.
+    pub fn save_data(&self) -> Option<Vec<u8>> {
+        if self.header.cartridge_type.battery {
+            Some(self.mbc.save_data())
+        } else {
+            None
+        }
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.mbc.load_save_data(data);
+        self.save_generation += 1;
+    }
+
+    /// Changes whenever the battery data may have changed: on writes to
+    /// 0xA000-0xBFFF, which reach RAM and clock registers alike, and on
+    /// loads. Time passing on a clock does not count, as the saved trailer
+    /// lets the clock catch up when it is loaded.
+    pub fn save_generation(&self) -> u64 {
+        self.save_generation
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.mbc.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.save_generation += 1;
+        self.mbc.load_state(r)
+    }
+
+    /// Installs `callback` to observe the rumble motor. Only called on
+    /// cartridges that have one, and only when its state changes.
+    pub fn on_rumble<F: FnMut(bool) + 'static>(&mut self, callback: F) {
+        self.mbc.set_rumble_callback(Box::new(callback));
+    }
+
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.mbc.set_tilt(x, y);
+    }
+
+    /// Points the Pocket Camera at `image` for every capture from now on.
+    pub fn set_camera_image(&mut self, image: SensorImage) {
+        self.mbc.set_camera_image(image);
+    }
+}
+
+/// Number of `bank_size` banks decoded for a memory of `len` bytes. Bank
+/// numbers wrap around at this count, like unconnected address lines do.
+fn bank_count(len: usize, bank_size: usize) -> usize {
+    (len.max(1).next_power_of_two() / bank_size).max(1)
+}
+
+/// Reads from `rom` at `bank` and the offset within the bank given by
+/// `addr`. Reads past the end of the image return 0xFF.
+fn read_rom_bank(rom: &[u8], bank: usize, addr: u16) -> u8 {
+    let bank = bank % bank_count(rom.len(), ROM_BANK_SIZE);
+    let offset = bank * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+    rom.get(offset).copied().unwrap_or(0xFF)
+}
+
+/// Offset into `ram` for `bank` and `addr`, wrapping around RAM smaller than
+/// the bank size. `None` when there is no RAM at all.
+fn ram_offset(ram: &[u8], bank: usize, addr: u16) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+    let offset = bank * RAM_BANK_SIZE + (addr as usize & (RAM_BANK_SIZE - 1));
+    Some(offset % ram.len())
+}
+
+/// Splits battery data into RAM and whatever trailer follows it. RAM always
+/// comes in multiples of 2 KiB, so anything past the last whole multiple is
+/// the trailer.
+fn split_trailer(data: &[u8]) -> (&[u8], &[u8]) {
+    data.split_at(data.len() - data.len() % 0x800)
+}
@@ -0,0 +1,55 @@
+use super::{ram_offset, read_rom_bank, Mbc};
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// 32 KiB of ROM with no controller, optionally with up to 8 KiB of RAM.
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> RomOnly {
+        RomOnly {
+            rom,
+            ram: vec![0; ram_size.min(0x2000)],
+        }
+    }
+}
+
+impl Mbc for RomOnly {
+    fn read_rom(&self, addr: u16) -> u8 {
+        read_rom_bank(&self.rom, (addr >> 14) as usize, addr)
+    }
+
+    fn write_rom(&mut self, _addr: u16, _value: u8) {}
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        match ram_offset(&self.ram, 0, addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if let Some(offset) = ram_offset(&self.ram, 0, addr) {
+            self.ram[offset] = value;
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = self.ram.len().min(data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.block(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.block(&mut self.ram)
+    }
+}
@@ -0,0 +1,394 @@
+//! The MBC3 real-time clock.
+//!
+//! The counters are brought up to date lazily from a [`Clock`], so the time
+//! source can be swapped for a [`ManualClock`] wherever runs have to be
+//! reproducible.
+//!
+//! The state is persisted in the 48-byte trailer most emulators append to
+//! the battery RAM: the five live registers and the five latched registers,
+//! each as a little-endian `u32`, followed by a little-endian `u64` UNIX
+//! timestamp. The older 44-byte variant with a 32-bit timestamp is accepted
+//! on load.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const TRAILER_SIZE: usize = 48;
+pub const SHORT_TRAILER_SIZE: usize = 44;
+
+/// A source of wall-clock time, in whole seconds.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// Seconds since the UNIX epoch.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so one
+/// can be handed to a cartridge and the other kept to drive it.
+#[derive(Clone, Default)]
+pub struct ManualClock(Arc<AtomicU64>);
+
+impl ManualClock {
+    pub fn new(now: u64) -> ManualClock {
+        ManualClock(Arc::new(AtomicU64::new(now)))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.0.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+const DH_DAY_HIGH: u8 = 0x01;
+const DH_HALT: u8 = 0x40;
+const DH_CARRY: u8 = 0x80;
+
+/// The live counters.
+#[derive(Clone, Copy, Default)]
+struct Counters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    /// Nine bits.
+    days: u16,
+    halted: bool,
+    /// Set when the day counter overflows; stays set until written.
+    carry: bool,
+}
+
+impl Counters {
+    fn advance(&mut self, mut seconds: u64) {
+        if self.halted {
+            return;
+        }
+
+        // Out-of-range values count up to the width of their register and
+        // wrap to zero without carrying, so step through those one by one.
+        while seconds > 0 && !self.in_range() {
+            self.tick();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+
+        let total = seconds
+            + u64::from(self.seconds)
+            + 60 * u64::from(self.minutes)
+            + 3600 * u64::from(self.hours)
+            + 86400 * u64::from(self.days);
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = total / 86400;
+        if days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (days & 0x1FF) as u16;
+    }
+
+    fn in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.carry = true;
+        }
+    }
+
+    /// As S, M, H, DL, DH.
+    fn registers(&self) -> [u8; 5] {
+        let mut dh = (self.days >> 8) as u8 & DH_DAY_HIGH;
+        if self.halted {
+            dh |= DH_HALT;
+        }
+        if self.carry {
+            dh |= DH_CARRY;
+        }
+        [self.seconds, self.minutes, self.hours, self.days as u8, dh]
+    }
+
+    fn set_registers(&mut self, registers: [u8; 5]) {
+        let [seconds, minutes, hours, dl, dh] = registers;
+        self.seconds = seconds & 0x3F;
+        self.minutes = minutes & 0x3F;
+        self.hours = hours & 0x1F;
+        self.days = u16::from(dl) | (u16::from(dh & DH_DAY_HIGH) << 8);
+        self.halted = dh & DH_HALT != 0;
+        self.carry = dh & DH_CARRY != 0;
+    }
+}
+
+pub struct Rtc {
+    clock: Box<dyn Clock>,
+    live: Counters,
+    /// S, M, H, DL, DH as of the last latch.
+    latched: [u8; 5],
+    /// Clock time the live counters were last brought up to date.
+    updated_at: u64,
+    /// A 0x00 was written to the latch register; a 0x01 now latches.
+    latch_armed: bool,
+}
+
+impl Rtc {
+    pub fn new(clock: Box<dyn Clock>) -> Rtc {
+        let updated_at = clock.now();
+        Rtc {
+            clock,
+            live: Counters::default(),
+            latched: [0; 5],
+            updated_at,
+            latch_armed: false,
+        }
+    }
+
+    /// Reads a latched register, selected by 0x08-0x0C.
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched[(register - 0x08) as usize]
+    }
+
+    /// Writes a live register, selected by 0x08-0x0C.
+    pub fn write(&mut self, register: u8, value: u8) {
+        self.update();
+        let mut registers = self.live.registers();
+        registers[(register - 0x08) as usize] = value;
+        self.live.set_registers(registers);
+    }
+
+    /// Handles a write to 0x6000-0x7FFF: writing 0x00 and then 0x01 copies
+    /// the live registers into the latched ones.
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.update();
+            self.latched = self.live.registers();
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    /// Brings the live counters up to the clock's current time.
+    fn update(&mut self) {
+        let now = self.clock.now();
+        self.live.advance(now.saturating_sub(self.updated_at));
+        self.updated_at = now;
+    }
+
+    /// The 48-byte save trailer.
+    pub fn save(&self) -> Vec<u8> {
+        let now = self.clock.now();
+        let mut live = self.live;
+        live.advance(now.saturating_sub(self.updated_at));
+
+        let mut data = Vec::with_capacity(TRAILER_SIZE);
+        for &value in live.registers().iter().chain(self.latched.iter()) {
+            data.extend_from_slice(&u32::from(value).to_le_bytes());
+        }
+        data.extend_from_slice(&now.to_le_bytes());
+        data
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.live.registers());
+        w.bytes(&self.latched);
+        w.u64(self.updated_at);
+        w.bool(self.latch_armed);
+    }
+
+    /// Restores what [`Rtc::save_state`] wrote. Time that has passed on the
+    /// clock since then is caught up on the next access.
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut registers = [0; 5];
+        r.bytes(&mut registers)?;
+        self.live.set_registers(registers);
+        r.bytes(&mut self.latched)?;
+        self.updated_at = r.u64()?;
+        self.latch_armed = r.bool()?;
+        Ok(())
+    }
+
+    /// Restores a trailer written by [`Rtc::save`] or by another emulator,
+    /// and catches up on the time that has passed since it was written.
+    /// Returns false if `data` is not a trailer.
+    pub fn load(&mut self, data: &[u8]) -> bool {
+        let timestamp = match data.len() {
+            TRAILER_SIZE => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&data[40..48]);
+                u64::from_le_bytes(bytes)
+            }
+            SHORT_TRAILER_SIZE => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&data[40..44]);
+                u64::from(u32::from_le_bytes(bytes))
+            }
+            _ => return false,
+        };
+
+        let field = |i: usize| data[i * 4];
+        self.live
+            .set_registers([field(0), field(1), field(2), field(3), field(4)]);
+        self.latched = [field(5), field(6), field(7), field(8), field(9)];
+        self.updated_at = timestamp;
+        self.update();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtc(now: u64) -> (Rtc, ManualClock) {
+        let clock = ManualClock::new(now);
+        (Rtc::new(Box::new(clock.clone())), clock)
+    }
+
+    /// Latches and returns S, M, H, DL, DH.
+    fn latched(rtc: &mut Rtc) -> [u8; 5] {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+        let mut registers = [0; 5];
+        for (register, value) in (0x08..).zip(registers.iter_mut()) {
+            *value = rtc.read(register);
+        }
+        registers
+    }
+
+    /// Sets S, M, H, DL, DH.
+    fn set(rtc: &mut Rtc, registers: [u8; 5]) {
+        for (register, &value) in (0x08..).zip(registers.iter()) {
+            rtc.write(register, value);
+        }
+    }
+
+    #[test]
+    fn reads_see_the_last_latch() {
+        let (mut rtc, clock) = rtc(0);
+        clock.advance(86400 + 3600 + 60 + 1);
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(latched(&mut rtc), [1, 1, 1, 1, 0]);
+
+        clock.advance(1);
+        assert_eq!(rtc.read(0x08), 1);
+        // Only 0x00 followed by 0x01 latches.
+        rtc.write_latch(0x01);
+        rtc.write_latch(0x02);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08), 1);
+        assert_eq!(latched(&mut rtc)[0], 2);
+    }
+
+    #[test]
+    fn halt_stops_counting() {
+        let (mut rtc, clock) = rtc(0);
+        clock.advance(5);
+        rtc.write(0x0C, DH_HALT);
+        clock.advance(100);
+        assert_eq!(latched(&mut rtc), [5, 0, 0, 0, DH_HALT]);
+
+        rtc.write(0x0C, 0x00);
+        clock.advance(7);
+        assert_eq!(latched(&mut rtc), [12, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn day_counter_overflow_sets_the_carry() {
+        let (mut rtc, clock) = rtc(0);
+        set(&mut rtc, [59, 59, 23, 0xFE, DH_DAY_HIGH]);
+        clock.advance(1);
+        assert_eq!(latched(&mut rtc), [0, 0, 0, 0xFF, DH_DAY_HIGH]);
+        clock.advance(86400);
+        assert_eq!(latched(&mut rtc), [0, 0, 0, 0x00, DH_CARRY]);
+
+        // The carry stays until written, however long the clock runs.
+        clock.advance(3 * 512 * 86400 + 2 * 86400);
+        assert_eq!(latched(&mut rtc), [0, 0, 0, 0x02, DH_CARRY]);
+        rtc.write(0x0C, 0x00);
+        assert_eq!(latched(&mut rtc)[4], 0x00);
+    }
+
+    #[test]
+    fn out_of_range_registers_wrap_without_carrying() {
+        let (mut rtc, clock) = rtc(0);
+        set(&mut rtc, [0xFF, 0xFF, 0xFF, 0x00, 0xFE]);
+        // Only the bits each register has are kept.
+        assert_eq!(latched(&mut rtc), [0x3F, 0x3F, 0x1F, 0x00, 0xC0]);
+
+        // Seconds wrap from 63 to 0 without touching the minutes.
+        rtc.write(0x0C, 0x00);
+        clock.advance(1);
+        assert_eq!(latched(&mut rtc), [0x00, 0x3F, 0x1F, 0x00, 0x00]);
+
+        set(&mut rtc, [0, 61, 0, 0, 0]);
+        clock.advance(3 * 60);
+        assert_eq!(latched(&mut rtc), [0, 0, 0, 0, 0]);
+        // Back in range, counting carries again.
+        clock.advance(3600 + 61);
+        assert_eq!(latched(&mut rtc), [1, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn trailers_round_trip_and_catch_up() {
+        let (mut rtc, clock) = rtc(1_000_000);
+        set(&mut rtc, [10, 20, 3, 4, 0]);
+        latched(&mut rtc);
+        clock.advance(30);
+        let trailer = rtc.save();
+        assert_eq!(trailer.len(), TRAILER_SIZE);
+        assert_eq!(trailer[..8], [40, 0, 0, 0, 20, 0, 0, 0]);
+        assert_eq!(trailer[20..24], [10, 0, 0, 0]);
+        assert_eq!(trailer[40..], 1_000_030u64.to_le_bytes());
+
+        let (mut loaded, later) = self::rtc(1_000_030 + 3600);
+        assert!(loaded.load(&trailer));
+        // The latched registers come back as saved.
+        assert_eq!(loaded.read(0x08), 10);
+        assert_eq!(latched(&mut loaded), [40, 20, 4, 4, 0]);
+
+        let mut short = trailer[..40].to_vec();
+        short.extend_from_slice(&1_000_030u32.to_le_bytes());
+        later.advance(60);
+        assert!(loaded.load(&short));
+        assert_eq!(latched(&mut loaded), [40, 21, 4, 4, 0]);
+        assert!(!loaded.load(&trailer[..47]));
+    }
+}
@@ -0,0 +1,121 @@
+//! The `0xCB`-prefixed opcode table.
+//!
+//! The low three bits select the operand (B, C, D, E, H, L, (HL), A) and the
+//! upper five the operation. `(HL)` operands cost an extra read, and an extra
+//! write for everything but `BIT`.
+
+use super::{Cpu, Memory};
+use crate::registers::Flags;
+
+impl Cpu {
+    pub(super) fn execute_cb<M: Memory>(&mut self, mem: &mut M, opcode: u8) {
+        let r = opcode & 7;
+        let bit = (opcode >> 3) & 7;
+        let value = self.read_r8(mem, r);
+
+        let result = match opcode >> 6 {
+            0 => self.shift(bit, value),
+            1 => {
+                let c = self.regs.f.c();
+                self.regs.f = Flags::new(value & (1 << bit) == 0, false, true, c);
+                return;
+            }
+            2 => value & !(1 << bit),
+            _ => value | (1 << bit),
+        };
+
+        self.write_r8(mem, r, result);
+    }
+
+    /// Rotate/shift by its opcode encoding: RLC, RRC, RL, RR, SLA, SRA, SWAP,
+    /// SRL.
+    fn shift(&mut self, op: u8, value: u8) -> u8 {
+        let carry_in = self.regs.f.c() as u8;
+        let (result, carry) = match op {
+            0 => (value.rotate_left(1), value & 0x80 != 0),
+            1 => (value.rotate_right(1), value & 0x01 != 0),
+            2 => ((value << 1) | carry_in, value & 0x80 != 0),
+            3 => ((value >> 1) | (carry_in << 7), value & 0x01 != 0),
+            4 => (value << 1, value & 0x80 != 0),
+            5 => ((value >> 1) | (value & 0x80), value & 0x01 != 0),
+            6 => (value.rotate_left(4), false),
+            _ => (value >> 1, value & 0x01 != 0),
+        };
+        self.regs.f = Flags::new(result == 0, false, false, carry);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::tests::load;
+    use crate::registers::Flags;
+
+    #[test]
+    fn shifts_and_rotates() {
+        // (operation, B, carry in, B after, Z, carry out)
+        let cases = [
+            (0, 0x81, false, 0x03, false, true), // RLC
+            (1, 0x81, false, 0xC0, false, true), // RRC
+            (2, 0x81, false, 0x02, false, true), // RL
+            (2, 0x80, false, 0x00, true, true),  // RL
+            (3, 0x81, true, 0xC0, false, true),  // RR
+            (4, 0x81, true, 0x02, false, true),  // SLA
+            (5, 0x81, false, 0xC0, false, true), // SRA
+            (5, 0x01, false, 0x00, true, true),  // SRA
+            (6, 0xF1, true, 0x1F, false, false), // SWAP
+            (6, 0x00, true, 0x00, true, false),  // SWAP
+            (7, 0x81, false, 0x40, false, true), // SRL
+        ];
+        for &(op, b, carry, result, z, carry_out) in &cases {
+            let (mut cpu, mut ram) = load(&[0xCB, op << 3]);
+            cpu.regs.b = b;
+            cpu.regs.f = Flags::new(!z, true, true, carry);
+            assert_eq!(cpu.step(&mut ram), 8);
+            assert_eq!(cpu.regs.b, result, "operation {} on {:#04x}", op, b);
+            assert_eq!(cpu.regs.f, Flags::new(z, false, false, carry_out));
+        }
+    }
+
+    #[test]
+    fn bit_tests_leave_the_carry() {
+        // BIT 7,H; BIT 0,H
+        let (mut cpu, mut ram) = load(&[0xCB, 0x7C, 0xCB, 0x44]);
+        cpu.regs.h = 0x7F;
+        cpu.regs.f = Flags::new(false, true, false, true);
+        cpu.step(&mut ram);
+        assert_eq!(cpu.regs.f, Flags::new(true, false, true, true));
+        cpu.step(&mut ram);
+        assert_eq!(cpu.regs.f, Flags::new(false, false, true, true));
+        assert_eq!(cpu.regs.h, 0x7F);
+    }
+
+    #[test]
+    fn res_and_set_change_one_bit() {
+        // RES 0,A; SET 7,A
+        let (mut cpu, mut ram) = load(&[0xCB, 0x87, 0xCB, 0xFF]);
+        cpu.regs.a = 0x0F;
+        cpu.regs.f = Flags::from_bits(0xF0);
+        cpu.step(&mut ram);
+        assert_eq!(cpu.regs.a, 0x0E);
+        cpu.step(&mut ram);
+        assert_eq!(cpu.regs.a, 0x8E);
+        assert_eq!(cpu.regs.f.bits(), 0xF0);
+    }
+
+    #[test]
+    fn hl_operands_cost_extra_cycles() {
+        // BIT 3,(HL); SET 3,(HL); RES 3,(HL); SWAP (HL)
+        let (mut cpu, mut ram) = load(&[0xCB, 0x5E, 0xCB, 0xDE, 0xCB, 0x9E, 0xCB, 0x36]);
+        cpu.regs.set_hl(0xC000);
+        ram.0[0xC000] = 0x21;
+        assert_eq!(cpu.step(&mut ram), 12);
+        assert!(cpu.regs.f.z());
+        assert_eq!(cpu.step(&mut ram), 16);
+        assert_eq!(ram.0[0xC000], 0x29);
+        assert_eq!(cpu.step(&mut ram), 16);
+        assert_eq!(ram.0[0xC000], 0x21);
+        assert_eq!(cpu.step(&mut ram), 16);
+        assert_eq!(ram.0[0xC000], 0x12);
+    }
+}
@@ -0,0 +1,589 @@
+//! The base (unprefixed) opcode table.
+
+use super::{Cpu, Memory, SPEED_SWITCH_CYCLES};
+use crate::registers::{Flags, Pair};
+
+impl Cpu {
+    pub(super) fn execute<M: Memory>(&mut self, mem: &mut M, opcode: u8) {
+        match opcode {
+            0x00 => {}
+
+            // LD rr,nn
+            0x01 | 0x11 | 0x21 | 0x31 => {
+                let value = self.fetch16(mem);
+                self.set_rp(opcode >> 4, value);
+            }
+
+            // LD (rr),A
+            0x02 | 0x12 => {
+                let addr = self.rp(opcode >> 4);
+                self.write(mem, addr, self.regs.a);
+            }
+            0x22 => {
+                let hl = self.regs.hl_inc();
+                self.write(mem, hl, self.regs.a);
+            }
+            0x32 => {
+                let hl = self.regs.hl_dec();
+                self.write(mem, hl, self.regs.a);
+            }
+
+            // LD A,(rr)
+            0x0A | 0x1A => {
+                let addr = self.rp(opcode >> 4);
+                self.regs.a = self.read(mem, addr);
+            }
+            0x2A => {
+                let hl = self.regs.hl_inc();
+                self.regs.a = self.read(mem, hl);
+            }
+            0x3A => {
+                let hl = self.regs.hl_dec();
+                self.regs.a = self.read(mem, hl);
+            }
+
+            // INC rr / DEC rr
+            0x03 | 0x13 | 0x23 => {
+                self.regs.inc_pair(pair(opcode >> 4));
+                self.idle(mem);
+            }
+            0x33 => {
+                self.sp = self.sp.wrapping_add(1);
+                self.idle(mem);
+            }
+            0x0B | 0x1B | 0x2B => {
+                self.regs.dec_pair(pair(opcode >> 4));
+                self.idle(mem);
+            }
+            0x3B => {
+                self.sp = self.sp.wrapping_sub(1);
+                self.idle(mem);
+            }
+
+            // INC r / DEC r
+            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => {
+                let r = opcode >> 3;
+                let value = self.read_r8(mem, r);
+                let result = value.wrapping_add(1);
+                let c = self.regs.f.c();
+                self.regs.f = Flags::new(result == 0, false, value & 0x0F == 0x0F, c);
+                self.write_r8(mem, r, result);
+            }
+            0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => {
+                let r = opcode >> 3;
+                let value = self.read_r8(mem, r);
+                let result = value.wrapping_sub(1);
+                let c = self.regs.f.c();
+                self.regs.f = Flags::new(result == 0, true, value & 0x0F == 0, c);
+                self.write_r8(mem, r, result);
+            }
+
+            // LD r,n
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
+                let value = self.fetch(mem);
+                self.write_r8(mem, opcode >> 3, value);
+            }
+
+            // Accumulator rotates always clear Z.
+            0x07 => {
+                let a = self.regs.a;
+                self.regs.a = a.rotate_left(1);
+                self.regs.f = Flags::new(false, false, false, a & 0x80 != 0);
+            }
+            0x0F => {
+                let a = self.regs.a;
+                self.regs.a = a.rotate_right(1);
+                self.regs.f = Flags::new(false, false, false, a & 0x01 != 0);
+            }
+            0x17 => {
+                let a = self.regs.a;
+                self.regs.a = (a << 1) | self.regs.f.c() as u8;
+                self.regs.f = Flags::new(false, false, false, a & 0x80 != 0);
+            }
+            0x1F => {
+                let a = self.regs.a;
+                self.regs.a = (a >> 1) | ((self.regs.f.c() as u8) << 7);
+                self.regs.f = Flags::new(false, false, false, a & 0x01 != 0);
+            }
+
+            // LD (nn),SP
+            0x08 => {
+                let addr = self.fetch16(mem);
+                let [lo, hi] = self.sp.to_le_bytes();
+                self.write(mem, addr, lo);
+                self.write(mem, addr.wrapping_add(1), hi);
+            }
+
+            // ADD HL,rr
+            0x09 | 0x19 | 0x29 | 0x39 => {
+                let hl = self.regs.hl();
+                let value = self.rp(opcode >> 4);
+                let (result, carry) = hl.overflowing_add(value);
+                let half = (hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF;
+                let z = self.regs.f.z();
+                self.regs.f = Flags::new(z, false, half, carry);
+                self.regs.set_hl(result);
+                self.idle(mem);
+            }
+
+            // STOP is encoded as two bytes; the second one is ignored.
+            0x10 => {
+                self.fetch(mem);
+                if mem.stop() {
+                    for _ in 0..SPEED_SWITCH_CYCLES {
+                        self.idle(mem);
+                    }
+                } else {
+                    self.stopped = true;
+                }
+            }
+
+            // JR e / JR cc,e
+            0x18 => self.jump_relative(mem, true),
+            0x20 | 0x28 | 0x30 | 0x38 => {
+                let taken = self.condition(opcode >> 3);
+                self.jump_relative(mem, taken);
+            }
+
+            0x27 => self.daa(),
+            0x2F => {
+                self.regs.a = !self.regs.a;
+                self.regs.f.set_n(true);
+                self.regs.f.set_h(true);
+            }
+            0x37 => {
+                let z = self.regs.f.z();
+                self.regs.f = Flags::new(z, false, false, true);
+            }
+            0x3F => {
+                let z = self.regs.f.z();
+                let c = self.regs.f.c();
+                self.regs.f = Flags::new(z, false, false, !c);
+            }
+
+            0x76 => {
+                if !self.ime && mem.pending_interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+            }
+
+            // LD r,r'
+            0x40..=0x7F => {
+                let value = self.read_r8(mem, opcode);
+                self.write_r8(mem, opcode >> 3, value);
+            }
+
+            // ALU A,r
+            0x80..=0xBF => {
+                let value = self.read_r8(mem, opcode);
+                self.alu(opcode >> 3, value);
+            }
+
+            // ALU A,n
+            0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => {
+                let value = self.fetch(mem);
+                self.alu(opcode >> 3, value);
+            }
+
+            // RET cc
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => {
+                self.idle(mem);
+                if self.condition(opcode >> 3) {
+                    self.pc = self.pop(mem);
+                    self.idle(mem);
+                }
+            }
+            0xC9 => {
+                self.pc = self.pop(mem);
+                self.idle(mem);
+            }
+            0xD9 => {
+                self.pc = self.pop(mem);
+                self.idle(mem);
+                self.ime = true;
+            }
+
+            // POP rr
+            0xC1 | 0xD1 | 0xE1 => {
+                let value = self.pop(mem);
+                self.set_rp((opcode >> 4) & 3, value);
+            }
+            0xF1 => {
+                let value = self.pop(mem);
+                self.regs.set_af(value);
+            }
+
+            // PUSH rr
+            0xC5 | 0xD5 | 0xE5 => {
+                let value = self.rp((opcode >> 4) & 3);
+                self.idle(mem);
+                self.push(mem, value);
+            }
+            0xF5 => {
+                let value = self.regs.af();
+                self.idle(mem);
+                self.push(mem, value);
+            }
+
+            // JP nn / JP cc,nn
+            0xC3 => {
+                let addr = self.fetch16(mem);
+                self.idle(mem);
+                self.pc = addr;
+            }
+            0xC2 | 0xCA | 0xD2 | 0xDA => {
+                let addr = self.fetch16(mem);
+                if self.condition(opcode >> 3) {
+                    self.idle(mem);
+                    self.pc = addr;
+                }
+            }
+            0xE9 => self.pc = self.regs.hl(),
+
+            // CALL nn / CALL cc,nn
+            0xCD => {
+                let addr = self.fetch16(mem);
+                self.call(mem, addr);
+            }
+            0xC4 | 0xCC | 0xD4 | 0xDC => {
+                let addr = self.fetch16(mem);
+                if self.condition(opcode >> 3) {
+                    self.call(mem, addr);
+                }
+            }
+
+            // RST n
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                self.call(mem, u16::from(opcode & 0x38));
+            }
+
+            0xCB => {
+                let opcode = self.fetch(mem);
+                self.execute_cb(mem, opcode);
+            }
+
+            // High page loads
+            0xE0 => {
+                let addr = 0xFF00 | u16::from(self.fetch(mem));
+                self.write(mem, addr, self.regs.a);
+            }
+            0xF0 => {
+                let addr = 0xFF00 | u16::from(self.fetch(mem));
+                self.regs.a = self.read(mem, addr);
+            }
+            0xE2 => {
+                let addr = 0xFF00 | u16::from(self.regs.c);
+                self.write(mem, addr, self.regs.a);
+            }
+            0xF2 => {
+                let addr = 0xFF00 | u16::from(self.regs.c);
+                self.regs.a = self.read(mem, addr);
+            }
+
+            // LD (nn),A / LD A,(nn)
+            0xEA => {
+                let addr = self.fetch16(mem);
+                self.write(mem, addr, self.regs.a);
+            }
+            0xFA => {
+                let addr = self.fetch16(mem);
+                self.regs.a = self.read(mem, addr);
+            }
+
+            // ADD SP,e / LD HL,SP+e
+            0xE8 => {
+                let result = self.sp_plus_offset(mem);
+                self.idle(mem);
+                self.idle(mem);
+                self.sp = result;
+            }
+            0xF8 => {
+                let result = self.sp_plus_offset(mem);
+                self.idle(mem);
+                self.regs.set_hl(result);
+            }
+            0xF9 => {
+                self.sp = self.regs.hl();
+                self.idle(mem);
+            }
+
+            0xF3 => {
+                self.ime = false;
+                self.ime_pending = false;
+            }
+            0xFB => self.ime_pending = true,
+
+            // D3, DB, DD, E3, E4, EB, EC, ED, F4, FC and FD
+            _ => self.locked = true,
+        }
+    }
+
+    /// Branch condition by its opcode encoding: NZ, Z, NC, C.
+    fn condition(&self, index: u8) -> bool {
+        match index & 3 {
+            0 => !self.regs.f.z(),
+            1 => self.regs.f.z(),
+            2 => !self.regs.f.c(),
+            _ => self.regs.f.c(),
+        }
+    }
+
+    fn jump_relative<M: Memory>(&mut self, mem: &mut M, taken: bool) {
+        let offset = self.fetch(mem) as i8;
+        if taken {
+            self.idle(mem);
+            self.pc = self.pc.wrapping_add(offset as u16);
+        }
+    }
+
+    fn call<M: Memory>(&mut self, mem: &mut M, addr: u16) {
+        self.idle(mem);
+        self.push(mem, self.pc);
+        self.pc = addr;
+    }
+
+    /// Shared by `ADD SP,e` and `LD HL,SP+e`: flags come from the unsigned
+    /// addition of the low byte.
+    fn sp_plus_offset<M: Memory>(&mut self, mem: &mut M) -> u16 {
+        let offset = self.fetch(mem);
+        let sp = self.sp;
+        let half = (sp & 0x0F) + u16::from(offset & 0x0F) > 0x0F;
+        let carry = (sp & 0xFF) + u16::from(offset) > 0xFF;
+        self.regs.f = Flags::new(false, false, half, carry);
+        sp.wrapping_add(offset as i8 as u16)
+    }
+
+    /// ALU operation by its opcode encoding: ADD, ADC, SUB, SBC, AND, XOR,
+    /// OR, CP.
+    fn alu(&mut self, op: u8, value: u8) {
+        let a = self.regs.a;
+        match op & 7 {
+            0 | 1 => {
+                let carry = (op == 1 && self.regs.f.c()) as u8;
+                let result = a.wrapping_add(value).wrapping_add(carry);
+                let half = (a & 0x0F) + (value & 0x0F) + carry > 0x0F;
+                let full = u16::from(a) + u16::from(value) + u16::from(carry) > 0xFF;
+                self.regs.f = Flags::new(result == 0, false, half, full);
+                self.regs.a = result;
+            }
+            2 | 3 | 7 => {
+                let carry = (op == 3 && self.regs.f.c()) as u8;
+                let result = a.wrapping_sub(value).wrapping_sub(carry);
+                let half = (a & 0x0F) < (value & 0x0F) + carry;
+                let full = u16::from(a) < u16::from(value) + u16::from(carry);
+                self.regs.f = Flags::new(result == 0, true, half, full);
+                if op != 7 {
+                    self.regs.a = result;
+                }
+            }
+            4 => {
+                self.regs.a = a & value;
+                self.regs.f = Flags::new(self.regs.a == 0, false, true, false);
+            }
+            5 => {
+                self.regs.a = a ^ value;
+                self.regs.f = Flags::new(self.regs.a == 0, false, false, false);
+            }
+            _ => {
+                self.regs.a = a | value;
+                self.regs.f = Flags::new(self.regs.a == 0, false, false, false);
+            }
+        }
+    }
+
+    fn daa(&mut self) {
+        let mut a = self.regs.a;
+        let n = self.regs.f.n();
+        let mut carry = self.regs.f.c();
+        if n {
+            if carry {
+                a = a.wrapping_sub(0x60);
+            }
+            if self.regs.f.h() {
+                a = a.wrapping_sub(0x06);
+            }
+        } else {
+            if carry || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                carry = true;
+            }
+            if self.regs.f.h() || a & 0x0F > 0x09 {
+                a = a.wrapping_add(0x06);
+            }
+        }
+        self.regs.a = a;
+        self.regs.f = Flags::new(a == 0, n, false, carry);
+    }
+}
+
+/// General-purpose register pair by its opcode encoding: BC, DE, HL.
+fn pair(index: u8) -> Pair {
+    match index & 3 {
+        0 => Pair::Bc,
+        1 => Pair::De,
+        _ => Pair::Hl,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::tests::load;
+    use crate::registers::Flags;
+
+    /// Packs a number below 100 as two BCD digits.
+    fn bcd(value: u8) -> u8 {
+        ((value / 10) << 4) | (value % 10)
+    }
+
+    #[test]
+    fn daa_corrects_bcd_addition_and_subtraction() {
+        for x in 0..100 {
+            for y in 0..100 {
+                // ADD A,B; DAA
+                let (mut cpu, mut ram) = load(&[0x80, 0x27]);
+                cpu.regs.a = bcd(x);
+                cpu.regs.b = bcd(y);
+                cpu.step(&mut ram);
+                assert_eq!(cpu.step(&mut ram), 4);
+                let sum = (x + y) % 100;
+                assert_eq!(cpu.regs.a, bcd(sum), "{} + {}", x, y);
+                assert_eq!(cpu.regs.f, Flags::new(sum == 0, false, false, x + y >= 100));
+
+                // SUB B; DAA
+                let (mut cpu, mut ram) = load(&[0x90, 0x27]);
+                cpu.regs.a = bcd(x);
+                cpu.regs.b = bcd(y);
+                cpu.step(&mut ram);
+                cpu.step(&mut ram);
+                let difference = (100 + x - y) % 100;
+                assert_eq!(cpu.regs.a, bcd(difference), "{} - {}", x, y);
+                assert_eq!(cpu.regs.f, Flags::new(difference == 0, true, false, x < y));
+            }
+        }
+    }
+
+    #[test]
+    fn sp_plus_offset_flags_come_from_the_low_byte() {
+        // ADD SP,8
+        let (mut cpu, mut ram) = load(&[0xE8, 0x08]);
+        cpu.sp = 0xFFF8;
+        cpu.regs.f = Flags::new(true, true, false, false);
+        assert_eq!(cpu.step(&mut ram), 16);
+        assert_eq!(cpu.sp, 0x0000);
+        assert_eq!(cpu.regs.f, Flags::new(false, false, true, true));
+
+        // ADD SP,-1 carries out of both nibble and byte.
+        let (mut cpu, mut ram) = load(&[0xE8, 0xFF]);
+        cpu.sp = 0x0005;
+        cpu.step(&mut ram);
+        assert_eq!(cpu.sp, 0x0004);
+        assert_eq!(cpu.regs.f, Flags::new(false, false, true, true));
+
+        // LD HL,SP-128
+        let (mut cpu, mut ram) = load(&[0xF8, 0x80]);
+        cpu.sp = 0x1000;
+        assert_eq!(cpu.step(&mut ram), 12);
+        assert_eq!((cpu.regs.hl(), cpu.sp), (0x0F80, 0x1000));
+        assert_eq!(cpu.regs.f, Flags::default());
+    }
+
+    #[test]
+    fn accumulator_rotates_clear_z() {
+        // (opcode, A, carry in, A after, carry out)
+        let cases = [
+            (0x07, 0x80, false, 0x01, true), // RLCA
+            (0x07, 0x00, true, 0x00, false), // RLCA
+            (0x17, 0x80, false, 0x00, true), // RLA
+            (0x17, 0x40, true, 0x81, false), // RLA
+            (0x0F, 0x01, false, 0x80, true), // RRCA
+            (0x1F, 0x01, false, 0x00, true), // RRA
+            (0x1F, 0x02, true, 0x81, false), // RRA
+        ];
+        for &(opcode, a, carry, result, carry_out) in &cases {
+            let (mut cpu, mut ram) = load(&[opcode]);
+            cpu.regs.a = a;
+            cpu.regs.f = Flags::new(true, true, true, carry);
+            assert_eq!(cpu.step(&mut ram), 4);
+            assert_eq!(cpu.regs.a, result, "{:#04x} on {:#04x}", opcode, a);
+            assert_eq!(cpu.regs.f, Flags::new(false, false, false, carry_out));
+        }
+    }
+
+    #[test]
+    fn halt_waits_for_an_interrupt() {
+        // HALT; INC A
+        let (mut cpu, mut ram) = load(&[0x76, 0x3C]);
+        ram.0[0xFFFF] = 0x04;
+        cpu.step(&mut ram);
+        assert!(cpu.halted);
+        assert_eq!(cpu.step(&mut ram), 4);
+        assert_eq!(cpu.pc, 0x0101);
+
+        // With IME clear the CPU wakes up and carries on in place.
+        ram.0[0xFF0F] = 0x04;
+        cpu.step(&mut ram);
+        assert!(!cpu.halted);
+        assert_eq!((cpu.pc, cpu.regs.a), (0x0102, 0x01));
+    }
+
+    #[test]
+    fn halt_with_an_interrupt_pending_and_ime_clear_repeats_a_byte() {
+        // HALT; INC A; NOP
+        let (mut cpu, mut ram) = load(&[0x76, 0x3C, 0x00]);
+        ram.0[0xFFFF] = 0x01;
+        ram.0[0xFF0F] = 0x01;
+        cpu.step(&mut ram);
+        assert!(!cpu.halted);
+        cpu.step(&mut ram);
+        assert_eq!((cpu.pc, cpu.regs.a), (0x0101, 0x01));
+        cpu.step(&mut ram);
+        assert_eq!((cpu.pc, cpu.regs.a), (0x0102, 0x02));
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // EI; INC A; INC A
+        let (mut cpu, mut ram) = load(&[0xFB, 0x3C, 0x3C]);
+        ram.0[0xFFFF] = 0x01;
+        ram.0[0xFF0F] = 0x01;
+        cpu.step(&mut ram);
+        assert!(!cpu.ime);
+        cpu.step(&mut ram);
+        assert_eq!((cpu.ime, cpu.regs.a), (true, 0x01));
+        assert_eq!(cpu.step(&mut ram), 20);
+        assert_eq!((cpu.pc, cpu.sp), (0x0040, 0xFFFC));
+        assert_eq!(ram.0[0xFFFC..0xFFFE], [0x02, 0x01]);
+        assert_eq!(ram.0[0xFF0F], 0x00);
+        assert!(!cpu.ime);
+
+        // DI right after EI cancels it.
+        let (mut cpu, mut ram) = load(&[0xFB, 0xF3, 0x3C]);
+        ram.0[0xFFFF] = 0x01;
+        ram.0[0xFF0F] = 0x01;
+        for _ in 0..3 {
+            cpu.step(&mut ram);
+        }
+        assert_eq!((cpu.ime, cpu.pc, cpu.regs.a), (false, 0x0103, 0x01));
+    }
+
+    #[test]
+    fn inc_and_dec_rr_wrap_without_touching_flags() {
+        // INC BC; DEC DE; INC HL; DEC SP
+        let (mut cpu, mut ram) = load(&[0x03, 0x1B, 0x23, 0x3B]);
+        cpu.regs.set_bc(0xFFFF);
+        cpu.regs.set_de(0x0000);
+        cpu.regs.set_hl(0x12FF);
+        cpu.sp = 0x0000;
+        cpu.regs.f = 0xA0.into();
+        for _ in 0..4 {
+            assert_eq!(cpu.step(&mut ram), 8);
+        }
+        assert_eq!(
+            (cpu.regs.bc(), cpu.regs.de(), cpu.regs.hl(), cpu.sp),
+            (0x0000, 0xFFFF, 0x1300, 0xFFFF)
+        );
+        assert_eq!(cpu.regs.f.bits(), 0xA0);
+    }
+}
@@ -0,0 +1,325 @@
+//! The Sharp LR35902 core.
+//!
+//! The CPU is driven one instruction at a time through [`Cpu::step`]. Every
+//! memory access goes through the [`Memory`] trait and costs one machine
+//! cycle (4 clocks), so whatever sits behind the trait can advance the rest
+//! of the hardware in lockstep with the instruction stream.
+
+mod cb;
+mod execute;
+
+use crate::cartridge::Header;
+use crate::model::Model;
+use crate::registers::{Flags, Registers};
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Machine cycles the CPU is stalled for while the clock changes speed.
+pub const SPEED_SWITCH_CYCLES: u32 = 2050;
+
+/// Interrupt vectors, indexed by bit in IE/IF.
+const INTERRUPT_VECTORS: [u16; 5] = [0x0040, 0x0048, 0x0050, 0x0058, 0x0060];
+
+/// The CPU's view of the address space.
+pub trait Memory {
+    /// Reads a byte. Takes one machine cycle.
+    fn read(&mut self, addr: u16) -> u8;
+
+    /// Writes a byte. Takes one machine cycle.
+    fn write(&mut self, addr: u16, value: u8);
+
+    /// A machine cycle in which the CPU does not touch the bus.
+    fn idle(&mut self) {}
+
+    /// Called as `STOP` runs. Returns true if it switched the CPU's speed
+    /// rather than stopping it, in which case the CPU stalls for
+    /// [`SPEED_SWITCH_CYCLES`] and carries on.
+    fn stop(&mut self) -> bool {
+        false
+    }
+
+    /// Interrupts that are both requested (IF) and enabled (IE), in the low
+    /// five bits.
+    fn pending_interrupts(&self) -> u8;
+
+    /// Clears the request bit of the interrupt being serviced.
+    fn acknowledge_interrupt(&mut self, bit: u8);
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Cpu {
+    pub regs: Registers,
+    pub sp: u16,
+    pub pc: u16,
+    /// Interrupt master enable.
+    pub ime: bool,
+    pub halted: bool,
+    pub stopped: bool,
+    /// Set by an illegal opcode. The real CPU hangs until it is reset.
+    pub locked: bool,
+    /// `EI` enables interrupts only after the following instruction.
+    ime_pending: bool,
+    /// `HALT` with IME clear and an interrupt pending fails to increment PC
+    /// on the next opcode fetch.
+    halt_bug: bool,
+    /// Clocks spent in the current step.
+    cycles: u32,
+}
+
+impl Cpu {
+    pub fn new() -> Cpu {
+        Cpu::default()
+    }
+
+    /// The CPU as the boot ROM of `model` leaves it when it jumps to the
+    /// entry point of the cartridge with `header`.
+    pub fn post_boot(model: Model, header: &Header) -> Cpu {
+        Cpu {
+            regs: model.registers(header),
+            sp: 0xFFFE,
+            pc: 0x0100,
+            ..Cpu::default()
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let r = &self.regs;
+        w.bytes(&[r.a, r.f.bits(), r.b, r.c, r.d, r.e, r.h, r.l]);
+        w.u16(self.sp);
+        w.u16(self.pc);
+        w.bool(self.ime);
+        w.bool(self.halted);
+        w.bool(self.stopped);
+        w.bool(self.locked);
+        w.bool(self.ime_pending);
+        w.bool(self.halt_bug);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut regs = [0; 8];
+        r.bytes(&mut regs)?;
+        let [a, f, b, c, d, e, h, l] = regs;
+        self.regs = Registers {
+            a,
+            f: Flags::from_bits(f),
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+        };
+        self.sp = r.u16()?;
+        self.pc = r.u16()?;
+        self.ime = r.bool()?;
+        self.halted = r.bool()?;
+        self.stopped = r.bool()?;
+        self.locked = r.bool()?;
+        self.ime_pending = r.bool()?;
+        self.halt_bug = r.bool()?;
+        Ok(())
+    }
+
+    /// Runs one instruction, services one interrupt or idles for one machine
+    /// cycle while halted. Returns the number of clocks taken.
+    pub fn step<M: Memory>(&mut self, mem: &mut M) -> u32 {
+        self.cycles = 0;
+
+        if self.locked {
+            self.idle(mem);
+            return self.cycles;
+        }
+
+        let pending = mem.pending_interrupts();
+
+        if self.stopped {
+            // Only a joypad press brings the CPU out of STOP.
+            if pending & 0x10 == 0 {
+                self.idle(mem);
+                return self.cycles;
+            }
+            self.stopped = false;
+        }
+
+        if self.halted {
+            if pending == 0 {
+                self.idle(mem);
+                return self.cycles;
+            }
+            self.halted = false;
+            if self.ime {
+                self.idle(mem);
+            }
+        }
+
+        if self.ime && pending != 0 {
+            self.dispatch_interrupt(mem);
+            return self.cycles;
+        }
+
+        if self.ime_pending {
+            self.ime_pending = false;
+            self.ime = true;
+        }
+
+        let opcode = self.fetch(mem);
+        self.execute(mem, opcode);
+        self.cycles
+    }
+
+    fn dispatch_interrupt<M: Memory>(&mut self, mem: &mut M) {
+        self.ime = false;
+        self.idle(mem);
+        self.idle(mem);
+
+        let [lo, hi] = self.pc.to_le_bytes();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(mem, self.sp, hi);
+
+        // Pushing the high byte can overwrite IE, so the interrupt to
+        // service is only decided now. If nothing is left, PC ends up at 0.
+        let pending = mem.pending_interrupts();
+
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(mem, self.sp, lo);
+
+        if pending == 0 {
+            self.pc = 0x0000;
+        } else {
+            let bit = pending.trailing_zeros() as u8;
+            mem.acknowledge_interrupt(bit);
+            self.pc = INTERRUPT_VECTORS[bit as usize];
+        }
+        self.idle(mem);
+    }
+
+    fn read<M: Memory>(&mut self, mem: &mut M, addr: u16) -> u8 {
+        self.cycles += 4;
+        mem.read(addr)
+    }
+
+    fn write<M: Memory>(&mut self, mem: &mut M, addr: u16, value: u8) {
+        self.cycles += 4;
+        mem.write(addr, value);
+    }
+
+    fn idle<M: Memory>(&mut self, mem: &mut M) {
+        self.cycles += 4;
+        mem.idle();
+    }
+
+    fn fetch<M: Memory>(&mut self, mem: &mut M) -> u8 {
+        let value = self.read(mem, self.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
+        value
+    }
+
+    fn fetch16<M: Memory>(&mut self, mem: &mut M) -> u16 {
+        let lo = self.fetch(mem);
+        let hi = self.fetch(mem);
+        u16::from_le_bytes([lo, hi])
+    }
+
+    fn push<M: Memory>(&mut self, mem: &mut M, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(mem, self.sp, hi);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(mem, self.sp, lo);
+    }
+
+    fn pop<M: Memory>(&mut self, mem: &mut M) -> u16 {
+        let lo = self.read(mem, self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        let hi = self.read(mem, self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        u16::from_le_bytes([lo, hi])
+    }
+
+    /// Register pair by its opcode encoding: BC, DE, HL, SP.
+    fn rp(&self, index: u8) -> u16 {
+        match index & 3 {
+            0 => self.regs.bc(),
+            1 => self.regs.de(),
+            2 => self.regs.hl(),
+            _ => self.sp,
+        }
+    }
+
+    fn set_rp(&mut self, index: u8, value: u16) {
+        match index & 3 {
+            0 => self.regs.set_bc(value),
+            1 => self.regs.set_de(value),
+            2 => self.regs.set_hl(value),
+            _ => self.sp = value,
+        }
+    }
+
+    /// 8-bit operand by its opcode encoding: B, C, D, E, H, L, (HL), A.
+    fn read_r8<M: Memory>(&mut self, mem: &mut M, index: u8) -> u8 {
+        match index & 7 {
+            0 => self.regs.b,
+            1 => self.regs.c,
+            2 => self.regs.d,
+            3 => self.regs.e,
+            4 => self.regs.h,
+            5 => self.regs.l,
+            6 => self.read(mem, self.regs.hl()),
+            _ => self.regs.a,
+        }
+    }
+
+    fn write_r8<M: Memory>(&mut self, mem: &mut M, index: u8, value: u8) {
+        match index & 7 {
+            0 => self.regs.b = value,
+            1 => self.regs.c = value,
+            2 => self.regs.d = value,
+            3 => self.regs.e = value,
+            4 => self.regs.h = value,
+            5 => self.regs.l = value,
+            6 => self.write(mem, self.regs.hl(), value),
+            _ => self.regs.a = value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 64 KiB of plain RAM, with IE and IF at their usual addresses.
+    pub(super) struct Ram(pub(super) Vec<u8>);
+
+    impl Memory for Ram {
+        fn read(&mut self, addr: u16) -> u8 {
+            self.0[usize::from(addr)]
+        }
+
+        fn write(&mut self, addr: u16, value: u8) {
+            self.0[usize::from(addr)] = value;
+        }
+
+        fn pending_interrupts(&self) -> u8 {
+            self.0[0xFFFF] & self.0[0xFF0F] & 0x1F
+        }
+
+        fn acknowledge_interrupt(&mut self, bit: u8) {
+            self.0[0xFF0F] &= !(1 << bit);
+        }
+    }
+
+    /// A CPU about to run `code` from 0x0100, with SP at 0xFFFE.
+    pub(super) fn load(code: &[u8]) -> (Cpu, Ram) {
+        let mut ram = vec![0; 0x10000];
+        ram[0x0100..0x0100 + code.len()].copy_from_slice(code);
+        let cpu = Cpu {
+            sp: 0xFFFE,
+            pc: 0x0100,
+            ..Cpu::default()
+        };
+        (cpu, Ram(ram))
+    }
+}
@@ -0,0 +1,596 @@
+use std::io;
+use std::path::PathBuf;
+
+use crate::battery::BatterySave;
+use crate::boot_rom::BootRom;
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::cpu::Cpu;
+use crate::model::Model;
+use crate::state::{self, StateError, StateReader, StateWriter, Tag};
+
+/// Clocks in one frame: 154 lines of 456 clocks.
+pub const CLOCKS_PER_FRAME: u32 = 154 * 456;
+
+/// Clocks between periodic battery flushes, about five seconds.
+const BATTERY_FLUSH_INTERVAL: u32 = 5 * 4_194_304;
+
+/// A complete machine: the CPU and everything on its bus.
+pub struct GameBoy {
+    pub cpu: Cpu,
+    pub bus: Bus,
+    battery: Option<BatterySave>,
+    clocks_since_flush: u32,
+}
+
+impl GameBoy {
+    /// Starts `cartridge` on `model` as if the boot ROM had just finished.
+    pub fn new(model: Model, cartridge: Cartridge) -> GameBoy {
+        let cpu = Cpu::post_boot(model, cartridge.header());
+        let mut bus = Bus::new(model, cartridge);
+        bus.apply_post_boot();
+        GameBoy {
+            cpu,
+            bus,
+            battery: None,
+            clocks_since_flush: 0,
+        }
+    }
+
+    /// Powers `cartridge` on in the model `boot_rom` belongs to, which runs
+    /// the boot ROM before the cartridge. States saved while the boot ROM
+    /// is running only load on machines started this way.
+    pub fn with_boot_rom(cartridge: Cartridge, boot_rom: BootRom) -> GameBoy {
+        let mut bus = Bus::new(boot_rom.model(), cartridge);
+        bus.map_boot_rom(boot_rom);
+        GameBoy {
+            cpu: Cpu::new(),
+            bus,
+            battery: None,
+            clocks_since_flush: 0,
+        }
+    }
+
+    /// Loads battery data from `path`, usually
+    /// [`BatterySave::path_for`] the ROM, and keeps it up to date from now
+    /// on: every few seconds of emulated time, and when the machine is
+    /// dropped. Does nothing for cartridges without a battery.
+    pub fn load_battery_save<P: Into<PathBuf>>(&mut self, path: P) -> io::Result<()> {
+        self.battery = BatterySave::open(path, self.bus.cartridge_mut())?;
+        self.clocks_since_flush = 0;
+        Ok(())
+    }
+
+    /// Writes battery data out now if it has changed.
+    pub fn flush_battery_save(&mut self) -> io::Result<()> {
+        if let Some(battery) = &mut self.battery {
+            battery.flush(self.bus.cartridge())?;
+        }
+        Ok(())
+    }
+
+    /// Snapshots the whole machine. Host-side attachments, such as the
+    /// battery save file, callbacks and input images, are not included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let section = |tag: &Tag, save: &dyn Fn(&mut StateWriter)| {
+            let mut w = StateWriter::new();
+            save(&mut w);
+            (*tag, w.into_inner())
+        };
+        state::encode(&[
+            section(b"INFO", &|w| self.save_info(w)),
+            section(b"CPU ", &|w| self.cpu.save_state(w)),
+            section(b"BUS ", &|w| self.bus.save_state(w)),
+            section(b"PPU ", &|w| self.bus.ppu.save_state(w)),
+            section(b"TIMR", &|w| self.bus.timer.save_state(w)),
+            section(b"SERL", &|w| self.bus.serial.save_state(w)),
+            section(b"JOYP", &|w| self.bus.joypad.save_state(w)),
+            section(b"APU ", &|w| self.bus.apu.save_state(w)),
+            section(b"CART", &|w| self.bus.cartridge().save_state(w)),
+        ])
+    }
+
+    /// Restores a snapshot taken by [`GameBoy::save_state`] on the same ROM
+    /// and model, possibly by an older build. The machine is left untouched
+    /// if the state cannot be loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let sections = state::decode(data)?;
+        let info =
+            state::section(&sections, b"INFO").ok_or(StateError::MissingSection(*b"INFO"))?;
+        self.check_info(&mut StateReader::new(info))?;
+
+        let backup = self.save_state();
+        let result = self.load_sections(&sections);
+        if result.is_err() {
+            self.load_sections(&state::decode(&backup)?)
+                .expect("a state saved by this build loads");
+        }
+        result
+    }
+
+    fn save_info(&self, w: &mut StateWriter) {
+        let model = self.bus.model();
+        let header = self.bus.cartridge().header();
+        w.u8(Model::ALL.iter().position(|&m| m == model).unwrap_or(0) as u8);
+        w.u8(header.header_checksum);
+        w.u16(header.global_checksum);
+    }
+
+    fn check_info(&self, r: &mut StateReader) -> Result<(), StateError> {
+        let model = Model::ALL.get(r.u8()? as usize).copied();
+        let header = self.bus.cartridge().header();
+        if r.u8()? != header.header_checksum || r.u16()? != header.global_checksum {
+            return Err(StateError::WrongRom);
+        }
+        if model != Some(self.bus.model()) {
+            return Err(StateError::WrongModel);
+        }
+        Ok(())
+    }
+
+    fn load_sections(&mut self, sections: &[(Tag, Vec<u8>)]) -> Result<(), StateError> {
+        fn load(
+            sections: &[(Tag, Vec<u8>)],
+            tag: &Tag,
+            load: impl FnOnce(&mut StateReader) -> Result<(), StateError>,
+        ) -> Result<(), StateError> {
+            // `migrate` fills in whatever older versions lacked, so every
+            // section is there in a good state.
+            let payload =
+                state::section(sections, tag).ok_or(StateError::Invalid("missing section"))?;
+            let mut r = StateReader::new(payload);
+            load(&mut r)?;
+            if r.remaining() != 0 {
+                return Err(StateError::Invalid("section length"));
+            }
+            Ok(())
+        }
+
+        load(sections, b"CPU ", |r| self.cpu.load_state(r))?;
+        load(sections, b"BUS ", |r| self.bus.load_state(r))?;
+        load(sections, b"PPU ", |r| self.bus.ppu.load_state(r))?;
+        load(sections, b"TIMR", |r| self.bus.timer.load_state(r))?;
+        load(sections, b"SERL", |r| self.bus.serial.load_state(r))?;
+        load(sections, b"JOYP", |r| self.bus.joypad.load_state(r))?;
+        load(sections, b"APU ", |r| self.bus.apu.load_state(r))?;
+        load(sections, b"CART", |r| {
+            self.bus.cartridge_mut().load_state(r)
+        })
+    }
+
+    /// Runs whole instructions until the next VBlank starts, or while the
+    /// LCD is off, until a frame's worth of clocks has passed. Returns the
+    /// number of clocks taken.
+    pub fn run_frame(&mut self) -> u32 {
+        self.bus.ppu.take_frame_ready();
+        let mut clocks = 0;
+        loop {
+            clocks += self.step();
+            if self.bus.ppu.take_frame_ready()
+                || (clocks >= CLOCKS_PER_FRAME && !self.bus.ppu.lcd_on())
+            {
+                return clocks;
+            }
+        }
+    }
+
+    /// Runs one instruction, or moves the VRAM DMA blocks that are due
+    /// while the CPU waits. Returns the number of clocks taken, at the
+    /// normal speed of 4 per machine cycle.
+    pub fn step(&mut self) -> u32 {
+        let clocks = match self.bus.run_hdma() {
+            0 => {
+                let clocks = self.cpu.step(&mut self.bus);
+                // The CPU counts 4 clocks per machine cycle, which at double
+                // speed go by twice as fast.
+                if self.bus.double_speed() {
+                    clocks / 2
+                } else {
+                    clocks
+                }
+            }
+            clocks => clocks,
+        };
+        if self.battery.is_some() {
+            self.clocks_since_flush += clocks;
+            if self.clocks_since_flush >= BATTERY_FLUSH_INTERVAL {
+                self.clocks_since_flush = 0;
+                // A failed write leaves the data dirty, so the next period
+                // tries again.
+                let _ = self.flush_battery_save();
+            }
+        }
+        clocks
+    }
+}
+
+impl Drop for GameBoy {
+    fn drop(&mut self) {
+        let _ = self.flush_battery_save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    use super::*;
+    use crate::ppu::{CompatPalettes, ManualPalette};
+    use crate::test_rom::{self, CODE_START};
+
+    /// Mixes DIV into VRAM forever while a timer interrupt counts into
+    /// cartridge RAM, so every frame changes the machine and the picture
+    /// in a way that depends on exact timing.
+    fn busy_machine() -> GameBoy {
+        let code = [
+            0x3E, 0x0A, 0xEA, 0x00, 0x00, // LD A,0x0A; LD (0x0000),A
+            0x3E, 0x05, 0xE0, 0x07, // LD A,0x05; LDH (TAC),A
+            0x3E, 0x04, 0xE0, 0xFF, // LD A,0x04; LDH (IE),A
+            0xFB, // EI
+            0x21, 0x00, 0x80, // LD HL,0x8000
+            0xF0, 0x04, // loop: LDH A,(DIV)
+            0x80, // ADD A,B
+            0x47, // LD B,A
+            0x22, // LD (HL+),A
+            0x7C, // LD A,H
+            0xFE, 0xA0, // CP 0xA0
+            0x20, 0xF6, // JR NZ,loop
+            0x21, 0x00, 0x80, // LD HL,0x8000
+            0x18, 0xF1, // JR loop
+        ];
+        // Timer handler: INC C; LD A,C; LD (0xA000),A; RETI
+        let handler: &[u8] = &[0x0C, 0x79, 0xEA, 0x00, 0xA0, 0xD9];
+        let rom = test_rom::build(0x03, 0x02, &code, &[(0x0050, handler)]);
+        GameBoy::new(Model::Dmg, Cartridge::new(rom).unwrap())
+    }
+
+    fn run_and_hash(gb: &mut GameBoy, frames: usize) -> u64 {
+        for _ in 0..frames {
+            gb.run_frame();
+        }
+        let mut hasher = DefaultHasher::new();
+        gb.save_state().hash(&mut hasher);
+        gb.bus.ppu.framebuffer().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn restore_replays_exactly() {
+        let mut gb = busy_machine();
+        run_and_hash(&mut gb, 10);
+        let state = gb.save_state();
+
+        let first = run_and_hash(&mut gb, 30);
+        gb.load_state(&state).unwrap();
+        let second = run_and_hash(&mut gb, 30);
+        assert_eq!(first, second);
+
+        // The same state restored on a fresh machine runs the same way.
+        let mut other = busy_machine();
+        other.load_state(&state).unwrap();
+        assert_eq!(run_and_hash(&mut other, 30), first);
+    }
+
+    #[test]
+    fn unknown_sections_are_skipped() {
+        let mut gb = busy_machine();
+        gb.run_frame();
+        let mut sections = state::decode(&gb.save_state()).unwrap();
+        sections.push((*b"XTRA", vec![1, 2, 3]));
+        let state = state::encode(&sections);
+
+        let mut other = busy_machine();
+        other.load_state(&state).unwrap();
+        assert_eq!(other.save_state(), gb.save_state());
+    }
+
+    #[test]
+    fn version_1_states_still_load() {
+        let mut gb = busy_machine();
+        gb.run_frame();
+        let mut sections = state::decode(&gb.save_state()).unwrap();
+        // The version 1 bus: VRAM, WRAM banks 0 and 1, OAM, HRAM, the LCD
+        // registers with the LCD off, then IE and IF. It had no PPU section.
+        let mut bus = vec![0; 0x2000 + 0x2000 + 0xA0 + 0x7F];
+        bus[0x0010] = 0x11; // 0x8010
+        bus[0x2123] = 0x5A; // 0xC123
+        bus[0x3456] = 0xA5; // 0xD456
+        bus[0x4003] = 0x33; // 0xFE03
+        bus[0x40A0 + 0x10] = 0x77; // 0xFF90
+        bus.extend(&[0x00, 0x40, 1, 2, 0, 0, 0xC1, 0xE4, 0xD0, 0xE0, 5, 6]);
+        bus.extend(&[0x05, 0x01]);
+        sections.retain(|(tag, _)| tag != b"PPU ");
+        for (tag, payload) in &mut sections {
+            if tag == b"BUS " {
+                *payload = bus.clone();
+            }
+        }
+        let mut old = state::encode(&sections);
+        old[8..10].copy_from_slice(&1u16.to_le_bytes());
+
+        let mut other = busy_machine();
+        other.load_state(&old).unwrap();
+        for (addr, value) in [
+            (0x8010, 0x11),
+            (0xC123, 0x5A),
+            (0xD456, 0xA5),
+            (0xFE03, 0x33),
+            (0xFF90, 0x77),
+            (0xFF42, 1),
+            (0xFF46, 0xC1),
+            (0xFFFF, 0x05),
+            (0xFF0F, 0xE1),
+        ] {
+            assert_eq!(other.bus.read_byte(addr), value, "{:#06x}", addr);
+        }
+        assert_eq!(other.cpu.pc, gb.cpu.pc);
+    }
+
+    #[test]
+    fn states_missing_a_section_are_rejected() {
+        let mut gb = busy_machine();
+        gb.run_frame();
+        let sections = state::decode(&gb.save_state()).unwrap();
+
+        for missing in [b"CPU ", b"BUS ", b"PPU "] {
+            let mut partial = sections.clone();
+            partial.retain(|(tag, _)| tag != missing);
+            let mut other = busy_machine();
+            let before = other.save_state();
+            assert_eq!(
+                other.load_state(&state::encode(&partial)),
+                Err(StateError::Invalid("missing section"))
+            );
+            assert_eq!(other.save_state(), before);
+        }
+    }
+
+    #[test]
+    fn bad_states_leave_the_machine_alone() {
+        let mut gb = busy_machine();
+        gb.run_frame();
+        let state = gb.save_state();
+        gb.run_frame();
+        let before = gb.save_state();
+
+        assert_eq!(gb.load_state(b"garbage"), Err(StateError::BadMagic));
+        assert_eq!(
+            gb.load_state(&state[..state.len() - 1]),
+            Err(StateError::Truncated)
+        );
+
+        let mut newer = state.clone();
+        newer[8..10].copy_from_slice(&(state::VERSION + 1).to_le_bytes());
+        assert_eq!(
+            gb.load_state(&newer),
+            Err(StateError::UnsupportedVersion(state::VERSION + 1))
+        );
+
+        // A CART section that is one byte short fails after the earlier
+        // sections have been applied, which must be rolled back.
+        let mut sections = state::decode(&state).unwrap();
+        sections.last_mut().unwrap().1.pop();
+        assert_eq!(
+            gb.load_state(&state::encode(&sections)),
+            Err(StateError::Truncated)
+        );
+        assert_eq!(gb.save_state(), before);
+
+        let other_rom = test_rom::build(0x00, 0x00, &[0x18, 0xFE], &[]);
+        let mut other = GameBoy::new(Model::Dmg, Cartridge::new(other_rom).unwrap());
+        assert_eq!(other.load_state(&state), Err(StateError::WrongRom));
+    }
+
+    #[test]
+    fn the_header_picks_the_model() {
+        for (flag, model, a) in [(0x00, Model::Dmg, 0x01), (0x80, Model::Cgb, 0x11)] {
+            let rom = test_rom::build(0x00, 0x00, &[0x18, 0xFE], &[(0x0143, &[flag])]);
+            let cartridge = Cartridge::new(rom).unwrap();
+            assert_eq!(Model::for_header(cartridge.header()), model);
+            let gb = GameBoy::new(model, cartridge);
+            assert_eq!(gb.cpu.regs.a, a);
+        }
+    }
+
+    #[test]
+    fn dmg_games_on_a_cgb_run_in_compatibility_mode() {
+        let code = [
+            0x3E, 0x01, 0xE0, 0x47, // LD A,0x01; LDH (BGP),A
+            0x18, 0xFE, // JR -2
+        ];
+        let patches: &[(usize, &[u8])] = &[(0x0134, b"TETRIS"), (0x014B, &[0x01])];
+        let rom = test_rom::build(0x00, 0x00, &code, patches);
+        let agb = GameBoy::new(Model::Agb, Cartridge::new(rom.clone()).unwrap());
+        let mut gb = GameBoy::new(Model::Cgb, Cartridge::new(rom).unwrap());
+        assert!(!gb.bus.ppu.cgb_mode());
+        // B holds the title checksum, one more on the AGB.
+        let regs = gb.cpu.regs;
+        assert_eq!((regs.a, regs.b, regs.c), (0x11, 0xDB, 0x00));
+        assert_eq!((regs.d, regs.e, regs.h, regs.l), (0x00, 0x08, 0x00, 0x7C));
+        assert_eq!(regs.f.bits(), 0x80);
+        assert_eq!((agb.cpu.regs.b, agb.cpu.regs.f.bits()), (0xDC, 0x00));
+        // The CGB registers are out of reach.
+        assert_eq!(gb.bus.read_byte(0xFF4D), 0xFF);
+        assert_eq!(gb.bus.read_byte(0xFF70), 0xFF);
+        gb.bus.write_byte(0xFF4F, 0x01);
+        assert_eq!(gb.bus.read_byte(0xFF4F), 0xFF);
+
+        // The blank background shows shade 1 in the colors picked for
+        // Tetris, until others are picked by hand.
+        gb.run_frame();
+        gb.run_frame();
+        assert!(gb.bus.ppu.color_framebuffer().iter().all(|&c| c == 0x03FF));
+        let grayscale = CompatPalettes::manual(ManualPalette::Grayscale);
+        gb.bus.ppu.set_compat_palettes(&grayscale);
+        gb.run_frame();
+        assert!(gb.bus.ppu.color_framebuffer().iter().all(|&c| c == 0x5294));
+    }
+
+    /// A stand-in for `model`'s boot ROM that leaves `cartridge` the way
+    /// the real one would, the slow way. A CGB given a DMG cartridge gets
+    /// its compatibility palettes and KEY0 first. Then it resets DIV,
+    /// writes the I/O registers and waits until DIV has counted as far as
+    /// the real one would have, before setting the CPU registers and
+    /// unmapping itself from 0x00FE, right before the cartridge entry
+    /// point. The CGB stand-ins run from above the cartridge header.
+    fn stand_in_boot_rom(model: Model, cartridge: &Cartridge) -> BootRom {
+        let header = cartridge.header();
+        let mut setup = vec![0x31, 0xFE, 0xFF]; // LD SP,0xFFFE
+        if model.is_cgb() && !header.supports_cgb() {
+            let palettes = CompatPalettes::for_header(header);
+            let colors = [
+                (0x68, &[palettes.bg][..]),
+                (0x6A, &[palettes.obj0, palettes.obj1][..]),
+            ];
+            for (index, group) in colors {
+                setup.extend(&[0x3E, 0x80, 0xE0, index]); // LD A,0x80; LDH (xCPS),A
+                for color in group.iter().flatten() {
+                    for byte in color.to_le_bytes() {
+                        setup.extend(&[0x3E, byte, 0xE0, index + 1]); // LD A,byte; LDH (xCPD),A
+                    }
+                }
+            }
+            setup.extend(&[0x3E, 0x04, 0xE0, 0x4C]); // LD A,0x04; LDH (KEY0),A
+        }
+        setup.extend(&[0xAF, 0xE0, 0x04]); // XOR A; LDH (DIV),A
+        let io = model.io_registers();
+        for &(addr, value) in &io {
+            setup.extend(&[0x3E, value, 0xE0, addr as u8]); // LD A,value; LDH (addr),A
+        }
+        // Machine cycles from the DIV reset to the entry point: 5 per
+        // register, 7 per round of the delay loop, one per NOP and 28 more
+        // around them.
+        let cycles = usize::from(model.div_counter()) / 4 - 5 * io.len() - 28;
+        let (rounds, nops) = (cycles / 7, cycles % 7);
+        setup.extend(&[0x01, rounds as u8, (rounds >> 8) as u8]); // LD BC,rounds
+        setup.extend(std::iter::repeat(0x00).take(nops)); // NOP
+        #[rustfmt::skip]
+        setup.extend(&[
+            0x0B, 0x78, 0xB1, 0x20, 0xFB, // loop: DEC BC; LD A,B; OR C; JR NZ,loop
+            0xC3, 0xF0, 0x00, // JP 0x00F0
+        ]);
+        let regs = model.registers(header);
+        #[rustfmt::skip]
+        let tail = [
+            0x01, regs.f.bits(), regs.a, // LD BC,AF
+            0xC5, 0xF1, // PUSH BC; POP AF
+            0x01, regs.c, regs.b, // LD BC,BC
+            0x11, regs.e, regs.d, // LD DE,DE
+            0x21, regs.l, regs.h, // LD HL,HL
+            0xE0, 0x50, // LDH (0xFF50),A
+        ];
+
+        let mut data = vec![0; BootRom::size(model)];
+        let start = if model.is_cgb() {
+            data[..3].copy_from_slice(&[0xC3, 0x00, 0x02]); // JP 0x0200
+            0x0200
+        } else {
+            assert!(setup.len() <= 0x00F0);
+            0x0000
+        };
+        data[start..start + setup.len()].copy_from_slice(&setup);
+        data[0x00F0..0x0100].copy_from_slice(&tail);
+        BootRom::new(model, data).unwrap()
+    }
+
+    #[test]
+    fn booting_ends_where_skipping_the_boot_rom_starts() {
+        for &model in &Model::ALL {
+            for cgb_flag in [0x00, 0x80] {
+                let rom = test_rom::build(0x00, 0x00, &[0x18, 0xFE], &[(0x0143, &[cgb_flag])]);
+                let cartridge = Cartridge::new(rom.clone()).unwrap();
+                let boot_rom = stand_in_boot_rom(model, &cartridge);
+                let mut booted = GameBoy::with_boot_rom(cartridge, boot_rom);
+                let mut skipped = GameBoy::new(model, Cartridge::new(rom).unwrap());
+                let what = format!("{:?} with CGB flag {:#04x}", model, cgb_flag);
+                assert_eq!(booted.cpu.pc, 0x0000, "{}", what);
+                for _ in 0..20_000 {
+                    if !booted.bus.boot_rom_mapped() {
+                        break;
+                    }
+                    booted.step();
+                }
+
+                assert_eq!(booted.cpu.pc, 0x0100, "{}", what);
+                assert_eq!(booted.cpu.sp, skipped.cpu.sp, "{}", what);
+                assert_eq!(booted.cpu.regs, skipped.cpu.regs, "{}", what);
+                let div = booted.bus.timer.counter();
+                assert_eq!(div, skipped.bus.timer.counter(), "{}", what);
+                assert_eq!(booted.bus.ppu.cgb_mode(), skipped.bus.ppu.cgb_mode());
+                // Where the PPU is depends on when the boot ROM turned the
+                // LCD on, and shows in STAT, LY and the palette data locked
+                // during mode 3.
+                let ppu_position = [0xFF41, 0xFF44, 0xFF69, 0xFF6B];
+                for addr in (0xFF00..=0xFF7F).chain(Some(0xFFFF)) {
+                    if !ppu_position.contains(&addr) {
+                        assert_eq!(
+                            booted.bus.read_byte(addr),
+                            skipped.bus.read_byte(addr),
+                            "{} {:#06x}",
+                            what,
+                            addr
+                        );
+                    }
+                }
+                // The blank background shows in the same color.
+                for gb in [&mut booted, &mut skipped] {
+                    gb.run_frame();
+                    gb.run_frame();
+                }
+                assert_eq!(
+                    booted.bus.ppu.color_framebuffer(),
+                    skipped.bus.ppu.color_framebuffer(),
+                    "{}",
+                    what
+                );
+            }
+        }
+    }
+
+    /// DIV and TIMA (at 16384 Hz) counted over 50 lines, on a CGB that
+    /// switches to double speed first if `double_speed` is set.
+    fn timer_ticks_over_50_lines(double_speed: bool) -> (u8, u8) {
+        let mut code = vec![
+            0x3E, 0x07, 0xE0, 0x07, // LD A,0x07; LDH (TAC),A
+        ];
+        if double_speed {
+            code.extend(&[
+                0x3E, 0x01, 0xE0, 0x4D, // LD A,0x01; LDH (KEY1),A
+                0x10, 0x00, // STOP
+            ]);
+        }
+        code.extend(&[0x18, 0xFE]); // JR -2
+        let rom = test_rom::build(0x00, 0x00, &code, &[(0x0143, &[0x80])]);
+        let mut gb = GameBoy::new(Model::Cgb, Cartridge::new(rom).unwrap());
+        while usize::from(gb.cpu.pc) < CODE_START + code.len() - 2 {
+            gb.step();
+        }
+        assert_eq!(
+            gb.bus.read_byte(0xFF4D),
+            if double_speed { 0xFE } else { 0x7E }
+        );
+
+        let sample = |gb: &mut GameBoy, line| {
+            while gb.bus.ppu.ly() != line {
+                gb.step();
+            }
+            (gb.bus.read_byte(0xFF04), gb.bus.read_byte(0xFF05))
+        };
+        let (div, tima) = sample(&mut gb, 10);
+        let (div_after, tima_after) = sample(&mut gb, 60);
+        (div_after.wrapping_sub(div), tima_after.wrapping_sub(tima))
+    }
+
+    #[test]
+    fn double_speed_doubles_the_timer_against_the_lcd() {
+        // 50 lines are 22800 clocks, which DIV and this TIMA rate count in
+        // 256s.
+        let (div, tima) = timer_ticks_over_50_lines(false);
+        assert!((89..=90).contains(&div), "{}", div);
+        assert!((89..=90).contains(&tima), "{}", tima);
+        let (div, tima) = timer_ticks_over_50_lines(true);
+        assert!((178..=179).contains(&div), "{}", div);
+        assert!((178..=179).contains(&tima), "{}", tima);
+    }
+}
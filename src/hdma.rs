@@ -0,0 +1,116 @@
+//! HDMA1-HDMA5, the CGB's DMA from ROM, cartridge RAM or WRAM into VRAM.
+//!
+//! Transfers move 16-byte blocks. A general-purpose transfer moves every
+//! block as soon as HDMA5 is written, and an HBlank transfer moves one
+//! block at the start of each HBlank. The CPU is stalled while a block
+//! moves; the bus does the copying, since it can reach both ends.
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Bytes moved per block.
+pub const BLOCK_SIZE: u16 = 0x10;
+
+#[derive(Clone, Debug, Default)]
+pub struct Hdma {
+    /// The next byte to read. The low four bits are always clear.
+    source: u16,
+    /// The next byte to write, as an offset into VRAM.
+    dest: u16,
+    /// Blocks left, minus one, as HDMA5 reports them.
+    length: u8,
+    /// An HBlank transfer is running.
+    hblank: bool,
+    /// Blocks due to move before the CPU runs again.
+    pending: u8,
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma::default()
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // Bit 7 is clear while an HBlank transfer is running.
+            0xFF55 => (!self.hblank as u8) << 7 | self.length,
+            // The address registers are write-only.
+            _ => 0xFF,
+        }
+    }
+
+    /// `hblank` says whether the PPU is somewhere an HBlank transfer can
+    /// start right away: in HBlank or with the LCD off.
+    pub fn write(&mut self, addr: u16, value: u8, hblank: bool) {
+        match addr {
+            0xFF51 => self.source = u16::from(value) << 8 | self.source & 0x00F0,
+            0xFF52 => self.source = self.source & 0xFF00 | u16::from(value & 0xF0),
+            0xFF53 => self.dest = u16::from(value & 0x1F) << 8 | self.dest & 0x00F0,
+            0xFF54 => self.dest = self.dest & 0x1F00 | u16::from(value & 0xF0),
+            0xFF55 => self.start(value, hblank),
+            _ => {}
+        }
+    }
+
+    fn start(&mut self, value: u8, hblank: bool) {
+        if self.hblank && value & 0x80 == 0 {
+            // Clearing bit 7 stops an HBlank transfer where it is.
+            self.hblank = false;
+            self.pending = 0;
+            return;
+        }
+        self.length = value & 0x7F;
+        if value & 0x80 != 0 {
+            self.hblank = true;
+            self.pending = hblank as u8;
+        } else {
+            self.pending = self.length + 1;
+        }
+    }
+
+    /// Called as HBlank starts on a visible line.
+    pub fn hblank_started(&mut self) {
+        if self.hblank {
+            self.pending = 1;
+        }
+    }
+
+    /// Takes the next block due to move, as its source address and VRAM
+    /// offset, and advances the registers past it.
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if self.pending == 0 {
+            return None;
+        }
+        self.pending -= 1;
+        let block = (self.source, self.dest);
+        self.source = self.source.wrapping_add(BLOCK_SIZE);
+        self.dest = (self.dest + BLOCK_SIZE) & 0x1FF0;
+        // The count wraps to 0x7F after the last block, which with bit 7
+        // set reads back as 0xFF.
+        self.length = self.length.wrapping_sub(1) & 0x7F;
+        if self.length == 0x7F {
+            self.hblank = false;
+            self.pending = 0;
+        }
+        Some(block)
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.source);
+        w.u16(self.dest);
+        w.u8(self.length);
+        w.bool(self.hblank);
+        w.u8(self.pending);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.source = r.u16()? & 0xFFF0;
+        self.dest = r.u16()? & 0x1FF0;
+        self.length = r.u8()? & 0x7F;
+        self.hblank = r.bool()?;
+        self.pending = r.u8()?;
+        if self.pending > 0x80 {
+            return Err(StateError::Invalid("HDMA block count"));
+        }
+        Ok(())
+    }
+}
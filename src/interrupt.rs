@@ -0,0 +1,15 @@
+/// The five interrupt sources, by their bit in IE and IF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank = 0,
+    Stat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
+}
+
+impl Interrupt {
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
@@ -0,0 +1,114 @@
+//! The P1 register.
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    /// Bit in the pressed mask: directions in the low nibble, actions in the
+    /// high nibble, each in P1 line order.
+    fn mask(self) -> u8 {
+        match self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Joypad {
+    /// P1 bits 4 and 5, as written. A zero selects the group.
+    select: u8,
+    pressed: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad {
+            select: 0x30,
+            pressed: 0,
+        }
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad::default()
+    }
+
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.select = value & 0x30;
+    }
+
+    /// Returns true when the press pulls a selected line low, which requests
+    /// the joypad interrupt.
+    pub fn press(&mut self, button: Button) -> bool {
+        let before = self.lines();
+        self.pressed |= button.mask();
+        before & !self.lines() != 0
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !button.mask();
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.select);
+        w.u8(self.pressed);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.select = r.u8()? & 0x30;
+        self.pressed = r.u8()?;
+        Ok(())
+    }
+
+    /// Buttons held down, one bit each: Right, Left, Up and Down in the low
+    /// nibble, A, B, Select and Start in the high nibble.
+    pub fn pressed(&self) -> u8 {
+        self.pressed
+    }
+
+    /// The four input lines, active low.
+    fn lines(&self) -> u8 {
+        let mut low = 0;
+        if self.select & 0x10 == 0 {
+            low |= self.pressed & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            low |= self.pressed >> 4;
+        }
+        !low & 0x0F
+    }
+}
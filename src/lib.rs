@@ -0,0 +1,20 @@
+pub mod apu;
+pub mod battery;
+pub mod boot_rom;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod gameboy;
+pub mod hdma;
+pub mod interrupt;
+pub mod joypad;
+pub mod model;
+pub mod ppu;
+pub mod registers;
+pub mod rewind;
+pub mod serial;
+pub mod state;
+pub mod timer;
+
+#[cfg(test)]
+mod test_rom;
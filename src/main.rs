@@ -1,17 +1,76 @@
+use std::env;
+use std::fs;
+use std::process;
 
-struct registers
-{
-    a: u8,
-    b: u8,
-    c: u8,
-    d: u8,
-    e: u8,
-    f: u8,
-    h: u8,
-    l: u8
-}
-
+use rustboy::cartridge::header::{CgbFlag, Destination};
+use rustboy::cartridge::Header;
 
 fn main() {
-    println!("Hello, world!");
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: rustboy <rom>");
+            process::exit(2);
+        }
+    };
+
+    let rom = match fs::read(&path) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        }
+    };
+
+    let header = match Header::parse(&rom) {
+        Ok(header) => header,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        }
+    };
+
+    print_header(&header);
+    for problem in header.validate(&rom) {
+        println!("warning: {}", problem);
+    }
+}
+
+fn print_header(header: &Header) {
+    println!("Title:          {}", header.title);
+    if let Some(code) = &header.manufacturer_code {
+        println!("Manufacturer:   {}", code);
+    }
+    let cgb = match header.cgb_flag {
+        CgbFlag::None => "no",
+        CgbFlag::Compatible => "yes",
+        CgbFlag::Only => "required",
+    };
+    println!("CGB:            {}", cgb);
+    println!(
+        "SGB:            {}",
+        if header.sgb_flag { "yes" } else { "no" }
+    );
+    if header.old_licensee_code == 0x33 {
+        println!(
+            "Licensee:       {}",
+            String::from_utf8_lossy(&header.new_licensee_code)
+        );
+    } else {
+        println!("Licensee:       {:02X}", header.old_licensee_code);
+    }
+    println!(
+        "Type:           {} ({:#04x})",
+        header.cartridge_type, header.cartridge_type.code
+    );
+    println!("ROM size:       {} KiB", header.rom_size() / 1024);
+    println!("RAM size:       {} KiB", header.ram_size() / 1024);
+    let destination = match header.destination {
+        Destination::Japan => "Japan",
+        Destination::Overseas => "overseas",
+    };
+    println!("Destination:    {}", destination);
+    println!("Version:        {}", header.version);
+    println!("Header sum:     {:#04x}", header.header_checksum);
+    println!("Global sum:     {:#06x}", header.global_checksum);
 }
@@ -0,0 +1,313 @@
+//! The hardware revisions rustboy can emulate, and the state each one is in
+//! when its boot ROM hands over to the cartridge at 0x0100.
+//!
+//! Games look at these values (mostly A and B) to tell the models apart, so
+//! skipping the boot ROM has to reproduce them exactly.
+
+use crate::cartridge::Header;
+use crate::registers::{Flags, Registers};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Model {
+    /// Early DMG with the original boot ROM.
+    Dmg0,
+    Dmg,
+    /// Game Boy Pocket and Light.
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    /// Game Boy Advance running Game Boy software.
+    Agb,
+}
+
+impl Model {
+    pub const ALL: [Model; 7] = [
+        Model::Dmg0,
+        Model::Dmg,
+        Model::Mgb,
+        Model::Sgb,
+        Model::Sgb2,
+        Model::Cgb,
+        Model::Agb,
+    ];
+
+    /// The model to run a cartridge on: CGB when its header says it uses the
+    /// color hardware, DMG otherwise.
+    pub fn for_header(header: &Header) -> Model {
+        if header.supports_cgb() {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    /// Whether the model has the Game Boy Color hardware.
+    pub fn is_cgb(self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    pub fn is_sgb(self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    /// CPU registers after the boot ROM. DMG and MGB leave H and C set unless
+    /// the header checksum at 0x014D is zero. CGB models leave different
+    /// values for DMG cartridges, with the title checksum of Nintendo's
+    /// games in B.
+    pub fn registers(self, header: &Header) -> Registers {
+        let checksum_flags = header.header_checksum != 0;
+        let dmg_on_cgb = self.is_cgb() && !header.supports_cgb();
+        let title = if header.nintendo_licensed() {
+            header.title_checksum
+        } else {
+            0x00
+        };
+        // The two titles whose palettes the boot ROM patches leave HL
+        // pointing into the tile map.
+        let (dmg_h, dmg_l) = if title == 0x43 || title == 0x58 {
+            (0x99, 0x1A)
+        } else {
+            (0x00, 0x7C)
+        };
+        let (a, f, b, c, d, e, h, l) = match self {
+            Model::Cgb if dmg_on_cgb => (
+                0x11,
+                Flags::new(true, false, false, false),
+                title,
+                0x00,
+                0x00,
+                0x08,
+                dmg_h,
+                dmg_l,
+            ),
+            // The extra `INC B` sets the flags from the title checksum.
+            Model::Agb if dmg_on_cgb => {
+                let b = title.wrapping_add(1);
+                let f = Flags::new(b == 0, false, b & 0x0F == 0, false);
+                (0x11, f, b, 0x00, 0x00, 0x08, dmg_h, dmg_l)
+            }
+            Model::Dmg0 => (0x01, Flags::default(), 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+            Model::Dmg | Model::Mgb => (
+                if self == Model::Dmg { 0x01 } else { 0xFF },
+                Flags::new(true, false, checksum_flags, checksum_flags),
+                0x00,
+                0x13,
+                0x00,
+                0xD8,
+                0x01,
+                0x4D,
+            ),
+            Model::Sgb => (0x01, Flags::default(), 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Sgb2 => (0xFF, Flags::default(), 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Cgb => (
+                0x11,
+                Flags::new(true, false, false, false),
+                0x00,
+                0x00,
+                0xFF,
+                0x56,
+                0x00,
+                0x0D,
+            ),
+            // The AGB boot ROM ends with an extra `INC B`.
+            Model::Agb => (0x11, Flags::default(), 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+        };
+        Registers {
+            a,
+            b,
+            c,
+            d,
+            e,
+            f,
+            h,
+            l,
+        }
+    }
+
+    /// The full 16-bit divider counter after the boot ROM. DIV is its upper
+    /// byte.
+    pub fn div_counter(self) -> u16 {
+        match self {
+            Model::Dmg0 => 0x182C,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb | Model::Sgb2 => 0xD85C,
+            Model::Cgb | Model::Agb => 0x267C,
+        }
+    }
+
+    /// I/O register writes that recreate the state the boot ROM leaves
+    /// behind, in the order they have to be applied. DIV is not included; see
+    /// [`Model::div_counter`].
+    pub fn io_registers(self) -> Vec<(u16, u8)> {
+        let cgb = self.is_cgb();
+        let mut io = vec![
+            (0xFF00, 0xCF),                          // P1
+            (0xFF01, 0x00),                          // SB
+            (0xFF02, if cgb { 0x7F } else { 0x7E }), // SC
+            (0xFF05, 0x00),                          // TIMA
+            (0xFF06, 0x00),                          // TMA
+            (0xFF07, 0xF8),                          // TAC
+            (0xFF0F, 0xE1),                          // IF
+            // NR52 first: the other sound registers ignore writes while the
+            // APU is off.
+            (0xFF26, if self.is_sgb() { 0xF0 } else { 0xF1 }),
+            (0xFF10, 0x80), // NR10
+            (0xFF11, 0xBF), // NR11
+            (0xFF12, 0xF3), // NR12
+            (0xFF13, 0xFF), // NR13
+            (0xFF14, 0xBF), // NR14
+            (0xFF16, 0x3F), // NR21
+            (0xFF17, 0x00), // NR22
+            (0xFF18, 0xFF), // NR23
+            (0xFF19, 0xBF), // NR24
+            (0xFF1A, 0x7F), // NR30
+            (0xFF1B, 0xFF), // NR31
+            (0xFF1C, 0x9F), // NR32
+            (0xFF1D, 0xFF), // NR33
+            (0xFF1E, 0xBF), // NR34
+            (0xFF20, 0xFF), // NR41
+            (0xFF21, 0x00), // NR42
+            (0xFF22, 0x00), // NR43
+            (0xFF23, 0xBF), // NR44
+            (0xFF24, 0x77), // NR50
+            (0xFF25, 0xF3), // NR51
+            (0xFF40, 0x91), // LCDC
+            (0xFF42, 0x00), // SCY
+            (0xFF43, 0x00), // SCX
+            (0xFF45, 0x00), // LYC
+            (0xFF47, 0xFC), // BGP
+            (0xFF48, 0xFF), // OBP0
+            (0xFF49, 0xFF), // OBP1
+            (0xFF4A, 0x00), // WY
+            (0xFF4B, 0x00), // WX
+        ];
+        if cgb {
+            io.extend_from_slice(&[
+                (0xFF4D, 0x7E), // KEY1
+                (0xFF4F, 0xFE), // VBK
+                (0xFF70, 0xF8), // SVBK
+            ]);
+        }
+        io
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_rom;
+
+    /// The test ROM's header, with `cgb_flag` at 0x0143.
+    fn header(cgb_flag: u8) -> Header {
+        let rom = test_rom::build(0x00, 0x00, &[], &[(0x0143, &[cgb_flag])]);
+        let mut header = Header::parse(&rom).unwrap();
+        header.header_checksum = 0x42;
+        header
+    }
+
+    /// AF, BC, DE and HL.
+    fn pairs(regs: Registers) -> [u16; 4] {
+        [regs.af(), regs.bc(), regs.de(), regs.hl()]
+    }
+
+    #[test]
+    fn registers_for_each_model() {
+        #[rustfmt::skip]
+        let table = [
+            (Model::Dmg0, [0x0100, 0xFF13, 0x00C1, 0x8403]),
+            (Model::Dmg,  [0x01B0, 0x0013, 0x00D8, 0x014D]),
+            (Model::Mgb,  [0xFFB0, 0x0013, 0x00D8, 0x014D]),
+            (Model::Sgb,  [0x0100, 0x0014, 0x0000, 0xC060]),
+            (Model::Sgb2, [0xFF00, 0x0014, 0x0000, 0xC060]),
+            (Model::Cgb,  [0x1180, 0x0000, 0xFF56, 0x000D]),
+            (Model::Agb,  [0x1100, 0x0100, 0xFF56, 0x000D]),
+        ];
+        let header = header(0x80);
+        for (model, expected) in table {
+            assert_eq!(pairs(model.registers(&header)), expected, "{:?}", model);
+        }
+    }
+
+    #[test]
+    fn a_zero_header_checksum_clears_h_and_c_on_dmg_and_mgb() {
+        let checksummed = header(0x00);
+        let mut zero = checksummed.clone();
+        zero.header_checksum = 0x00;
+        for model in Model::ALL {
+            let expected = match model {
+                Model::Dmg | Model::Mgb => 0x80,
+                _ => model.registers(&checksummed).f.bits(),
+            };
+            assert_eq!(model.registers(&zero).f.bits(), expected, "{:?}", model);
+        }
+    }
+
+    #[test]
+    fn dmg_games_on_cgb_and_agb_depend_on_the_title_checksum() {
+        // Licensee, title checksum, then AF, BC, DE, HL on CGB and on AGB.
+        #[rustfmt::skip]
+        let table = [
+            ((0x00, *b"00"), 0x43, [0x1180, 0x0000, 0x0008, 0x007C], [0x1100, 0x0100, 0x0008, 0x007C]),
+            ((0x01, *b"00"), 0x43, [0x1180, 0x4300, 0x0008, 0x991A], [0x1100, 0x4400, 0x0008, 0x991A]),
+            ((0x33, *b"01"), 0x58, [0x1180, 0x5800, 0x0008, 0x991A], [0x1100, 0x5900, 0x0008, 0x991A]),
+            ((0x33, *b"08"), 0x58, [0x1180, 0x0000, 0x0008, 0x007C], [0x1100, 0x0100, 0x0008, 0x007C]),
+            ((0x01, *b"00"), 0x0F, [0x1180, 0x0F00, 0x0008, 0x007C], [0x1120, 0x1000, 0x0008, 0x007C]),
+            ((0x01, *b"00"), 0xFF, [0x1180, 0xFF00, 0x0008, 0x007C], [0x11A0, 0x0000, 0x0008, 0x007C]),
+        ];
+        for ((old, new), title, cgb, agb) in table {
+            let mut header = header(0x00);
+            header.old_licensee_code = old;
+            header.new_licensee_code = new;
+            header.title_checksum = title;
+            let what = format!("licensee {:#04x}/{:?}, title {:#04x}", old, new, title);
+            assert_eq!(pairs(Model::Cgb.registers(&header)), cgb, "CGB {}", what);
+            assert_eq!(pairs(Model::Agb.registers(&header)), agb, "AGB {}", what);
+        }
+    }
+
+    #[test]
+    fn div_counter_for_each_model() {
+        let table = [
+            (Model::Dmg0, 0x182C),
+            (Model::Dmg, 0xABCC),
+            (Model::Mgb, 0xABCC),
+            (Model::Sgb, 0xD85C),
+            (Model::Sgb2, 0xD85C),
+            (Model::Cgb, 0x267C),
+            (Model::Agb, 0x267C),
+        ];
+        for (model, counter) in table {
+            assert_eq!(model.div_counter(), counter, "{:?}", model);
+        }
+    }
+
+    #[test]
+    fn io_registers_for_each_model() {
+        // SC, NR52 and whether the CGB registers are written.
+        let table = [
+            (Model::Dmg0, 0x7E, 0xF1, false),
+            (Model::Dmg, 0x7E, 0xF1, false),
+            (Model::Mgb, 0x7E, 0xF1, false),
+            (Model::Sgb, 0x7E, 0xF0, false),
+            (Model::Sgb2, 0x7E, 0xF0, false),
+            (Model::Cgb, 0x7F, 0xF1, true),
+            (Model::Agb, 0x7F, 0xF1, true),
+        ];
+        for (model, sc, nr52, cgb) in table {
+            let io = model.io_registers();
+            let value = |addr| io.iter().find(|&&(a, _)| a == addr).map(|&(_, v)| v);
+            assert_eq!(value(0xFF02), Some(sc), "{:?}", model);
+            assert_eq!(value(0xFF26), Some(nr52), "{:?}", model);
+            assert_eq!(value(0xFF40), Some(0x91), "{:?}", model);
+            assert_eq!(value(0xFF47), Some(0xFC), "{:?}", model);
+            for (addr, reg) in [(0xFF4D, 0x7E), (0xFF4F, 0xFE), (0xFF70, 0xF8)] {
+                assert_eq!(value(addr), Some(reg).filter(|_| cgb), "{:?}", model);
+            }
+            assert!(io.iter().all(|&(addr, _)| addr != 0xFF04), "{:?}", model);
+            // NR52 turns the APU on before any other sound register.
+            let first_sound = io.iter().position(|&(a, _)| (0xFF10..=0xFF3F).contains(&a));
+            assert_eq!(first_sound.map(|i| io[i].0), Some(0xFF26), "{:?}", model);
+        }
+    }
+}
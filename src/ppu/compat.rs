@@ -0,0 +1,402 @@
+//! The palettes a CGB gives games made for the DMG.
+//!
+//! The CGB runs such games in a compatibility mode, where the DMG palette
+//! registers pick colors from background palette 0 and object palettes 0
+//! and 1 instead of shades. The boot ROM fills those in before handing
+//! over. For cartridges with a Nintendo licensee it sums the 16 title bytes
+//! at 0x0134-0x0143 and looks the sum up in a table of known games; where
+//! two games share a sum, the fourth letter of the title tells them apart.
+//! Anything else gets the default palettes.
+//!
+//! Holding a direction, alone or with A or B, while the logo shows
+//! overrides the choice with one of twelve [`ManualPalette`]s.
+
+use crate::cartridge::Header;
+use crate::joypad::Button;
+
+/// The colors a DMG game is shown in, in RGB555, each indexed by the shade
+/// the matching DMG palette register gives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompatPalettes {
+    /// Background and window, through BGP.
+    pub bg: [u16; 4],
+    /// Objects using OBP0.
+    pub obj0: [u16; 4],
+    /// Objects using OBP1.
+    pub obj1: [u16; 4],
+}
+
+impl CompatPalettes {
+    /// What the boot ROM picks for the cartridge with `header`.
+    pub fn for_header(header: &Header) -> CompatPalettes {
+        let index = title_index(header).unwrap_or(0);
+        CompatPalettes::combination(TITLE_COMBINATIONS[index])
+    }
+
+    /// The palettes chosen by holding `palette`'s buttons at boot.
+    pub fn manual(palette: ManualPalette) -> CompatPalettes {
+        CompatPalettes::combination(palette.combination())
+    }
+
+    fn combination(id: u8) -> CompatPalettes {
+        let [obj0, obj1, bg] = COMBINATIONS[usize::from(id)];
+        CompatPalettes {
+            bg: colors(bg),
+            obj0: colors(obj0),
+            obj1: colors(obj1),
+        }
+    }
+}
+
+/// The palettes that can be picked by hand, each named for its background
+/// and listed with the buttons that pick it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ManualPalette {
+    /// Up.
+    Brown,
+    /// Up + A.
+    Red,
+    /// Up + B.
+    DarkBrown,
+    /// Left.
+    Blue,
+    /// Left + A.
+    DarkBlue,
+    /// Left + B.
+    Grayscale,
+    /// Down.
+    PastelMix,
+    /// Down + A.
+    Orange,
+    /// Down + B.
+    Yellow,
+    /// Right.
+    Green,
+    /// Right + A. Also what unknown games get.
+    DarkGreen,
+    /// Right + B.
+    Inverted,
+}
+
+impl ManualPalette {
+    pub const ALL: [ManualPalette; 12] = [
+        ManualPalette::Brown,
+        ManualPalette::Red,
+        ManualPalette::DarkBrown,
+        ManualPalette::Blue,
+        ManualPalette::DarkBlue,
+        ManualPalette::Grayscale,
+        ManualPalette::PastelMix,
+        ManualPalette::Orange,
+        ManualPalette::Yellow,
+        ManualPalette::Green,
+        ManualPalette::DarkGreen,
+        ManualPalette::Inverted,
+    ];
+
+    /// The direction and the optional A or B that pick this palette.
+    pub fn buttons(self) -> (Button, Option<Button>) {
+        use self::ManualPalette::*;
+        match self {
+            Brown => (Button::Up, None),
+            Red => (Button::Up, Some(Button::A)),
+            DarkBrown => (Button::Up, Some(Button::B)),
+            Blue => (Button::Left, None),
+            DarkBlue => (Button::Left, Some(Button::A)),
+            Grayscale => (Button::Left, Some(Button::B)),
+            PastelMix => (Button::Down, None),
+            Orange => (Button::Down, Some(Button::A)),
+            Yellow => (Button::Down, Some(Button::B)),
+            Green => (Button::Right, None),
+            DarkGreen => (Button::Right, Some(Button::A)),
+            Inverted => (Button::Right, Some(Button::B)),
+        }
+    }
+
+    /// The palette `held` picks, if any. It needs exactly one direction,
+    /// and at most one of A and B; other buttons are ignored.
+    pub fn from_buttons(held: &[Button]) -> Option<ManualPalette> {
+        let pressed = |button| held.contains(&button);
+        let mut directions = [Button::Right, Button::Left, Button::Up, Button::Down]
+            .iter()
+            .copied()
+            .filter(|&button| pressed(button));
+        let direction = directions.next()?;
+        if directions.next().is_some() {
+            return None;
+        }
+        let modifier = match (pressed(Button::A), pressed(Button::B)) {
+            (false, false) => None,
+            (true, false) => Some(Button::A),
+            (false, true) => Some(Button::B),
+            (true, true) => return None,
+        };
+        ManualPalette::ALL
+            .iter()
+            .copied()
+            .find(|palette| palette.buttons() == (direction, modifier))
+    }
+
+    fn combination(self) -> u8 {
+        use self::ManualPalette::*;
+        match self {
+            Brown => 5,
+            Red => 43,
+            DarkBrown => 28,
+            Blue => 48,
+            DarkBlue => 40,
+            Grayscale => 7,
+            PastelMix => 8,
+            Orange => 3,
+            Yellow => 49,
+            Green => 1,
+            DarkGreen => 0,
+            Inverted => 6,
+        }
+    }
+}
+
+/// Where the boot ROM finds the cartridge in its table of known games, or
+/// `None` if it is not there.
+fn title_index(header: &Header) -> Option<usize> {
+    if !header.nintendo_licensed() {
+        return None;
+    }
+    let sum = header.title_checksum;
+    // Titles shorter than four letters, or with odd bytes in them, match
+    // none of the letters.
+    let fourth_letter = header.title.as_bytes().get(3).copied();
+    (0..TITLE_COMBINATIONS.len()).find(|&index| {
+        if index < AMBIGUOUS {
+            CHECKSUMS[index] == sum
+        } else {
+            // The letters run through the shared sums more than once.
+            let shared = AMBIGUOUS + (index - AMBIGUOUS) % (CHECKSUMS.len() - AMBIGUOUS);
+            CHECKSUMS[shared] == sum && Some(FOURTH_LETTERS[index - AMBIGUOUS]) == fourth_letter
+        }
+    })
+}
+
+/// The four colors starting `offset` colors into [`PALETTES`].
+fn colors(offset: u8) -> [u16; 4] {
+    let mut colors = [0; 4];
+    for (i, color) in colors.iter_mut().enumerate() {
+        let offset = usize::from(offset) + i;
+        *color = PALETTES[offset / 4][offset % 4];
+    }
+    colors
+}
+
+/// Title sums from this index on are shared by more than one game.
+const AMBIGUOUS: usize = 65;
+
+/// Title sums of the known games. Zero is a placeholder for the default.
+#[rustfmt::skip]
+const CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+    // Shared sums.
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+];
+
+/// The fourth title letter for each index from [`AMBIGUOUS`] on, taking
+/// the shared sums in order, then again.
+const FOURTH_LETTERS: [u8; 29] = *b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// The palette combination for each index into the known games, 0 being
+/// the default.
+#[rustfmt::skip]
+const TITLE_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17,
+    46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+/// Object palette 0, object palette 1 and background of a combination, as
+/// offsets in colors into [`PALETTES`].
+type Combination = [u8; 3];
+
+/// A combination of whole palettes.
+const fn palettes(obj0: u8, obj1: u8, bg: u8) -> Combination {
+    [obj0 * 4, obj1 * 4, bg * 4]
+}
+
+/// The palette combinations. A few start partway into a palette and run
+/// into the next.
+const COMBINATIONS: [Combination; 51] = [
+    palettes(4, 4, 29),
+    palettes(18, 18, 18),
+    palettes(20, 20, 20),
+    palettes(24, 24, 24),
+    palettes(9, 9, 9),
+    palettes(0, 0, 0),
+    palettes(27, 27, 27),
+    palettes(5, 5, 5),
+    palettes(12, 12, 12),
+    palettes(26, 26, 26),
+    palettes(16, 8, 8),
+    palettes(4, 28, 28),
+    palettes(4, 2, 2),
+    palettes(3, 4, 4),
+    palettes(4, 29, 29),
+    palettes(28, 4, 28),
+    palettes(2, 17, 2),
+    palettes(16, 16, 8),
+    palettes(4, 4, 7),
+    palettes(4, 4, 18),
+    palettes(4, 4, 20),
+    palettes(19, 19, 9),
+    [4 * 4 - 1, 4 * 4 - 1, 11 * 4],
+    palettes(17, 17, 2),
+    palettes(4, 4, 2),
+    palettes(4, 4, 3),
+    palettes(28, 28, 0),
+    palettes(3, 3, 0),
+    palettes(0, 0, 1),
+    palettes(18, 22, 18),
+    palettes(20, 22, 20),
+    palettes(24, 22, 24),
+    palettes(16, 22, 8),
+    palettes(17, 4, 13),
+    [28 * 4 - 1, 0, 14 * 4],
+    [28 * 4 - 1, 4 * 4, 15 * 4],
+    [19 * 4, 23 * 4 - 1, 9 * 4],
+    palettes(16, 28, 10),
+    palettes(4, 23, 28),
+    palettes(17, 22, 2),
+    palettes(4, 0, 2),
+    palettes(4, 28, 3),
+    palettes(28, 3, 0),
+    palettes(3, 28, 4),
+    palettes(21, 28, 4),
+    palettes(3, 28, 0),
+    palettes(25, 3, 28),
+    palettes(0, 28, 8),
+    palettes(4, 3, 28),
+    palettes(28, 3, 6),
+    palettes(4, 28, 29),
+];
+
+/// The boot ROM's palettes, lightest color first.
+const PALETTES: [[u16; 4]; 30] = [
+    [0x7FFF, 0x32BF, 0x00D0, 0x0000],
+    [0x639F, 0x4279, 0x15B0, 0x04CB],
+    [0x7FFF, 0x6E31, 0x454A, 0x0000],
+    [0x7FFF, 0x1BEF, 0x0200, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x5294, 0x294A, 0x0000],
+    [0x7FFF, 0x03FF, 0x012F, 0x0000],
+    [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+    [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+    [0x7E74, 0x03FF, 0x0180, 0x0000],
+    [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+    [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+    [0x53FF, 0x4A5F, 0x7E52, 0x0000],
+    [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+    [0x03ED, 0x7FFF, 0x255F, 0x0000],
+    [0x036A, 0x021F, 0x03FF, 0x7FFF],
+    [0x7FFF, 0x01DF, 0x0112, 0x0000],
+    [0x231F, 0x035F, 0x00F2, 0x0009],
+    [0x7FFF, 0x03EA, 0x011F, 0x0000],
+    [0x299F, 0x001A, 0x000C, 0x0000],
+    [0x7FFF, 0x027F, 0x001F, 0x0000],
+    [0x7FFF, 0x03E0, 0x0206, 0x0120],
+    [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+    [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+    [0x7FFF, 0x03FF, 0x001F, 0x0000],
+    [0x03FF, 0x001F, 0x000C, 0x0000],
+    [0x7FFF, 0x033F, 0x0193, 0x0000],
+    [0x0000, 0x4200, 0x037F, 0x7FFF],
+    [0x7FFF, 0x7E8C, 0x7C00, 0x0000],
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header with `title` and a Nintendo licensee code.
+    fn rom(title: &str) -> Vec<u8> {
+        let mut rom = vec![0; 0x0150];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title.as_bytes());
+        rom[0x014B] = 0x01;
+        rom
+    }
+
+    fn header(rom: &[u8]) -> Header {
+        Header::parse(rom).unwrap()
+    }
+
+    const RED: [u16; 4] = [0x7FFF, 0x421F, 0x1CF2, 0x0000];
+    const GREEN: [u16; 4] = [0x7FFF, 0x1BEF, 0x0200, 0x0000];
+    const BLUE: [u16; 4] = [0x7FFF, 0x7E8C, 0x7C00, 0x0000];
+
+    #[test]
+    fn known_titles_get_their_palettes() {
+        let red = CompatPalettes::for_header(&header(&rom("POKEMON RED")));
+        assert_eq!(red.bg, RED);
+        assert_eq!(red.obj0, GREEN);
+        assert_eq!(red.obj1, RED);
+
+        // The new licensee code counts when the old one says to look there.
+        let mut blue = rom("POKEMON BLUE");
+        blue[0x014B] = 0x33;
+        blue[0x0144..0x0146].copy_from_slice(b"01");
+        let blue = CompatPalettes::for_header(&header(&blue));
+        assert_eq!(blue.bg, BLUE);
+        assert_eq!(blue.obj0, RED);
+        assert_eq!(blue.obj1, BLUE);
+    }
+
+    #[test]
+    fn the_fourth_letter_settles_shared_sums() {
+        // Both titles sum to 0x46.
+        assert_eq!(title_index(&header(&rom("SUPER MARIOLAND"))), Some(66));
+        assert_eq!(title_index(&header(&rom("METROID2"))), Some(80));
+        // The same sum with another fourth letter is not a known game.
+        let mut other = rom("METROID2");
+        other[0x0137] = b'Z';
+        other[0x0138] -= b'Z' - b'R';
+        assert_eq!(title_index(&header(&other)), None);
+    }
+
+    #[test]
+    fn other_licensees_get_the_default() {
+        let mut rom = rom("POKEMON RED");
+        rom[0x014B] = 0x33;
+        rom[0x0144..0x0146].copy_from_slice(b"08");
+        let palettes = CompatPalettes::for_header(&header(&rom));
+        assert_eq!(palettes, CompatPalettes::manual(ManualPalette::DarkGreen));
+        assert_eq!(palettes.bg, [0x7FFF, 0x1BEF, 0x6180, 0x0000]);
+        assert_eq!(palettes.obj0, RED);
+    }
+
+    #[test]
+    fn manual_palettes_follow_the_buttons() {
+        for &palette in &ManualPalette::ALL {
+            let (direction, modifier) = palette.buttons();
+            let mut held = vec![direction, Button::Start];
+            held.extend(modifier);
+            assert_eq!(ManualPalette::from_buttons(&held), Some(palette));
+        }
+        assert_eq!(ManualPalette::from_buttons(&[Button::A]), None);
+        assert_eq!(
+            ManualPalette::from_buttons(&[Button::Up, Button::Left]),
+            None
+        );
+        assert_eq!(
+            ManualPalette::from_buttons(&[Button::Up, Button::A, Button::B]),
+            None
+        );
+        assert_eq!(
+            CompatPalettes::manual(ManualPalette::Grayscale).bg[1],
+            0x5294
+        );
+    }
+}
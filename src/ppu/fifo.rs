@@ -0,0 +1,277 @@
+//! The pixel FIFO renderer: a fetcher reads one tile row every six dots
+//! and pushes it into a FIFO, which shifts out one pixel per dot. Registers
+//! are sampled when each tile is fetched and palettes as each pixel leaves
+//! the FIFO, so changes in the middle of mode 3 land where they would on
+//! hardware, and the length of mode 3 falls out of the work done.
+//!
+//! Mode 3 starts with a fetch whose result is thrown away. The first SCX % 8
+//! pixels are shifted out and dropped, and starting the window empties the
+//! FIFO and restarts the fetcher on the window's first tile. When the next
+//! pixel is where an object starts, everything stops while the object's
+//! row is fetched and laid over the eight pixels of the object FIFO.
+
+use super::sprites::ObjPixel;
+use super::{bg_pixel, Ppu, SCREEN_WIDTH};
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Dots the fetcher takes to read a tile number and its two bitplanes.
+const FETCH_DOTS: u8 = 6;
+/// The fetcher only pushes a new tile row once the FIFO is down to this.
+const PUSH_THRESHOLD: usize = 8;
+
+#[derive(Clone, Default)]
+pub(super) struct Fifo {
+    /// Background pixels waiting to be shifted out, oldest first.
+    pixels: [u8; 16],
+    len: usize,
+    /// Dots into the current tile fetch.
+    fetch_dot: u8,
+    /// Tiles fetched so far on this line, or since the window started.
+    fetch_x: u8,
+    tile: u8,
+    low: u8,
+    high: u8,
+    /// The first fetch of the line is done twice and only the second one
+    /// kept.
+    first_fetch: bool,
+    /// Pixels still to be dropped for fine scrolling.
+    discard: u8,
+    /// Pixels output so far.
+    lcd_x: u8,
+    window: bool,
+    /// Object pixels for the next eight screen pixels.
+    objs: [ObjPixel; 8],
+    /// The first selected object not yet fetched.
+    next_sprite: usize,
+    /// Dots left in the current object fetch.
+    stall: u16,
+    /// Columns that have paid the object fetch alignment penalty.
+    columns: u64,
+}
+
+impl Fifo {
+    fn push_row(&mut self, low: u8, high: u8, attrs: u8) {
+        for bit in (0..8).rev() {
+            let color = (high >> bit & 1) << 1 | (low >> bit & 1);
+            self.pixels[self.len] = bg_pixel(color, attrs);
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> u8 {
+        let pixel = self.pixels[0];
+        self.pixels.copy_within(1..self.len, 0);
+        self.len -= 1;
+        pixel
+    }
+
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.pixels);
+        w.u8(self.len as u8);
+        for value in [
+            self.fetch_dot,
+            self.fetch_x,
+            self.tile,
+            self.low,
+            self.high,
+            self.discard,
+            self.lcd_x,
+        ] {
+            w.u8(value);
+        }
+        w.bool(self.first_fetch);
+        w.bool(self.window);
+        for obj in &self.objs {
+            w.u8(obj.color);
+            w.u8(obj.attrs);
+            w.u8(obj.index);
+        }
+        w.u8(self.next_sprite as u8);
+        w.u16(self.stall);
+        w.u64(self.columns);
+    }
+
+    /// `sprites` is the number of objects selected for the line.
+    pub(super) fn load_state(
+        &mut self,
+        r: &mut StateReader,
+        sprites: usize,
+    ) -> Result<(), StateError> {
+        r.bytes(&mut self.pixels)?;
+        self.len = r.u8()? as usize;
+        if self.len > self.pixels.len() {
+            return Err(StateError::Invalid("pixel FIFO length"));
+        }
+        self.fetch_dot = r.u8()?;
+        self.fetch_x = r.u8()?;
+        self.tile = r.u8()?;
+        self.low = r.u8()?;
+        self.high = r.u8()?;
+        self.discard = r.u8()?;
+        self.lcd_x = r.u8()?;
+        self.first_fetch = r.bool()?;
+        self.window = r.bool()?;
+        for obj in &mut self.objs {
+            obj.color = r.u8()? & 0x03;
+            obj.attrs = r.u8()?;
+            obj.index = r.u8()?;
+        }
+        self.next_sprite = r.u8()? as usize;
+        if self.next_sprite > sprites {
+            return Err(StateError::Invalid("object fetch position"));
+        }
+        self.stall = r.u16()?;
+        self.columns = r.u64()?;
+        Ok(())
+    }
+}
+
+impl Ppu {
+    pub(super) fn start_fifo(&mut self) {
+        let window = self.window_wraps();
+        self.fifo = Fifo {
+            first_fetch: true,
+            discard: if window { 0 } else { self.scx % 8 },
+            window,
+            ..Fifo::default()
+        };
+        if window {
+            self.start_window();
+        }
+    }
+
+    /// Runs the FIFO renderer for one dot. Returns true once the line is
+    /// complete.
+    pub(super) fn fifo_dot(&mut self) -> bool {
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
+        if self.fetch_sprite() {
+            return false;
+        }
+        self.shift_pixel();
+        if usize::from(self.fifo.lcd_x) == SCREEN_WIDTH {
+            return true;
+        }
+        self.fetcher_dot();
+        false
+    }
+
+    fn shift_pixel(&mut self) {
+        if self.fifo.len == 0 {
+            return;
+        }
+        // Only at WX = 0 can the window start before the fine scroll is
+        // done, which then eats into the window instead.
+        let scrolled = self.fifo.discard == 0 || self.wx == 0;
+        if !self.fifo.window && scrolled && self.window_starts_at(self.fifo.lcd_x) {
+            self.fifo.window = true;
+            self.fifo.len = 0;
+            self.fifo.fetch_dot = 0;
+            self.fifo.fetch_x = 0;
+            self.fifo.discard += self.window_hidden();
+            self.start_window();
+            return;
+        }
+        let color = self.fifo.pop();
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+        let x = usize::from(self.fifo.lcd_x);
+        let obj = self.fifo.objs[0];
+        self.fifo.objs.copy_within(1.., 0);
+        self.fifo.objs[7] = ObjPixel::default();
+        self.put_pixel(x, color, obj);
+        self.fifo.lcd_x += 1;
+    }
+
+    /// Fetches the objects that start at the next pixel. Returns true if
+    /// that takes up this dot.
+    fn fetch_sprite(&mut self) -> bool {
+        if self.lcdc & 0x02 == 0 {
+            return false;
+        }
+        while let Some(&sprite) = self.sprites.get(self.fifo.next_sprite) {
+            if u16::from(sprite.x) > u16::from(self.fifo.lcd_x) + 8 {
+                break;
+            }
+            self.fifo.next_sprite += 1;
+            let left = isize::from(sprite.x) - 8 - isize::from(self.fifo.lcd_x);
+            for (i, &pixel) in self.sprite_pixels(&sprite).iter().enumerate() {
+                let slot = left + i as isize;
+                if (0..8).contains(&slot) {
+                    let mut obj = self.fifo.objs[slot as usize];
+                    self.merge_obj_pixel(&mut obj, pixel);
+                    self.fifo.objs[slot as usize] = obj;
+                }
+            }
+            let mut columns = self.fifo.columns;
+            let penalty = self.sprite_penalty(&sprite, &mut columns);
+            self.fifo.columns = columns;
+            if penalty > 0 {
+                self.fifo.stall = penalty - 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn fetcher_dot(&mut self) {
+        // Past FETCH_DOTS the fetcher is waiting for room in the FIFO.
+        if self.fifo.fetch_dot <= FETCH_DOTS {
+            self.fifo.fetch_dot += 1;
+        }
+        match self.fifo.fetch_dot {
+            2 => self.fifo.tile = self.fetch_tile_number(),
+            4 => self.fifo.low = self.fetch_tile_data(0),
+            FETCH_DOTS => self.fifo.high = self.fetch_tile_data(1),
+            _ => {}
+        }
+        if self.fifo.fetch_dot < FETCH_DOTS {
+            return;
+        }
+        if self.fifo.first_fetch {
+            self.fifo.first_fetch = false;
+            self.fifo.fetch_dot = 0;
+        } else if self.fifo.len <= PUSH_THRESHOLD {
+            let (low, high) = (self.fifo.low, self.fifo.high);
+            let (_, attrs) = self.fetch_map_entry();
+            self.fifo.push_row(low, high, attrs);
+            self.fifo.fetch_dot = 0;
+            self.fifo.fetch_x = self.fifo.fetch_x.wrapping_add(1);
+        }
+    }
+
+    /// The row of the background or window the fetcher is working on, and
+    /// the tile map it reads from.
+    fn fetch_position(&self) -> (usize, u8, u8) {
+        if self.fifo.window {
+            let map = self.tile_map(0x40);
+            (map, self.fifo.fetch_x, self.window_line())
+        } else {
+            let map = self.tile_map(0x08);
+            let x = (self.scx / 8).wrapping_add(self.fifo.fetch_x) & 0x1F;
+            (map, x, self.line.wrapping_add(self.scy))
+        }
+    }
+
+    /// The tile number and attributes the fetcher is working on. The CPU
+    /// cannot reach VRAM in mode 3, so the attributes can be read again
+    /// whenever they are needed.
+    fn fetch_map_entry(&self) -> (u8, u8) {
+        let (map, tile_x, y) = self.fetch_position();
+        self.map_entry(map, tile_x, y)
+    }
+
+    fn fetch_tile_number(&self) -> u8 {
+        self.fetch_map_entry().0
+    }
+
+    fn fetch_tile_data(&self, plane: usize) -> u8 {
+        let (_, _, y) = self.fetch_position();
+        let (_, attrs) = self.fetch_map_entry();
+        self.bg_tile_data(self.fifo.tile, attrs, y % 8, plane)
+    }
+}
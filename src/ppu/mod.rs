@@ -0,0 +1,845 @@
+//! The picture processing unit.
+//!
+//! The PPU runs one dot per clock, four per machine cycle. Each line takes
+//! 456 dots. Lines 0-143 go through OAM scan (mode 2, 80 dots), pixel
+//! transfer (mode 3, 172 dots or more) and HBlank (mode 0, the rest of the
+//! line). Lines 144-153 are VBlank (mode 1). During mode 3 the CPU cannot
+//! reach VRAM, and during modes 2 and 3 it cannot reach OAM either.
+//!
+//! The STAT interrupt fires on the rising edge of the OR of every enabled
+//! source. While one source holds the line high, another becoming true does
+//! not fire again. This is the "STAT blocking" games have to work around.
+//!
+//! DMG models output a 160×144 framebuffer of shade indices, 0 (lightest)
+//! to 3 (darkest), after the DMG palettes have been applied. CGB models
+//! output RGB555 colors from the color palettes instead, and add a second
+//! VRAM bank holding tile data and an attribute byte for every tile map
+//! entry: its palette, data bank, flips and priority over objects. A CGB
+//! running a DMG game leaves those features off and colors the DMG shades
+//! with the [`CompatPalettes`] its boot ROM picked.
+//!
+//! Mode 3 is drawn by one of two [`Renderer`]s: the pixel FIFO, which
+//! follows the hardware dot by dot and handles raster effects, or a faster
+//! scanline renderer that draws each line in one go.
+
+mod compat;
+mod fifo;
+mod palette;
+mod scanline;
+mod sprites;
+mod window;
+
+pub use self::compat::{CompatPalettes, ManualPalette};
+
+use self::fifo::Fifo;
+use self::palette::ColorPalettes;
+use self::sprites::Sprite;
+use self::window::Window;
+
+use crate::interrupt::Interrupt;
+use crate::model::Model;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const VBLANK_START: u8 = 144;
+const LAST_LINE: u8 = 153;
+
+/// How mode 3 turns tiles into pixels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Renderer {
+    /// Draws each line in one go from the registers as mode 3 starts.
+    /// Fast, but blind to changes made during mode 3.
+    Scanline,
+    /// Fetches tiles and shifts pixels out dot by dot, like the hardware.
+    #[default]
+    Fifo,
+}
+
+/// The mode shown in the low two bits of STAT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Transfer = 3,
+}
+
+pub struct Ppu {
+    model: Model,
+    /// Two banks on CGB models. DMG models only use the first.
+    vram: [u8; 0x4000],
+    /// VBK, the VRAM bank the CPU sees.
+    vbk: u8,
+    oam: [u8; 0xA0],
+    lcdc: u8,
+    /// The STAT interrupt enables, bits 3-6. The rest is computed on read.
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    /// Object priority mode, CGB only.
+    opri: u8,
+    bg_palettes: ColorPalettes,
+    obj_palettes: ColorPalettes,
+    /// A CGB model running a DMG game.
+    dmg_compat: bool,
+    /// Objects selected for the current line.
+    sprites: Vec<Sprite>,
+    unlimited_sprites: bool,
+    window: Window,
+    /// The line being drawn. LY differs from it during most of line 153,
+    /// where it already reads 0.
+    line: u8,
+    /// Dots into the current line.
+    dot: u16,
+    mode: Mode,
+    renderer: Renderer,
+    /// The renderer drawing the current line. Switching renderers takes
+    /// effect from the next line.
+    line_renderer: Renderer,
+    /// The dot at which mode 3 ends on this line, for the scanline
+    /// renderer.
+    transfer_end: u16,
+    fifo: Fifo,
+    /// The LY=LYC flag, which stops updating while the LCD is off.
+    coincidence: bool,
+    /// The OR of the enabled STAT sources as of the last dot.
+    stat_line: bool,
+    framebuffer: Vec<u8>,
+    color_framebuffer: Vec<u16>,
+    /// Interrupts raised since the bus last collected them.
+    interrupts: u8,
+    frame_ready: bool,
+    /// HBlank has started on a visible line since the bus last checked.
+    hblank_started: bool,
+}
+
+impl Ppu {
+    pub fn new(model: Model) -> Ppu {
+        Ppu {
+            model,
+            vram: [0; 0x4000],
+            vbk: 0,
+            oam: [0; 0xA0],
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            opri: 0,
+            bg_palettes: ColorPalettes::new(),
+            obj_palettes: ColorPalettes::new(),
+            dmg_compat: false,
+            sprites: Vec::new(),
+            unlimited_sprites: false,
+            window: Window::default(),
+            line: 0,
+            dot: 0,
+            mode: Mode::HBlank,
+            renderer: Renderer::default(),
+            line_renderer: Renderer::default(),
+            transfer_end: 0,
+            fifo: Fifo::default(),
+            coincidence: false,
+            stat_line: false,
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            color_framebuffer: vec![WHITE; SCREEN_WIDTH * SCREEN_HEIGHT],
+            interrupts: 0,
+            frame_ready: false,
+            hblank_started: false,
+        }
+    }
+
+    /// Shade indices, row by row from the top left. Only DMG models draw
+    /// here.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// RGB555 colors, row by row from the top left. Only CGB models draw
+    /// here, DMG games included.
+    pub fn color_framebuffer(&self) -> &[u16] {
+        &self.color_framebuffer
+    }
+
+    /// Runs a CGB model the way it runs DMG games, with the CGB features
+    /// off. Does nothing on other models.
+    pub fn enter_dmg_compat(&mut self) {
+        self.dmg_compat = self.model.is_cgb();
+    }
+
+    /// Shows the DMG shades in `palettes` in DMG compatibility mode, as if
+    /// the boot ROM had picked them.
+    pub fn set_compat_palettes(&mut self, palettes: &CompatPalettes) {
+        self.bg_palettes.set_palette(0, &palettes.bg);
+        self.obj_palettes.set_palette(0, &palettes.obj0);
+        self.obj_palettes.set_palette(1, &palettes.obj1);
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    /// Lifts the limit of ten objects per line, which hardware enforces
+    /// and games rely on for flicker. Mode 3 timing stays as it would be
+    /// with the limit.
+    pub fn set_unlimited_sprites(&mut self, unlimited: bool) {
+        self.unlimited_sprites = unlimited;
+    }
+
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    pub fn lcd_on(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    /// Returns true once per frame, after VBlank has started.
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    /// Returns true once HBlank has started on a visible line since the
+    /// last call.
+    pub fn take_hblank_started(&mut self) -> bool {
+        std::mem::take(&mut self.hblank_started)
+    }
+
+    /// Whether the CPU would find HBlank or the LCD off right now.
+    pub fn in_hblank(&self) -> bool {
+        !self.lcd_on() || self.mode == Mode::HBlank
+    }
+
+    /// The interrupt flags raised since the last call.
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+
+    /// Advances `dots` dots: four per machine cycle, or two while the CPU
+    /// runs at double speed.
+    pub fn tick(&mut self, dots: u8) {
+        if !self.lcd_on() {
+            return;
+        }
+        for _ in 0..dots {
+            self.tick_dot();
+        }
+    }
+
+    fn tick_dot(&mut self) {
+        self.dot += 1;
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.line = if self.line == LAST_LINE {
+                0
+            } else {
+                self.line + 1
+            };
+            self.ly = self.line;
+            self.start_line();
+        } else if self.line < VBLANK_START {
+            if self.dot == OAM_SCAN_DOTS {
+                self.start_transfer();
+            } else if self.mode == Mode::Transfer && self.transfer_dot() {
+                self.mode = Mode::HBlank;
+                self.hblank_started = true;
+            }
+        } else if self.line == LAST_LINE && self.dot == 4 {
+            self.ly = 0;
+        }
+        self.update_stat_line();
+    }
+
+    fn start_line(&mut self) {
+        if self.line < VBLANK_START {
+            self.mode = Mode::OamScan;
+            self.window_next_line();
+        } else if self.line == VBLANK_START {
+            self.mode = Mode::VBlank;
+            self.window = Window::default();
+            self.interrupts |= Interrupt::VBlank.mask();
+            self.frame_ready = true;
+        }
+    }
+
+    fn start_transfer(&mut self) {
+        self.mode = Mode::Transfer;
+        self.line_renderer = self.renderer;
+        self.check_window_y();
+        self.scan_oam();
+        match self.line_renderer {
+            Renderer::Scanline => {
+                self.transfer_end = OAM_SCAN_DOTS + self.transfer_length();
+                self.render_scanline();
+            }
+            Renderer::Fifo => self.start_fifo(),
+        }
+    }
+
+    /// Runs one dot of mode 3. Returns true when it has finished.
+    fn transfer_dot(&mut self) -> bool {
+        match self.line_renderer {
+            Renderer::Scanline => self.dot == self.transfer_end,
+            Renderer::Fifo => self.fifo_dot(),
+        }
+    }
+
+    /// Whether the CGB features are in use: on a CGB model, unless it is
+    /// running a DMG game.
+    pub fn cgb_mode(&self) -> bool {
+        self.model.is_cgb() && !self.dmg_compat
+    }
+
+    /// The tile number and attributes at `tile_x`, `y` of `map`. Without
+    /// the CGB features the attributes are all clear.
+    fn map_entry(&self, map: usize, tile_x: u8, y: u8) -> (u8, u8) {
+        let addr = map + usize::from(y / 8) * 32 + usize::from(tile_x & 0x1F);
+        let attrs = if self.cgb_mode() {
+            self.vram[0x2000 + addr]
+        } else {
+            0
+        };
+        (self.vram[addr], attrs)
+    }
+
+    /// Bitplane `plane` of `row` of background or window tile `tile`, with
+    /// the bank and flips `attrs` ask for.
+    fn bg_tile_data(&self, tile: u8, attrs: u8, row: u8, plane: usize) -> u8 {
+        let row = if attrs & 0x40 != 0 { 7 - row } else { row };
+        let bank = if attrs & 0x08 != 0 { 0x2000 } else { 0 };
+        let data = self.vram[bank + self.bg_tile_addr(tile, row) + plane];
+        if attrs & 0x20 != 0 {
+            data.reverse_bits()
+        } else {
+            data
+        }
+    }
+
+    /// The tile map LCDC bit `bit` selects, as an offset into VRAM.
+    fn tile_map(&self, bit: u8) -> usize {
+        if self.lcdc & bit != 0 {
+            0x1C00
+        } else {
+            0x1800
+        }
+    }
+
+    /// Where `row` of background or window tile `tile` starts in VRAM,
+    /// addressed the way LCDC bit 4 selects.
+    fn bg_tile_addr(&self, tile: u8, row: u8) -> usize {
+        let base = if self.lcdc & 0x10 != 0 {
+            usize::from(tile) * 16
+        } else {
+            (0x1000 + i32::from(tile as i8) * 16) as usize
+        };
+        base + usize::from(row) * 2
+    }
+
+    /// Whether any of the STAT sources in `enables` is active.
+    fn stat_sources(&self, enables: u8) -> bool {
+        // The mode 2 source also fires as VBlank starts.
+        let oam_scan = self.mode == Mode::OamScan || (self.line == VBLANK_START && self.dot == 0);
+        (enables & 0x40 != 0 && self.coincidence)
+            || (enables & 0x20 != 0 && oam_scan)
+            || (enables & 0x10 != 0 && self.mode == Mode::VBlank)
+            || (enables & 0x08 != 0 && self.mode == Mode::HBlank)
+    }
+
+    fn update_stat_line(&mut self) {
+        if !self.lcd_on() {
+            return;
+        }
+        self.coincidence = self.ly == self.lyc;
+        let line = self.stat_sources(self.stat);
+        if line && !self.stat_line {
+            self.interrupts |= Interrupt::Stat.mask();
+        }
+        self.stat_line = line;
+    }
+
+    fn set_lcdc(&mut self, value: u8) {
+        let was_on = self.lcd_on();
+        self.lcdc = value;
+        if was_on == self.lcd_on() {
+            return;
+        }
+        self.line = 0;
+        self.ly = 0;
+        self.dot = 0;
+        // The first line after the LCD comes on skips OAM scan and reports
+        // mode 0 until pixel transfer starts.
+        self.mode = Mode::HBlank;
+        self.transfer_end = 0;
+        self.window = Window::default();
+        self.stat_line = false;
+        if was_on {
+            self.framebuffer.fill(0);
+            self.color_framebuffer.fill(WHITE);
+        } else {
+            self.update_stat_line();
+        }
+    }
+
+    fn set_stat(&mut self, value: u8) {
+        // On DMG models a write briefly enables every source, which fires
+        // the interrupt outside mode 3 or while LY=LYC.
+        if !self.model.is_cgb() && self.lcd_on() && !self.stat_line && self.stat_sources(0x78) {
+            self.interrupts |= Interrupt::Stat.mask();
+            self.stat_line = true;
+        }
+        self.stat = value & 0x78;
+        self.update_stat_line();
+    }
+
+    /// Reads an LCD register in 0xFF40-0xFF4B, except DMA at 0xFF46, or
+    /// one of the CGB registers VBK at 0xFF4F, BCPS/BCPD/OCPS/OCPD at
+    /// 0xFF68-0xFF6B and OPRI at 0xFF6C.
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                let mode = if self.lcd_on() { self.mode as u8 } else { 0 };
+                0x80 | self.stat | (self.coincidence as u8) << 2 | mode
+            }
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F if self.cgb_mode() => 0xFE | self.vbk,
+            0xFF68 if self.cgb_mode() => self.bg_palettes.read_spec(),
+            0xFF69 if self.cgb_mode() => self.bg_palettes.read_data(self.palettes_locked()),
+            0xFF6A if self.cgb_mode() => self.obj_palettes.read_spec(),
+            0xFF6B if self.cgb_mode() => self.obj_palettes.read_data(self.palettes_locked()),
+            0xFF6C if self.cgb_mode() => 0xFE | self.opri,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF40 => self.set_lcdc(value),
+            0xFF41 => self.set_stat(value),
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            // LY is read-only.
+            0xFF45 => {
+                self.lyc = value;
+                self.update_stat_line();
+            }
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp0 = value,
+            0xFF49 => self.obp1 = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            0xFF4F if self.cgb_mode() => self.vbk = value & 0x01,
+            0xFF68 if self.cgb_mode() => self.bg_palettes.write_spec(value),
+            0xFF69 if self.cgb_mode() => {
+                let locked = self.palettes_locked();
+                self.bg_palettes.write_data(value, locked);
+            }
+            0xFF6A if self.cgb_mode() => self.obj_palettes.write_spec(value),
+            0xFF6B if self.cgb_mode() => {
+                let locked = self.palettes_locked();
+                self.obj_palettes.write_data(value, locked);
+            }
+            0xFF6C if self.cgb_mode() => self.opri = value & 0x01,
+            _ => {}
+        }
+    }
+
+    fn palettes_locked(&self) -> bool {
+        self.mode == Mode::Transfer
+    }
+
+    /// Where the CPU's view of `addr` in 0x8000-0x9FFF is in VRAM, in the
+    /// bank VBK selects.
+    fn vram_index(&self, addr: u16) -> usize {
+        usize::from(self.vbk) * 0x2000 + (addr & 0x1FFF) as usize
+    }
+
+    /// CPU read of 0x8000-0x9FFF. Reads 0xFF during mode 3.
+    pub fn read_vram(&self, addr: u16) -> u8 {
+        if self.mode == Mode::Transfer {
+            return 0xFF;
+        }
+        self.vram[self.vram_index(addr)]
+    }
+
+    /// CPU write to 0x8000-0x9FFF. Ignored during mode 3.
+    pub fn write_vram(&mut self, addr: u16, value: u8) {
+        if self.mode != Mode::Transfer {
+            self.vram[self.vram_index(addr)] = value;
+        }
+    }
+
+    fn oam_accessible(&self) -> bool {
+        !matches!(self.mode, Mode::OamScan | Mode::Transfer)
+    }
+
+    /// CPU read of 0xFE00-0xFE9F. Reads 0xFF during modes 2 and 3.
+    pub fn read_oam(&self, addr: u16) -> u8 {
+        if !self.oam_accessible() {
+            return 0xFF;
+        }
+        self.oam[(addr - 0xFE00) as usize]
+    }
+
+    /// CPU write to 0xFE00-0xFE9F. Ignored during modes 2 and 3.
+    pub fn write_oam(&mut self, addr: u16, value: u8) {
+        if self.oam_accessible() {
+            self.oam[(addr - 0xFE00) as usize] = value;
+        }
+    }
+
+    /// OAM DMA write, which is not blocked by the mode.
+    pub fn dma_write_oam(&mut self, index: u8, value: u8) {
+        self.oam[index as usize] = value;
+    }
+
+    /// The renderer setting belongs to the host and is not saved, but the
+    /// renderer drawing the current line is.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.vram[..0x2000]);
+        w.bytes(&self.oam);
+        for value in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx, self.opri, self.line,
+        ] {
+            w.u8(value);
+        }
+        w.u16(self.dot);
+        w.u8(self.mode as u8);
+        w.bool(self.line_renderer == Renderer::Fifo);
+        w.u16(self.transfer_end);
+        self.save_sprites(w);
+        self.window.save_state(w);
+        self.fifo.save_state(w);
+        w.bool(self.coincidence);
+        w.bool(self.stat_line);
+        w.bytes(&self.framebuffer);
+        w.bytes(&self.vram[0x2000..]);
+        w.u8(self.vbk);
+        self.bg_palettes.save_state(w);
+        self.obj_palettes.save_state(w);
+        for &color in &self.color_framebuffer {
+            w.u16(color);
+        }
+        w.bool(self.dmg_compat);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.vram[..0x2000])?;
+        r.bytes(&mut self.oam)?;
+        self.lcdc = r.u8()?;
+        self.stat = r.u8()? & 0x78;
+        self.scy = r.u8()?;
+        self.scx = r.u8()?;
+        self.ly = r.u8()?;
+        self.lyc = r.u8()?;
+        self.bgp = r.u8()?;
+        self.obp0 = r.u8()?;
+        self.obp1 = r.u8()?;
+        self.wy = r.u8()?;
+        self.wx = r.u8()?;
+        self.opri = r.u8()? & 0x01;
+        self.line = r.u8()?;
+        self.dot = r.u16()?;
+        if self.line > LAST_LINE || self.dot >= DOTS_PER_LINE {
+            return Err(StateError::Invalid("PPU position"));
+        }
+        self.mode = match r.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            3 => Mode::Transfer,
+            _ => return Err(StateError::Invalid("PPU mode")),
+        };
+        self.line_renderer = if r.bool()? {
+            Renderer::Fifo
+        } else {
+            Renderer::Scanline
+        };
+        self.transfer_end = r.u16()?;
+        self.load_sprites(r)?;
+        self.window.load_state(r)?;
+        self.fifo.load_state(r, self.sprites.len())?;
+        self.coincidence = r.bool()?;
+        self.stat_line = r.bool()?;
+        r.bytes(&mut self.framebuffer)?;
+        r.bytes(&mut self.vram[0x2000..])?;
+        self.vbk = r.u8()? & 0x01;
+        self.bg_palettes.load_state(r)?;
+        self.obj_palettes.load_state(r)?;
+        for color in &mut self.color_framebuffer {
+            *color = r.u16()? & 0x7FFF;
+        }
+        self.dmg_compat = r.bool()? && self.model.is_cgb();
+        Ok(())
+    }
+}
+
+/// A background or window pixel as the renderers pass it around: the color
+/// index in bits 0-1, with the CGB palette in bits 2-4 and the priority
+/// attribute in bit 7.
+fn bg_pixel(color: u8, attrs: u8) -> u8 {
+    color | (attrs & 0x07) << 2 | attrs & 0x80
+}
+
+/// What the CGB shows with the LCD off.
+const WHITE: u16 = 0x7FFF;
+
+/// The shade `palette` gives color index `color`.
+fn shade(palette: u8, color: u8) -> u8 {
+    palette >> (color * 2) & 0x03
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn running_ppu() -> Ppu {
+        let mut ppu = Ppu::new(Model::Dmg);
+        ppu.write(0xFF40, 0x91);
+        ppu
+    }
+
+    /// Ticks until `done` holds, returning the number of dots it took.
+    fn dots_until(ppu: &mut Ppu, done: impl Fn(&Ppu) -> bool) -> u32 {
+        let mut dots = 0;
+        while !done(ppu) {
+            ppu.tick_dot();
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn line_and_frame_timing() {
+        let mut ppu = running_ppu();
+        // Skip the first line, which has no OAM scan.
+        dots_until(&mut ppu, |p| p.line == 1);
+        assert_eq!(ppu.mode, Mode::OamScan);
+        assert_eq!(dots_until(&mut ppu, |p| p.mode == Mode::Transfer), 80);
+        assert_eq!(dots_until(&mut ppu, |p| p.mode == Mode::HBlank), 172);
+        assert_eq!(dots_until(&mut ppu, |p| p.mode == Mode::OamScan), 204);
+
+        ppu.take_interrupts();
+        assert_eq!(dots_until(&mut ppu, |p| p.mode == Mode::VBlank), 142 * 456);
+        assert_eq!(ppu.ly, 144);
+        assert_eq!(ppu.take_interrupts(), Interrupt::VBlank.mask());
+        assert!(ppu.take_frame_ready());
+
+        // LY reads 0 for all but the first few dots of line 153.
+        dots_until(&mut ppu, |p| p.line == 153);
+        assert_eq!(ppu.ly, 153);
+        assert_eq!(dots_until(&mut ppu, |p| p.ly == 0), 4);
+        assert_eq!(dots_until(&mut ppu, |p| p.line == 0), 452);
+        assert_eq!(ppu.mode, Mode::OamScan);
+    }
+
+    #[test]
+    fn stat_interrupt_is_blocked_while_the_line_is_high() {
+        let mut ppu = running_ppu();
+        // HBlank and LY=LYC both enabled, with LYC matching line 5.
+        ppu.write(0xFF41, 0x48);
+        ppu.write(0xFF45, 5);
+        dots_until(&mut ppu, |p| p.line == 4 && p.mode == Mode::Transfer);
+        ppu.take_interrupts();
+
+        // HBlank on line 4 raises the line...
+        dots_until(&mut ppu, |p| p.mode == Mode::HBlank);
+        assert_eq!(ppu.take_interrupts(), Interrupt::Stat.mask());
+        // ...and LY=LYC on line 5 keeps it high, so it does not fire again.
+        dots_until(&mut ppu, |p| p.line == 5);
+        assert_eq!(ppu.read(0xFF41) & 0x04, 0x04);
+        assert_eq!(ppu.take_interrupts(), 0);
+    }
+
+    #[test]
+    fn vram_and_oam_are_blocked_by_mode() {
+        let mut ppu = running_ppu();
+        dots_until(&mut ppu, |p| p.mode == Mode::OamScan);
+        ppu.write_oam(0xFE00, 0x12);
+        ppu.write_vram(0x8000, 0x34);
+        assert_eq!(ppu.read_oam(0xFE00), 0xFF);
+        assert_eq!(ppu.read_vram(0x8000), 0x34);
+
+        dots_until(&mut ppu, |p| p.mode == Mode::Transfer);
+        ppu.write_vram(0x8000, 0x56);
+        assert_eq!(ppu.read_vram(0x8000), 0xFF);
+
+        dots_until(&mut ppu, |p| p.mode == Mode::HBlank);
+        assert_eq!(ppu.read_vram(0x8000), 0x34);
+        assert_eq!(ppu.read_oam(0xFE00), 0x00);
+    }
+
+    /// A busy background and window, scrolled by a few pixels, on a PPU
+    /// that has just been switched on.
+    fn patterned_ppu(renderer: Renderer) -> Ppu {
+        let mut ppu = Ppu::new(Model::Dmg);
+        ppu.set_renderer(renderer);
+        for addr in 0x8000..0xA000u16 {
+            let i = addr as u8;
+            ppu.write_vram(addr, i.wrapping_mul(7) ^ (addr >> 5) as u8);
+        }
+        for (addr, value) in [
+            (0xFF42, 5),
+            (0xFF43, 3),
+            (0xFF47, 0xE4),
+            (0xFF4A, 40),
+            (0xFF4B, 87),
+        ] {
+            ppu.write(addr, value);
+        }
+        ppu.write(0xFF40, 0xF1);
+        ppu
+    }
+
+    #[test]
+    fn renderers_agree_on_a_still_picture() {
+        let mut scanline = patterned_ppu(Renderer::Scanline);
+        let mut fifo = patterned_ppu(Renderer::Fifo);
+        for ppu in [&mut scanline, &mut fifo] {
+            dots_until(ppu, |p| p.frame_ready);
+        }
+        assert!(fifo.framebuffer().iter().any(|&shade| shade != 0));
+        assert!(scanline.framebuffer() == fifo.framebuffer());
+    }
+
+    #[test]
+    fn mode_3_grows_with_fine_scroll_and_the_window() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = patterned_ppu(renderer);
+            dots_until(&mut ppu, |p| p.line == 39 && p.mode == Mode::Transfer);
+            assert_eq!(dots_until(&mut ppu, |p| p.mode == Mode::HBlank), 172 + 3);
+            dots_until(&mut ppu, |p| p.line == 40 && p.mode == Mode::Transfer);
+            assert_eq!(
+                dots_until(&mut ppu, |p| p.mode == Mode::HBlank),
+                172 + 3 + 6
+            );
+        }
+    }
+
+    #[test]
+    fn fifo_picks_up_palette_changes_mid_line() {
+        let mut ppu = patterned_ppu(Renderer::Fifo);
+        ppu.write(0xFF40, 0x00);
+        ppu.write(0xFF43, 0);
+        ppu.write(0xFF40, 0x91);
+        // Line 0 draws its first pixel 13 dots into mode 3; stop with 80
+        // pixels drawn.
+        dots_until(&mut ppu, |p| p.mode == Mode::Transfer);
+        for _ in 0..12 + 80 {
+            ppu.tick_dot();
+        }
+        ppu.write(0xFF47, 0x00);
+        dots_until(&mut ppu, |p| p.mode == Mode::HBlank);
+
+        let line = &ppu.framebuffer()[..SCREEN_WIDTH];
+        assert!(line[..80].iter().any(|&shade| shade != 0));
+        assert!(line[80..].iter().all(|&shade| shade == 0));
+    }
+
+    #[test]
+    fn color_palettes_auto_increment_and_lock_in_mode_3() {
+        let mut ppu = Ppu::new(Model::Cgb);
+        ppu.write(0xFF68, 0x80);
+        for value in [0x11, 0x22, 0x33] {
+            ppu.write(0xFF69, value);
+        }
+        assert_eq!(ppu.read(0xFF68), 0xC3);
+        ppu.write(0xFF68, 0x01);
+        // Reads do not move the index.
+        assert_eq!(ppu.read(0xFF69), 0x22);
+        assert_eq!(ppu.read(0xFF69), 0x22);
+        assert_eq!(ppu.bg_palettes.color(0, 0), 0x2211);
+
+        ppu.write(0xFF40, 0x91);
+        dots_until(&mut ppu, |p| p.mode == Mode::Transfer);
+        ppu.write(0xFF6A, 0xBF);
+        ppu.write(0xFF6B, 0x44);
+        assert_eq!(ppu.read(0xFF6B), 0xFF);
+        // The index wraps, even though the write was lost.
+        assert_eq!(ppu.read(0xFF6A), 0xC0);
+        dots_until(&mut ppu, |p| p.mode == Mode::HBlank);
+        ppu.write(0xFF6A, 0x3F);
+        assert_eq!(ppu.read(0xFF6B), 0x00);
+    }
+
+    #[test]
+    fn cgb_tile_attributes() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = Ppu::new(Model::Cgb);
+            ppu.set_renderer(renderer);
+            // Bank 1 holds tile 0 with its left half in color 1 and tile 1
+            // with only its last row in color 2. Bank 0 is blank.
+            ppu.write(0xFF4F, 1);
+            for row in 0..8 {
+                ppu.write_vram(0x8000 + row * 2, 0xF0);
+            }
+            ppu.write_vram(0x801F, 0xFF);
+            // Bank 1 of the map: palette 2, bank 1, X flip; palette 3, bank
+            // 1, Y flip; then bank 1 without flips.
+            for (i, attrs) in [0x2A, 0x4B, 0x08].iter().enumerate() {
+                ppu.write_vram(0x9800 + i as u16, *attrs);
+            }
+            ppu.write(0xFF4F, 0);
+            for (i, tile) in [0, 1, 1].iter().enumerate() {
+                ppu.write_vram(0x9800 + i as u16, *tile);
+            }
+            ppu.write(0xFF68, 0x80);
+            for _ in 0..32 {
+                ppu.write(0xFF69, 0x00);
+            }
+            for (palette, color, value) in [(2, 1, 0x1234), (3, 2, 0x0ACE)] {
+                ppu.write(0xFF68, palette * 8 + color * 2);
+                let [low, high] = u16::to_le_bytes(value);
+                ppu.write(0xFF69, low);
+                ppu.write(0xFF68, palette * 8 + color * 2 + 1);
+                ppu.write(0xFF69, high);
+            }
+            ppu.write(0xFF40, 0x91);
+            dots_until(&mut ppu, |p| p.frame_ready);
+
+            let mut expected = [0; 24];
+            expected[4..8].fill(0x1234);
+            expected[8..16].fill(0x0ACE);
+            assert_eq!(
+                &ppu.color_framebuffer()[..24],
+                &expected[..],
+                "{:?}",
+                renderer
+            );
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! CGB color palette RAM, reached through BCPS/BCPD for the background and
+//! OCPS/OCPD for objects.
+//!
+//! Each RAM holds eight palettes of four colors, two bytes per color in
+//! little-endian RGB555: red in bits 0-4, green in 5-9, blue in 10-14. The
+//! spec register picks a byte with bits 0-5, and bit 7 moves it on after
+//! every data write. The PPU holds the RAM during mode 3, when reads give
+//! 0xFF and writes are lost but still move the index on.
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+#[derive(Clone)]
+pub(super) struct ColorPalettes {
+    ram: [u8; 64],
+    /// BCPS or OCPS, without the unused bit 6.
+    spec: u8,
+}
+
+impl ColorPalettes {
+    pub(super) fn new() -> ColorPalettes {
+        ColorPalettes {
+            ram: [0; 64],
+            spec: 0,
+        }
+    }
+
+    pub(super) fn read_spec(&self) -> u8 {
+        0x40 | self.spec
+    }
+
+    pub(super) fn write_spec(&mut self, value: u8) {
+        self.spec = value & 0xBF;
+    }
+
+    pub(super) fn read_data(&self, locked: bool) -> u8 {
+        if locked {
+            return 0xFF;
+        }
+        self.ram[usize::from(self.spec & 0x3F)]
+    }
+
+    pub(super) fn write_data(&mut self, value: u8, locked: bool) {
+        if !locked {
+            self.ram[usize::from(self.spec & 0x3F)] = value;
+        }
+        if self.spec & 0x80 != 0 {
+            self.spec = 0x80 | (self.spec + 1) & 0x3F;
+        }
+    }
+
+    /// The RGB555 value of `color` in `palette`.
+    pub(super) fn color(&self, palette: u8, color: u8) -> u16 {
+        let i = usize::from(palette & 0x07) * 8 + usize::from(color) * 2;
+        u16::from_le_bytes([self.ram[i], self.ram[i + 1]]) & 0x7FFF
+    }
+
+    /// Fills `palette` with `colors`, as the boot ROM does.
+    pub(super) fn set_palette(&mut self, palette: u8, colors: &[u16; 4]) {
+        for (color, &value) in colors.iter().enumerate() {
+            let i = usize::from(palette & 0x07) * 8 + color * 2;
+            self.ram[i..i + 2].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.spec);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.ram)?;
+        self.spec = r.u8()? & 0xBF;
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//! The scanline renderer: draws a whole line at once as mode 3 starts,
+//! from the registers as they are at that moment.
+
+use super::sprites::ObjPixel;
+use super::{bg_pixel, Ppu, SCREEN_WIDTH};
+
+/// Mode 3 takes at least this many dots.
+const MIN_TRANSFER_DOTS: u16 = 172;
+/// Dots lost to refetching when the window starts.
+const WINDOW_START_DOTS: u16 = 6;
+
+impl Ppu {
+    /// How long mode 3 lasts on the current line, as the FIFO renderer
+    /// would take with the registers left alone: the first SCX % 8 pixels
+    /// are thrown away at a dot each, starting the window restarts the
+    /// fetcher and drops any pixels it cuts off, and each object stalls
+    /// it. A window carried over from the line before skips all that.
+    pub(super) fn transfer_length(&self) -> u16 {
+        let scroll = if self.window_wraps() {
+            0
+        } else {
+            let window = (0..SCREEN_WIDTH as u8).any(|x| self.window_starts_at(x));
+            u16::from(self.scx % 8)
+                + if window {
+                    WINDOW_START_DOTS + u16::from(self.window_hidden())
+                } else {
+                    0
+                }
+        };
+        let mut columns = 0;
+        let sprites: u16 = self
+            .sprites
+            .iter()
+            .map(|sprite| self.sprite_penalty(sprite, &mut columns))
+            .sum();
+        MIN_TRANSFER_DOTS + scroll + sprites
+    }
+
+    pub(super) fn render_scanline(&mut self) {
+        let y = self.line;
+        let mut bg = [0; SCREEN_WIDTH];
+        let (window_x, hidden) = if self.window_wraps() {
+            (Some(0), 0)
+        } else {
+            let start = (0..SCREEN_WIDTH as u8).find(|&x| self.window_starts_at(x));
+            let fine_scroll = if self.wx == 0 { self.scx % 8 } else { 0 };
+            (start, self.window_hidden() + fine_scroll)
+        };
+        if window_x.is_some() {
+            self.start_window();
+        }
+        for (x, color) in (0..SCREEN_WIDTH as u8).zip(bg.iter_mut()) {
+            let (map, map_x, map_y) = match window_x {
+                Some(start) if x >= start => {
+                    let map = self.tile_map(0x40);
+                    (map, x - start + hidden, self.window_line())
+                }
+                _ => {
+                    let map = self.tile_map(0x08);
+                    (map, x.wrapping_add(self.scx), y.wrapping_add(self.scy))
+                }
+            };
+            let (tile, attrs) = self.map_entry(map, map_x / 8, map_y);
+            let low = self.bg_tile_data(tile, attrs, map_y % 8, 0);
+            let high = self.bg_tile_data(tile, attrs, map_y % 8, 1);
+            let bit = 7 - map_x % 8;
+            *color = bg_pixel((high >> bit & 1) << 1 | (low >> bit & 1), attrs);
+        }
+
+        let mut objs = [ObjPixel::default(); SCREEN_WIDTH];
+        for sprite in &self.sprites {
+            let left = isize::from(sprite.x) - 8;
+            for (i, &pixel) in self.sprite_pixels(sprite).iter().enumerate() {
+                let x = left + i as isize;
+                if (0..SCREEN_WIDTH as isize).contains(&x) {
+                    self.merge_obj_pixel(&mut objs[x as usize], pixel);
+                }
+            }
+        }
+
+        for x in 0..SCREEN_WIDTH {
+            self.put_pixel(x, bg[x], objs[x]);
+        }
+    }
+}
@@ -0,0 +1,508 @@
+//! Objects: selecting them during OAM scan, fetching their tile rows and
+//! deciding which one shows where they overlap.
+//!
+//! OAM scan picks the first ten objects in OAM order whose rows cover the
+//! line. Where opaque pixels of two objects overlap, DMG models show the
+//! one with the smaller X, falling back to OAM order on a tie. CGB models
+//! go by OAM order alone, unless bit 0 of OPRI asks for the DMG rule.
+//!
+//! Each object fetch stalls mode 3: 6 dots, plus up to 5 more for the
+//! first object in each 8-pixel column while the background fetch there
+//! finishes.
+
+use super::{Ppu, SCREEN_WIDTH};
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Objects OAM scan selects per line on hardware.
+const LINE_LIMIT: usize = 10;
+/// Dots an object fetch always costs.
+const FETCH_PENALTY: u16 = 6;
+
+/// An object selected for the current line.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct Sprite {
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub attrs: u8,
+    /// Position in OAM, 0-39.
+    pub index: u8,
+    /// Past the hardware limit, shown only because the limit is lifted.
+    /// Such objects are drawn for free.
+    pub extra: bool,
+}
+
+/// An object pixel waiting to be mixed with the background.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct ObjPixel {
+    /// 0 when no object is opaque here.
+    pub color: u8,
+    pub attrs: u8,
+    pub index: u8,
+}
+
+impl Ppu {
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & 0x04 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Selects the objects on the current line, in the order the fetcher
+    /// meets them: by X, then by OAM index.
+    pub(super) fn scan_oam(&mut self) {
+        self.sprites.clear();
+        if self.lcdc & 0x02 == 0 {
+            return;
+        }
+        let height = self.sprite_height();
+        let row = self.line + 16;
+        for (index, entry) in self.oam.chunks_exact(4).enumerate() {
+            let y = entry[0];
+            if row < y || row >= y.saturating_add(height) {
+                continue;
+            }
+            let extra = self.sprites.len() >= LINE_LIMIT;
+            if extra && !self.unlimited_sprites {
+                break;
+            }
+            self.sprites.push(Sprite {
+                y,
+                x: entry[1],
+                tile: entry[2],
+                attrs: entry[3],
+                index: index as u8,
+                extra,
+            });
+        }
+        self.sprites.sort_by_key(|sprite| sprite.x);
+    }
+
+    /// Dots `sprite` adds to mode 3. `columns` tracks which 8-pixel columns
+    /// have already paid for finishing the background fetch.
+    pub(super) fn sprite_penalty(&self, sprite: &Sprite, columns: &mut u64) -> u16 {
+        if sprite.extra || sprite.x >= 168 {
+            return 0;
+        }
+        if sprite.x == 0 {
+            return FETCH_PENALTY + 5;
+        }
+        let position = u16::from(sprite.x) + u16::from(self.scx);
+        let column = 1 << (position / 8);
+        if *columns & column != 0 {
+            return FETCH_PENALTY;
+        }
+        *columns |= column;
+        FETCH_PENALTY + 5 - (position % 8).min(5)
+    }
+
+    /// The eight pixels `sprite` shows on the current line, left to right.
+    pub(super) fn sprite_pixels(&self, sprite: &Sprite) -> [ObjPixel; 8] {
+        let height = self.sprite_height();
+        let mut row = (self.line + 16).wrapping_sub(sprite.y) & (height - 1);
+        if sprite.attrs & 0x40 != 0 {
+            row = height - 1 - row;
+        }
+        let tile = if height == 16 {
+            sprite.tile & 0xFE
+        } else {
+            sprite.tile
+        };
+        let bank = if self.cgb_mode() && sprite.attrs & 0x08 != 0 {
+            0x2000
+        } else {
+            0
+        };
+        let addr = bank + usize::from(tile) * 16 + usize::from(row) * 2;
+        let (low, high) = (self.vram[addr], self.vram[addr + 1]);
+
+        let mut pixels = [ObjPixel::default(); 8];
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let bit = if sprite.attrs & 0x20 != 0 { i } else { 7 - i };
+            pixel.color = (high >> bit & 1) << 1 | (low >> bit & 1);
+            pixel.attrs = sprite.attrs;
+            pixel.index = sprite.index;
+        }
+        pixels
+    }
+
+    /// Puts `new` over `slot` if it wins there. Objects arrive in fetch
+    /// order, so under the DMG rule the first opaque pixel stays.
+    pub(super) fn merge_obj_pixel(&self, slot: &mut ObjPixel, new: ObjPixel) {
+        if new.color == 0 {
+            return;
+        }
+        let index_priority = self.cgb_mode() && self.opri & 0x01 == 0;
+        if slot.color == 0 || (index_priority && new.index < slot.index) {
+            *slot = new;
+        }
+    }
+
+    pub(super) fn save_sprites(&self, w: &mut StateWriter) {
+        w.u8(self.sprites.len() as u8);
+        for sprite in &self.sprites {
+            w.bytes(&[sprite.y, sprite.x, sprite.tile, sprite.attrs, sprite.index]);
+            w.bool(sprite.extra);
+        }
+    }
+
+    pub(super) fn load_sprites(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let count = r.u8()? as usize;
+        if count > self.oam.len() / 4 {
+            return Err(StateError::Invalid("object count"));
+        }
+        self.sprites.clear();
+        for _ in 0..count {
+            let mut fields = [0; 5];
+            r.bytes(&mut fields)?;
+            let [y, x, tile, attrs, index] = fields;
+            self.sprites.push(Sprite {
+                y,
+                x,
+                tile,
+                attrs,
+                index,
+                extra: r.bool()?,
+            });
+        }
+        Ok(())
+    }
+
+    /// Draws pixel `x` of the current line from background pixel `bg` and
+    /// `obj` on top.
+    pub(super) fn put_pixel(&mut self, x: usize, bg: u8, obj: ObjPixel) {
+        let i = usize::from(self.line) * SCREEN_WIDTH + x;
+        if self.cgb_mode() {
+            self.color_framebuffer[i] = self.mix_color(bg, obj);
+            return;
+        }
+        let (shade, obj_palette) = self.mix(bg, obj);
+        if self.model.is_cgb() {
+            // A DMG game on a CGB: the shade picks a color from background
+            // palette 0 or from the object palette matching OBP0 or OBP1.
+            self.color_framebuffer[i] = match obj_palette {
+                Some(palette) => self.obj_palettes.color(palette, shade),
+                None => self.bg_palettes.color(0, shade),
+            };
+        } else {
+            self.framebuffer[i] = shade;
+        }
+    }
+
+    /// The shade shown for background pixel `bg` with `obj` on top, and
+    /// which of OBP0 and OBP1 it came through if the object won.
+    fn mix(&self, bg: u8, obj: ObjPixel) -> (u8, Option<u8>) {
+        // With LCDC bit 0 clear the background is blank and never covers
+        // objects.
+        let bg = if self.lcdc & 0x01 != 0 { bg & 0x03 } else { 0 };
+        let behind_bg = obj.attrs & 0x80 != 0 && bg != 0;
+        if obj.color != 0 && self.lcdc & 0x02 != 0 && !behind_bg {
+            let (palette, register) = if obj.attrs & 0x10 != 0 {
+                (1, self.obp1)
+            } else {
+                (0, self.obp0)
+            };
+            (super::shade(register, obj.color), Some(palette))
+        } else if self.lcdc & 0x01 != 0 {
+            (super::shade(self.bgp, bg), None)
+        } else {
+            (0, None)
+        }
+    }
+
+    /// The color shown for background pixel `bg` with `obj` on top, with
+    /// the CGB rules: LCDC bit 0 takes away the background's priority
+    /// rather than the background, and either the tile attributes or the
+    /// object can put the background in front.
+    fn mix_color(&self, bg: u8, obj: ObjPixel) -> u16 {
+        let color = bg & 0x03;
+        if obj.color != 0 && self.lcdc & 0x02 != 0 {
+            let bg_priority = bg & 0x80 != 0 || obj.attrs & 0x80 != 0;
+            if !(self.lcdc & 0x01 != 0 && color != 0 && bg_priority) {
+                return self.obj_palettes.color(obj.attrs, obj.color);
+            }
+        }
+        self.bg_palettes.color(bg >> 2, color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Mode, Renderer, SCREEN_WIDTH};
+    use super::*;
+    use crate::model::Model;
+
+    const RENDERERS: [Renderer; 2] = [Renderer::Scanline, Renderer::Fifo];
+
+    /// Tile rows as color indices, eight digits per row.
+    fn tile(rows: [&str; 8]) -> [u8; 16] {
+        let mut data = [0; 16];
+        for (row, pixels) in rows.iter().enumerate() {
+            for (x, color) in pixels.bytes().enumerate() {
+                let color = color - b'0';
+                let bit = 0x80 >> x;
+                if color & 1 != 0 {
+                    data[row * 2] |= bit;
+                }
+                if color & 2 != 0 {
+                    data[row * 2 + 1] |= bit;
+                }
+            }
+        }
+        data
+    }
+
+    /// Tile 0 is blank, so the background shows shade 0 everywhere except
+    /// where a test puts tile 4 in the map.
+    fn sprite_ppu(model: Model, renderer: Renderer) -> Ppu {
+        let tiles = [
+            tile(["00000000"; 8]),
+            tile([
+                "10000000", "02000000", "00300000", "00010000", "00002000", "00000300", "00000010",
+                "00000002",
+            ]),
+            tile(["11111111"; 8]),
+            tile(["22222222"; 8]),
+            tile(["00001111"; 8]),
+            tile(["33333333"; 8]),
+        ];
+        let mut ppu = Ppu::new(model);
+        ppu.set_renderer(renderer);
+        for (i, data) in tiles.iter().enumerate() {
+            for (j, &byte) in data.iter().enumerate() {
+                ppu.write_vram(0x8000 + (i * 16 + j) as u16, byte);
+            }
+        }
+        ppu.write(0xFF47, 0xE4); // BGP
+        ppu.write(0xFF48, 0xE4); // OBP0
+        ppu.write(0xFF49, 0x1B); // OBP1, reversed
+
+        // On CGB models the first background and object palettes give color
+        // n the value n, to read back like the shades above.
+        ppu.write(0xFF68, 0x80);
+        ppu.write(0xFF6A, 0x80);
+        for color in 0..4 {
+            for &data in &[0xFF69, 0xFF6B] {
+                ppu.write(data, color);
+                ppu.write(data, 0);
+            }
+        }
+        ppu
+    }
+
+    /// Puts object `index` at screen position (`x`, `y`).
+    fn place(ppu: &mut Ppu, index: u16, x: u8, y: u8, tile: u8, attrs: u8) {
+        let addr = 0xFE00 + index * 4;
+        for (i, value) in [y + 16, x + 8, tile, attrs].iter().enumerate() {
+            ppu.write_oam(addr + i as u16, *value);
+        }
+    }
+
+    /// Turns the LCD on with `lcdc` and returns the first frame's shades,
+    /// or colors on CGB models, in the `width`×`height` area at the top
+    /// left, one string per row.
+    fn render(mut ppu: Ppu, lcdc: u8, width: usize, height: usize) -> Vec<String> {
+        ppu.write(0xFF40, lcdc);
+        while !ppu.frame_ready {
+            ppu.tick_dot();
+        }
+        let pixels: Vec<u16> = if ppu.model.is_cgb() {
+            ppu.color_framebuffer().to_vec()
+        } else {
+            ppu.framebuffer()
+                .iter()
+                .map(|&shade| u16::from(shade))
+                .collect()
+        };
+        pixels
+            .chunks(SCREEN_WIDTH)
+            .take(height)
+            .map(|row| row[..width].iter().map(|pixel| pixel.to_string()).collect())
+            .collect()
+    }
+
+    /// Checks both renderers against `expected`.
+    fn check(model: Model, lcdc: u8, setup: impl Fn(&mut Ppu), expected: &[&str]) {
+        for &renderer in &RENDERERS {
+            let mut ppu = sprite_ppu(model, renderer);
+            setup(&mut ppu);
+            let width = expected[0].len();
+            let image = render(ppu, lcdc, width, expected.len());
+            assert_eq!(image, expected, "{:?} renderer", renderer);
+        }
+    }
+
+    #[test]
+    fn palettes_and_transparency() {
+        check(
+            Model::Dmg,
+            0x93,
+            |ppu| {
+                place(ppu, 0, 0, 0, 1, 0x00);
+                place(ppu, 1, 8, 0, 1, 0x10);
+            },
+            &[
+                "1000000020000000",
+                "0200000001000000",
+                "0030000000000000",
+                "0001000000020000",
+                "0000200000001000",
+                "0000030000000000",
+                "0000001000000020",
+                "0000000200000001",
+            ],
+        );
+    }
+
+    #[test]
+    fn flips() {
+        check(
+            Model::Dmg,
+            0x93,
+            |ppu| {
+                place(ppu, 0, 0, 0, 1, 0x20);
+                place(ppu, 1, 8, 0, 1, 0x40);
+                place(ppu, 2, 16, 0, 1, 0x60);
+            },
+            &[
+                "000000010000000220000000",
+                "000000200000001001000000",
+                "000003000000030000300000",
+                "000010000000200000020000",
+                "000200000001000000001000",
+                "003000000030000000000300",
+                "010000000200000000000020",
+                "200000001000000000000001",
+            ],
+        );
+    }
+
+    #[test]
+    fn tall_objects_use_a_tile_pair() {
+        // Tile 3 selects tiles 2 and 3; flipping swaps them.
+        let expected = ["1111111122222222"; 8]
+            .iter()
+            .chain(&["2222222211111111"; 8])
+            .copied()
+            .collect::<Vec<_>>();
+        check(
+            Model::Dmg,
+            0x97,
+            |ppu| {
+                place(ppu, 0, 0, 0, 3, 0x00);
+                place(ppu, 1, 8, 0, 3, 0x40);
+            },
+            &expected,
+        );
+    }
+
+    #[test]
+    fn ten_objects_per_line() {
+        let setup = |ppu: &mut Ppu| {
+            for i in 0..11 {
+                place(ppu, i, i as u8 * 8, 0, 2, 0x00);
+            }
+        };
+        check(Model::Dmg, 0x93, setup, &[&("1".repeat(80) + "00000000")]);
+
+        for &renderer in &RENDERERS {
+            let mut ppu = sprite_ppu(Model::Dmg, renderer);
+            ppu.set_unlimited_sprites(true);
+            setup(&mut ppu);
+            let image = render(ppu, 0x93, 88, 1);
+            assert_eq!(image, ["1".repeat(88)]);
+        }
+    }
+
+    #[test]
+    fn dmg_prefers_the_smaller_x() {
+        // Object 0 is further right, so object 1 wins where they overlap.
+        check(
+            Model::Dmg,
+            0x93,
+            |ppu| {
+                place(ppu, 0, 4, 0, 2, 0x00);
+                place(ppu, 1, 0, 0, 3, 0x00);
+            },
+            &["222222221111"],
+        );
+    }
+
+    #[test]
+    fn cgb_prefers_the_lower_index_unless_opri_says_otherwise() {
+        let setup = |ppu: &mut Ppu| {
+            place(ppu, 0, 4, 0, 2, 0x00);
+            place(ppu, 1, 0, 0, 3, 0x00);
+        };
+        check(Model::Cgb, 0x93, setup, &["222211111111"]);
+        check(
+            Model::Cgb,
+            0x93,
+            |ppu| {
+                ppu.write(0xFF6C, 0x01);
+                setup(ppu);
+            },
+            &["222222221111"],
+        );
+    }
+
+    #[test]
+    fn background_over_object() {
+        // Tile 4 covers the right half of the first map cell with color 1.
+        check(
+            Model::Dmg,
+            0x93,
+            |ppu| {
+                ppu.write_vram(0x9800, 4);
+                place(ppu, 0, 0, 0, 5, 0x80);
+                place(ppu, 1, 8, 0, 5, 0x80);
+                place(ppu, 2, 0, 8, 5, 0x00);
+            },
+            &["3333111133333333", "3333111133333333"],
+        );
+    }
+
+    #[test]
+    fn cgb_background_priority() {
+        let setup = |ppu: &mut Ppu| {
+            ppu.write_vram(0x9800, 4);
+            ppu.write(0xFF4F, 1);
+            ppu.write_vram(0x9800, 0x80);
+            ppu.write(0xFF4F, 0);
+            place(ppu, 0, 0, 0, 5, 0x00);
+        };
+        // The tile attribute puts colors 1-3 of the background in front...
+        check(Model::Cgb, 0x93, setup, &["33331111"]);
+        // ...unless LCDC bit 0 takes priority away from the background.
+        check(Model::Cgb, 0x92, setup, &["33333333"]);
+    }
+
+    #[test]
+    fn objects_lengthen_mode_3() {
+        for &renderer in &RENDERERS {
+            let mut ppu = sprite_ppu(Model::Dmg, renderer);
+            // Aligned with a column: 6 dots plus 5 to finish the background
+            // fetch. A second object in the same column costs only 6.
+            place(&mut ppu, 0, 16, 1, 2, 0x00);
+            place(&mut ppu, 1, 16, 10, 2, 0x00);
+            place(&mut ppu, 2, 20, 10, 2, 0x00);
+            ppu.write(0xFF40, 0x93);
+
+            let mut lengths = Vec::new();
+            for &line in &[1, 10] {
+                while !(ppu.line == line && ppu.mode == Mode::Transfer) {
+                    ppu.tick_dot();
+                }
+                let mut dots = 0;
+                while ppu.mode == Mode::Transfer {
+                    ppu.tick_dot();
+                    dots += 1;
+                }
+                lengths.push(dots);
+            }
+            assert_eq!(lengths, [172 + 11, 172 + 11 + 6], "{:?}", renderer);
+        }
+    }
+}
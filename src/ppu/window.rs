@@ -0,0 +1,271 @@
+//! When and where the window shows.
+//!
+//! The window is armed for the rest of the frame once LY has matched WY at
+//! the start of a line's pixel transfer; changing WY afterwards does not
+//! take it away, and setting WY to a line already passed does not bring it
+//! in until the next frame. It then starts on each line at X = WX - 7.
+//!
+//! The window keeps its own line counter, which only advances on lines
+//! where the window actually started, so hiding it for a few lines with
+//! LCDC bit 5 picks up where it left off rather than skipping rows.
+//!
+//! Two WX values misbehave. Below 7 the window starts at the left edge
+//! with its first 7 - WX pixels cut off, and at WX = 0 the fine scroll of
+//! the background cuts off SCX % 8 more. At WX = 166 the window starts on
+//! the last pixel and carries on from the left edge of the next line.
+
+use super::Ppu;
+use crate::state::{StateError, StateReader, StateWriter};
+
+#[derive(Clone, Debug, Default)]
+pub(super) struct Window {
+    /// LY has matched WY this frame.
+    y_triggered: bool,
+    /// Window rows drawn so far this frame.
+    line: u8,
+    /// The window started on the current line.
+    drawn: bool,
+    /// The window fills the current line from the left edge, having
+    /// started at WX = 166 on the line before.
+    wraps: bool,
+    /// The window started at WX = 166 on the current line.
+    wraps_next: bool,
+}
+
+impl Window {
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.y_triggered);
+        w.u8(self.line);
+        w.bool(self.drawn);
+        w.bool(self.wraps);
+        w.bool(self.wraps_next);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.y_triggered = r.bool()?;
+        self.line = r.u8()?;
+        self.drawn = r.bool()?;
+        self.wraps = r.bool()?;
+        self.wraps_next = r.bool()?;
+        Ok(())
+    }
+}
+
+impl Ppu {
+    /// Called as each visible line starts.
+    pub(super) fn window_next_line(&mut self) {
+        if std::mem::take(&mut self.window.drawn) {
+            self.window.line = self.window.line.wrapping_add(1);
+        }
+        self.window.wraps = std::mem::take(&mut self.window.wraps_next);
+    }
+
+    /// Called as pixel transfer starts.
+    pub(super) fn check_window_y(&mut self) {
+        if self.line == self.wy {
+            self.window.y_triggered = true;
+        }
+    }
+
+    /// Whether the window fills the current line from the left edge, left
+    /// over from WX = 166 on the line before.
+    pub(super) fn window_wraps(&self) -> bool {
+        self.window.wraps && self.lcdc & 0x20 != 0
+    }
+
+    /// Whether the window starts at or before pixel `x` of the current
+    /// line.
+    pub(super) fn window_starts_at(&self, x: u8) -> bool {
+        self.lcdc & 0x20 != 0 && self.window.y_triggered && u16::from(x) + 7 >= u16::from(self.wx)
+    }
+
+    /// Window pixels cut off at the left edge when WX is below 7, not
+    /// counting the extra fine scroll at WX = 0.
+    pub(super) fn window_hidden(&self) -> u8 {
+        7u8.saturating_sub(self.wx)
+    }
+
+    /// Records that the window has started on the current line.
+    pub(super) fn start_window(&mut self) {
+        self.window.drawn = true;
+        self.window.wraps_next = !self.window.wraps && self.wx == 166;
+    }
+
+    /// The window row drawn on the current line.
+    pub(super) fn window_line(&self) -> u8 {
+        self.window.line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Mode, Renderer, SCREEN_WIDTH};
+    use super::*;
+    use crate::model::Model;
+
+    const RENDERERS: [Renderer; 2] = [Renderer::Scanline, Renderer::Fifo];
+
+    /// The background is blank. The window map at 0x9C00 uses tile 1,
+    /// colored 1 2 3 0 across each half, for its first row of tiles and
+    /// the solid color 3 tile 2 below.
+    fn window_ppu(renderer: Renderer) -> Ppu {
+        let mut ppu = Ppu::new(Model::Dmg);
+        ppu.set_renderer(renderer);
+        for row in 0..8 {
+            ppu.write_vram(0x8010 + row * 2, 0xAA);
+            ppu.write_vram(0x8011 + row * 2, 0x66);
+            ppu.write_vram(0x8020 + row * 2, 0xFF);
+            ppu.write_vram(0x8021 + row * 2, 0xFF);
+        }
+        for i in 0..32 {
+            ppu.write_vram(0x9C00 + i, 1);
+            ppu.write_vram(0x9C20 + i, 2);
+        }
+        ppu.write(0xFF47, 0xE4);
+        ppu
+    }
+
+    /// Runs until mode 3 of `line` is over.
+    fn run_to_end_of(ppu: &mut Ppu, line: u8) {
+        while !(ppu.line == line && ppu.mode == Mode::HBlank) {
+            ppu.tick_dot();
+        }
+    }
+
+    fn row(ppu: &Ppu, line: u8) -> String {
+        let start = usize::from(line) * SCREEN_WIDTH;
+        ppu.framebuffer()[start..start + SCREEN_WIDTH]
+            .iter()
+            .map(|shade| shade.to_string())
+            .collect()
+    }
+
+    /// `count` repeats of the window's first tile row, starting `skip`
+    /// pixels in.
+    fn pattern(skip: usize, count: usize) -> String {
+        "1230".chars().cycle().skip(skip).take(count).collect()
+    }
+
+    #[test]
+    fn line_counter_only_counts_lines_the_window_was_on() {
+        for &renderer in &RENDERERS {
+            let mut ppu = window_ppu(renderer);
+            ppu.write(0xFF4A, 10); // WY
+            ppu.write(0xFF4B, 7); // WX
+            ppu.write(0xFF40, 0xF1);
+
+            // Seven rows of window on lines 10-16, then hide it for ten
+            // lines.
+            run_to_end_of(&mut ppu, 16);
+            ppu.write(0xFF40, 0xD1);
+            run_to_end_of(&mut ppu, 26);
+            ppu.write(0xFF40, 0xF1);
+
+            // Line 27 shows window row 7, the last of the first tile row,
+            // and line 28 moves on to the solid tiles.
+            run_to_end_of(&mut ppu, 27);
+            assert_eq!(row(&ppu, 27), pattern(0, 160), "{:?}", renderer);
+            run_to_end_of(&mut ppu, 28);
+            assert_eq!(row(&ppu, 28), "3".repeat(160), "{:?}", renderer);
+        }
+    }
+
+    #[test]
+    fn wy_only_counts_when_it_matches() {
+        for &renderer in &RENDERERS {
+            let mut ppu = window_ppu(renderer);
+            ppu.write(0xFF4A, 100);
+            ppu.write(0xFF4B, 87);
+            ppu.write(0xFF40, 0xF1);
+
+            // Moving WY to a line already passed does not start the window.
+            run_to_end_of(&mut ppu, 20);
+            ppu.write(0xFF4A, 5);
+            run_to_end_of(&mut ppu, 143);
+            assert_eq!(row(&ppu, 143), "0".repeat(160), "{:?}", renderer);
+
+            // Moving it to a line still ahead does, with the window's first
+            // row.
+            run_to_end_of(&mut ppu, 0);
+            run_to_end_of(&mut ppu, 3);
+            ppu.write(0xFF4A, 30);
+            run_to_end_of(&mut ppu, 30);
+            let expected = "0".repeat(80) + &pattern(0, 80);
+            assert_eq!(row(&ppu, 30), expected, "{:?}", renderer);
+
+            // Once started, moving WY away does not stop it.
+            ppu.write(0xFF4A, 200);
+            run_to_end_of(&mut ppu, 31);
+            assert_eq!(row(&ppu, 31), expected, "{:?}", renderer);
+        }
+    }
+
+    #[test]
+    fn wx_below_7_cuts_off_the_left_of_the_window() {
+        for &renderer in &RENDERERS {
+            let mut ppu = window_ppu(renderer);
+            ppu.write(0xFF43, 2); // SCX
+            ppu.write(0xFF4B, 4);
+            ppu.write(0xFF40, 0xF1);
+            run_to_end_of(&mut ppu, 1);
+            assert_eq!(row(&ppu, 1), pattern(3, 160), "{:?}", renderer);
+        }
+    }
+
+    #[test]
+    fn wx_0_also_loses_the_fine_scroll() {
+        for &renderer in &RENDERERS {
+            let mut ppu = window_ppu(renderer);
+            ppu.write(0xFF43, 2);
+            ppu.write(0xFF4B, 0);
+            ppu.write(0xFF40, 0xF1);
+            run_to_end_of(&mut ppu, 1);
+            assert_eq!(row(&ppu, 1), pattern(7 + 2, 160), "{:?}", renderer);
+        }
+    }
+
+    #[test]
+    fn wx_166_carries_over_to_the_next_line() {
+        for &renderer in &RENDERERS {
+            let mut ppu = window_ppu(renderer);
+            ppu.write(0xFF4A, 10);
+            ppu.write(0xFF4B, 166);
+            ppu.write(0xFF40, 0xF1);
+
+            run_to_end_of(&mut ppu, 10);
+            assert_eq!(row(&ppu, 10), "0".repeat(159) + "1", "{:?}", renderer);
+            run_to_end_of(&mut ppu, 11);
+            assert_eq!(row(&ppu, 11), pattern(0, 160), "{:?}", renderer);
+            // The carried-over line starts a new window line, and the one
+            // after it goes back to starting on the last pixel.
+            run_to_end_of(&mut ppu, 12);
+            assert_eq!(row(&ppu, 12), "0".repeat(159) + "1", "{:?}", renderer);
+            assert_eq!(ppu.window_line(), 2);
+        }
+    }
+
+    #[test]
+    fn mode_3_pays_for_the_window() {
+        for &renderer in &RENDERERS {
+            let mut lengths = Vec::new();
+            for &wx in &[7, 3, 0, 100] {
+                let mut ppu = window_ppu(renderer);
+                ppu.write(0xFF43, 2);
+                ppu.write(0xFF4B, wx);
+                ppu.write(0xFF40, 0xF1);
+                while !(ppu.line == 1 && ppu.mode == Mode::Transfer) {
+                    ppu.tick_dot();
+                }
+                let mut dots = 0;
+                while ppu.mode == Mode::Transfer {
+                    ppu.tick_dot();
+                    dots += 1;
+                }
+                lengths.push(dots);
+            }
+            // Fine scroll, the restart, and a dot per pixel cut off.
+            let base = 172 + 2 + 6;
+            assert_eq!(lengths, [base, base + 4, base + 7, base], "{:?}", renderer);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+/// The eight 8-bit registers of the LR35902.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: Flags,
+    pub h: u8,
+    pub l: u8,
+}
+
+impl Registers {
+    /// A in the high byte, F in the low byte. The low nibble of F is always
+    /// zero.
+    pub fn af(&self) -> u16 {
+        u16::from_be_bytes([self.a, self.f.bits()])
+    }
+
+    pub fn set_af(&mut self, value: u16) {
+        let [a, f] = value.to_be_bytes();
+        self.a = a;
+        self.f = Flags::from_bits(f);
+    }
+
+    pub fn bc(&self) -> u16 {
+        u16::from_be_bytes([self.b, self.c])
+    }
+
+    pub fn set_bc(&mut self, value: u16) {
+        let [b, c] = value.to_be_bytes();
+        self.b = b;
+        self.c = c;
+    }
+
+    pub fn de(&self) -> u16 {
+        u16::from_be_bytes([self.d, self.e])
+    }
+
+    pub fn set_de(&mut self, value: u16) {
+        let [d, e] = value.to_be_bytes();
+        self.d = d;
+        self.e = e;
+    }
+
+    pub fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.h, self.l])
+    }
+
+    pub fn set_hl(&mut self, value: u16) {
+        let [h, l] = value.to_be_bytes();
+        self.h = h;
+        self.l = l;
+    }
+
+    /// Increments `pair`, wrapping at 0xFFFF, as `INC rr` does. Flags are
+    /// left alone.
+    pub fn inc_pair(&mut self, pair: Pair) {
+        let value = self.pair(pair).wrapping_add(1);
+        self.set_pair(pair, value);
+    }
+
+    /// Decrements `pair`, wrapping at 0x0000, as `DEC rr` does. Flags are
+    /// left alone.
+    pub fn dec_pair(&mut self, pair: Pair) {
+        let value = self.pair(pair).wrapping_sub(1);
+        self.set_pair(pair, value);
+    }
+
+    fn pair(&self, pair: Pair) -> u16 {
+        match pair {
+            Pair::Bc => self.bc(),
+            Pair::De => self.de(),
+            Pair::Hl => self.hl(),
+        }
+    }
+
+    fn set_pair(&mut self, pair: Pair, value: u16) {
+        match pair {
+            Pair::Bc => self.set_bc(value),
+            Pair::De => self.set_de(value),
+            Pair::Hl => self.set_hl(value),
+        }
+    }
+
+    /// Post-increments HL, as `LD (HL+),A` and `LD A,(HL+)` do. Returns the
+    /// value before the increment.
+    pub fn hl_inc(&mut self) -> u16 {
+        let hl = self.hl();
+        self.set_hl(hl.wrapping_add(1));
+        hl
+    }
+
+    /// Post-decrements HL, as `LD (HL-),A` and `LD A,(HL-)` do. Returns the
+    /// value before the decrement.
+    pub fn hl_dec(&mut self) -> u16 {
+        let hl = self.hl();
+        self.set_hl(hl.wrapping_sub(1));
+        hl
+    }
+}
+
+/// The general-purpose register pairs. AF only ever moves as a whole.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pair {
+    Bc,
+    De,
+    Hl,
+}
+
+/// The F register.
+///
+/// Only the upper nibble exists in hardware: Z (bit 7), N (bit 6), H (bit 5)
+/// and C (bit 4). The low nibble always reads back as zero, whatever was
+/// written to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    const Z: u8 = 0x80;
+    const N: u8 = 0x40;
+    const H: u8 = 0x20;
+    const C: u8 = 0x10;
+
+    pub fn new(z: bool, n: bool, h: bool, c: bool) -> Flags {
+        let mut flags = Flags(0);
+        flags.set_z(z);
+        flags.set_n(n);
+        flags.set_h(h);
+        flags.set_c(c);
+        flags
+    }
+
+    /// Builds the register from a raw byte, dropping the low nibble.
+    pub fn from_bits(bits: u8) -> Flags {
+        Flags(bits & 0xF0)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Zero.
+    pub fn z(self) -> bool {
+        self.0 & Flags::Z != 0
+    }
+
+    /// Subtract.
+    pub fn n(self) -> bool {
+        self.0 & Flags::N != 0
+    }
+
+    /// Half carry.
+    pub fn h(self) -> bool {
+        self.0 & Flags::H != 0
+    }
+
+    /// Carry.
+    pub fn c(self) -> bool {
+        self.0 & Flags::C != 0
+    }
+
+    pub fn set_z(&mut self, value: bool) {
+        self.set(Flags::Z, value);
+    }
+
+    pub fn set_n(&mut self, value: bool) {
+        self.set(Flags::N, value);
+    }
+
+    pub fn set_h(&mut self, value: bool) {
+        self.set(Flags::H, value);
+    }
+
+    pub fn set_c(&mut self, value: bool) {
+        self.set(Flags::C, value);
+    }
+
+    fn set(&mut self, mask: u8, value: bool) {
+        if value {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
+    }
+}
+
+impl From<u8> for Flags {
+    fn from(bits: u8) -> Flags {
+        Flags::from_bits(bits)
+    }
+}
+
+impl From<Flags> for u8 {
+    fn from(flags: Flags) -> u8 {
+        flags.bits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs_are_high_byte_first() {
+        let mut regs = Registers::default();
+        regs.set_bc(0x1234);
+        regs.set_de(0x5678);
+        regs.set_hl(0x9ABC);
+        assert_eq!((regs.b, regs.c), (0x12, 0x34));
+        assert_eq!((regs.d, regs.e), (0x56, 0x78));
+        assert_eq!((regs.h, regs.l), (0x9A, 0xBC));
+        assert_eq!(regs.bc(), 0x1234);
+        assert_eq!(regs.de(), 0x5678);
+        assert_eq!(regs.hl(), 0x9ABC);
+    }
+
+    #[test]
+    fn af_masks_low_nibble() {
+        let mut regs = Registers::default();
+        regs.set_af(0x12FF);
+        assert_eq!(regs.a, 0x12);
+        assert_eq!(regs.f.bits(), 0xF0);
+        assert_eq!(regs.af(), 0x12F0);
+
+        regs.set_af(0xAB0F);
+        assert_eq!(regs.af(), 0xAB00);
+        assert_eq!(regs.f, Flags::default());
+    }
+
+    #[test]
+    fn flags_keep_their_bit_positions() {
+        let f = Flags::new(true, false, true, false);
+        assert_eq!(f.bits(), 0xA0);
+        assert_eq!(Flags::from_bits(0x5F).bits(), 0x50);
+        assert!(Flags::from(0x10).c());
+    }
+
+    #[test]
+    fn hl_inc_wraps_at_ffff() {
+        let mut regs = Registers::default();
+        regs.set_hl(0xFFFF);
+        assert_eq!(regs.hl_inc(), 0xFFFF);
+        assert_eq!(regs.hl(), 0x0000);
+        assert_eq!(regs.hl_dec(), 0x0000);
+        assert_eq!(regs.hl(), 0xFFFF);
+    }
+
+    #[test]
+    fn pair_inc_and_dec_wrap() {
+        let mut regs = Registers {
+            f: Flags::from_bits(0xF0),
+            ..Registers::default()
+        };
+        for &pair in &[Pair::Bc, Pair::De, Pair::Hl] {
+            regs.set_pair(pair, 0xFFFF);
+            regs.inc_pair(pair);
+            assert_eq!(regs.pair(pair), 0x0000, "{:?}", pair);
+            regs.dec_pair(pair);
+            assert_eq!(regs.pair(pair), 0xFFFF, "{:?}", pair);
+        }
+        regs.set_de(0x12FF);
+        regs.inc_pair(Pair::De);
+        assert_eq!((regs.d, regs.e), (0x13, 0x00));
+        assert_eq!(regs.f.bits(), 0xF0);
+    }
+
+    #[test]
+    fn setters_accept_full_range() {
+        let mut regs = Registers::default();
+        regs.set_bc(0xFFFF);
+        regs.set_bc(regs.bc().wrapping_add(1));
+        assert_eq!(regs.bc(), 0);
+        regs.set_de(regs.de().wrapping_sub(1));
+        assert_eq!(regs.de(), 0xFFFF);
+    }
+}
@@ -0,0 +1,354 @@
+//! Rewinding through recent history.
+//!
+//! [`Rewind`] takes a save state every few frames. Only the newest one is
+//! kept whole; each older one is stored as the XOR against its successor,
+//! run-length encoded, which is small because little changes between
+//! nearby frames. When the history outgrows its memory budget the oldest
+//! snapshots are dropped.
+//!
+//! The buttons held during every frame are recorded too, so stepping back
+//! a single frame between snapshots loads the snapshot before it and plays
+//! the remaining frames again with the same input.
+
+use std::collections::VecDeque;
+
+use crate::gameboy::GameBoy;
+
+struct Snapshot {
+    /// The frame count when the snapshot was taken.
+    frame: u64,
+    /// The full state for the newest snapshot, a delta against the next
+    /// newer one otherwise.
+    data: Vec<u8>,
+    /// Buttons held during each frame after this snapshot.
+    inputs: Vec<u8>,
+}
+
+impl Snapshot {
+    fn size(&self) -> usize {
+        self.data.len() + self.inputs.len()
+    }
+}
+
+pub struct Rewind {
+    interval: u32,
+    budget: usize,
+    snapshots: VecDeque<Snapshot>,
+    /// Frames recorded so far.
+    frame: u64,
+    used: usize,
+}
+
+impl Rewind {
+    /// Snapshots every `interval` frames, keeping at most about `budget`
+    /// bytes of history. The newest snapshot is always kept, even if it
+    /// alone exceeds the budget.
+    pub fn new(interval: u32, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            snapshots: VecDeque::new(),
+            frame: 0,
+            used: 0,
+        }
+    }
+
+    /// Records the frame `gb` has just finished. Call once after every
+    /// frame, with the input for that frame still applied.
+    pub fn record(&mut self, gb: &GameBoy) {
+        if let Some(newest) = self.snapshots.back_mut() {
+            newest.inputs.push(gb.bus.joypad.pressed());
+            self.used += 1;
+        }
+        self.frame += 1;
+        if self.snapshots.is_empty() || self.frame % u64::from(self.interval) == 0 {
+            self.push(gb.save_state());
+        }
+    }
+
+    /// Puts `gb` back one frame. Returns false, leaving `gb` alone, when
+    /// there is no history left.
+    pub fn step_back(&mut self, gb: &mut GameBoy) -> bool {
+        let target = match self.frame.checked_sub(1) {
+            Some(target) => target,
+            None => return false,
+        };
+        let oldest = match self.snapshots.front() {
+            Some(oldest) => oldest.frame,
+            None => return false,
+        };
+        if target < oldest {
+            return false;
+        }
+        // Rebuild the snapshot to resume from without touching the history,
+        // which stays as it was if the state does not load.
+        let keep = self
+            .snapshots
+            .iter()
+            .rposition(|s| s.frame <= target)
+            .expect("the oldest snapshot is not after the target");
+        let mut newer = self.snapshots.range(keep..).rev();
+        let mut state = newer
+            .next()
+            .expect("a snapshot to resume from")
+            .data
+            .clone();
+        for snapshot in newer {
+            state = apply_delta(&state, &snapshot.data);
+        }
+        if gb.load_state(&state).is_err() {
+            return false;
+        }
+        for dropped in self.snapshots.drain(keep + 1..) {
+            self.used -= dropped.size();
+        }
+
+        let newest = self
+            .snapshots
+            .back_mut()
+            .expect("the oldest snapshot is kept");
+        self.used -= newest.data.len();
+        self.used += state.len();
+        newest.data = state;
+
+        let replay = (target - newest.frame) as usize;
+        for &input in &newest.inputs[..replay] {
+            gb.bus.set_pressed(input);
+            gb.run_frame();
+        }
+        self.used -= newest.inputs.len() - replay;
+        newest.inputs.truncate(replay);
+        self.frame = target;
+        true
+    }
+
+    /// How many frames back the history reaches.
+    pub fn frames(&self) -> u64 {
+        self.snapshots
+            .front()
+            .map_or(0, |oldest| self.frame - oldest.frame)
+    }
+
+    /// Bytes of history held.
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    /// Forgets all history, for example after loading a state.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.used = 0;
+    }
+
+    fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.snapshots.back_mut() {
+            let delta = delta(&state, &newest.data);
+            self.used -= newest.data.len();
+            self.used += delta.len();
+            newest.data = delta;
+        }
+        let snapshot = Snapshot {
+            frame: self.frame,
+            data: state,
+            inputs: Vec::new(),
+        };
+        self.used += snapshot.size();
+        self.snapshots.push_back(snapshot);
+
+        while self.used > self.budget && self.snapshots.len() > 1 {
+            let oldest = self.snapshots.pop_front().expect("more than one snapshot");
+            self.used -= oldest.size();
+        }
+    }
+}
+
+/// Encodes how to turn `base` into `target`: the length of `target`, then
+/// runs of a count of unchanged bytes followed by a count of changed bytes
+/// and their XOR with `base`. Counts are LEB128.
+fn delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| target[i] ^ base.get(i).copied().unwrap_or(0);
+    let mut out = Vec::new();
+    push_varint(&mut out, target.len());
+
+    let mut i = 0;
+    while i < target.len() {
+        let same_start = i;
+        while i < target.len() && xor(i) == 0 {
+            i += 1;
+        }
+        if i == target.len() {
+            // The rest is unchanged, which the length already implies.
+            break;
+        }
+        let changed_start = i;
+        while i < target.len() {
+            // Gaps of up to two unchanged bytes cost less to carry along
+            // than to start a new run for.
+            let gap_closes = (i + 1..target.len().min(i + 3)).any(|j| xor(j) != 0);
+            if xor(i) == 0 && !gap_closes {
+                break;
+            }
+            i += 1;
+        }
+        push_varint(&mut out, changed_start - same_start);
+        push_varint(&mut out, i - changed_start);
+        out.extend((changed_start..i).map(xor));
+    }
+    out
+}
+
+/// Rebuilds the `target` that [`delta`] was given.
+fn apply_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut out = base.to_vec();
+    out.resize(len, 0);
+
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+        for (byte, &x) in out[i..i + changed]
+            .iter_mut()
+            .zip(&delta[pos..pos + changed])
+        {
+            *byte ^= x;
+        }
+        i += changed;
+        pos += changed;
+    }
+    out
+}
+
+fn push_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= usize::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::model::Model;
+    use crate::test_rom;
+
+    fn round_trip(base: &[u8], target: &[u8]) -> Vec<u8> {
+        let encoded = delta(base, target);
+        assert_eq!(apply_delta(base, &encoded), target);
+        encoded
+    }
+
+    #[test]
+    fn deltas_rebuild_their_target() {
+        assert_eq!(round_trip(&[], &[]), [0]);
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(round_trip(&data, &data), [0x80, 0x02]);
+        let inverted: Vec<u8> = data.iter().map(|b| !b).collect();
+        let encoded = round_trip(&data, &inverted);
+        assert_eq!(encoded[..5], [0x80, 0x02, 0x00, 0x80, 0x02]);
+        assert!(encoded[5..].iter().all(|&x| x == 0xFF));
+
+        round_trip(&data[..10], &data);
+        round_trip(&data, &data[..10]);
+        round_trip(&[], &data);
+        round_trip(&data, &[]);
+    }
+
+    #[test]
+    fn short_gaps_stay_in_the_run() {
+        let base = [0; 12];
+        let target = [1, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0];
+        // One run over the first gap, a new one after the second.
+        assert_eq!(round_trip(&base, &target), [12, 0, 4, 1, 0, 0, 1, 3, 1, 1]);
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for &value in &[0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, usize::MAX] {
+            let mut out = Vec::new();
+            push_varint(&mut out, value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&out, &mut pos), value);
+            assert_eq!(pos, out.len());
+        }
+        let mut out = Vec::new();
+        push_varint(&mut out, 300);
+        assert_eq!(out, [0xAC, 0x02]);
+    }
+
+    /// Counts frames into WRAM, so every frame has its own state.
+    fn machine(title: &[u8]) -> GameBoy {
+        let code = [
+            0x76, // HALT
+            0x21, 0x00, 0xC0, // LD HL,0xC000
+            0x34, // INC (HL)
+            0x18, 0xF9, // JR -7
+        ];
+        let handler: &[u8] = &[0xD9]; // RETI
+        let patches: &[(usize, &[u8])] = &[(0x0040, handler), (0x0134, title)];
+        let mut rom = test_rom::build(0x00, 0x00, &code, patches);
+        // EI and enable the VBlank interrupt before the loop.
+        rom[0x0100..0x0104].copy_from_slice(&[0xFB, 0xC3, 0x50, 0x01]);
+        let mut gb = GameBoy::new(Model::Dmg, Cartridge::new(rom).unwrap());
+        gb.bus.write_byte(0xFFFF, 0x01);
+        gb
+    }
+
+    #[test]
+    fn step_back_replays_recorded_frames() {
+        let mut gb = machine(b"TEST");
+        let mut rewind = Rewind::new(4, usize::MAX);
+        let mut states = vec![gb.save_state()];
+        for frame in 0..10u8 {
+            gb.bus.set_pressed(frame);
+            gb.run_frame();
+            rewind.record(&gb);
+            states.push(gb.save_state());
+        }
+        assert_eq!(rewind.frames(), 9);
+
+        for frame in (1..10).rev() {
+            assert!(rewind.step_back(&mut gb));
+            assert_eq!(gb.save_state(), states[frame], "frame {}", frame);
+        }
+        assert!(!rewind.step_back(&mut gb));
+        assert_eq!(rewind.frames(), 0);
+        assert_eq!(rewind.memory_used(), rewind.snapshots[0].size());
+    }
+
+    #[test]
+    fn failed_loads_keep_the_history() {
+        let mut gb = machine(b"TEST");
+        let mut rewind = Rewind::new(2, usize::MAX);
+        for _ in 0..6 {
+            gb.run_frame();
+            rewind.record(&gb);
+        }
+        let used = rewind.memory_used();
+
+        let mut other = machine(b"ELSE");
+        let before = other.save_state();
+        assert!(!rewind.step_back(&mut other));
+        assert_eq!(other.save_state(), before);
+        assert_eq!((rewind.frames(), rewind.memory_used()), (5, used));
+        assert!(rewind.step_back(&mut gb));
+        assert_eq!(rewind.frames(), 4);
+    }
+}
@@ -0,0 +1,83 @@
+//! SB and SC.
+//!
+//! There is no link partner, so a transfer on the internal clock shifts in
+//! all ones and completes after 8 bit periods. Bytes sent are kept so that
+//! test ROMs reporting over the link port can be read back.
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Clocks per bit at 8192 Hz.
+const BIT_PERIOD: u32 = 512;
+
+#[derive(Clone, Debug, Default)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    /// Clocks left in the current transfer.
+    remaining: u32,
+    output: Vec<u8>,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial::default()
+    }
+
+    /// Advances one machine cycle. Returns true when the serial interrupt
+    /// should be requested.
+    pub fn tick(&mut self) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+        self.remaining -= 4;
+        if self.remaining > 0 {
+            return false;
+        }
+        self.sb = 0xFF;
+        self.sc &= 0x7F;
+        true
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            _ => 0x7E | self.sc,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF01 => self.sb = value,
+            _ => {
+                self.sc = value & 0x81;
+                if value & 0x81 == 0x81 {
+                    self.output.push(self.sb);
+                    self.remaining = 8 * BIT_PERIOD;
+                }
+            }
+        }
+    }
+
+    /// The bytes sent so far are a log for the host, not hardware state, and
+    /// are not part of it.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u32(self.remaining);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()? & 0x81;
+        self.remaining = r.u32()?;
+        if self.remaining % 4 != 0 || self.remaining > 8 * BIT_PERIOD {
+            return Err(StateError::Invalid("serial transfer"));
+        }
+        Ok(())
+    }
+
+    /// Everything sent over the link port so far.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+}
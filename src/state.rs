@@ -0,0 +1,356 @@
+//! The save state container and the primitives components use to fill it.
+//!
+//! A state starts with an 8-byte magic and a little-endian `u16` format
+//! version, followed by sections. Each section is a 4-byte tag, a `u32`
+//! payload length and the payload, which a single component writes and
+//! reads back field by field in a fixed order.
+//!
+//! Sections a build does not know are skipped. Changing the layout of a
+//! section, or adding one, needs a new version: [`VERSION`] goes up once
+//! per release that changes the format, however many changes that release
+//! makes, and [`migrate`] gets one step that rewrites the previous
+//! release's sections into the new layout, so states written by released
+//! builds keep loading.
+
+use std::fmt;
+
+use crate::hdma::Hdma;
+use crate::model::Model;
+use crate::ppu::Ppu;
+
+pub const MAGIC: [u8; 8] = *b"RUSTBOY\x1A";
+pub const VERSION: u16 = 2;
+
+pub type Tag = [u8; 4];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// Not a rustboy save state.
+    BadMagic,
+    /// Written by a newer build with a layout this one cannot read.
+    UnsupportedVersion(u16),
+    /// The data ends in the middle of a field or section.
+    Truncated,
+    /// A required section is missing.
+    MissingSection(Tag),
+    /// The state is for a different ROM.
+    WrongRom,
+    /// The state is for a different hardware model.
+    WrongModel,
+    /// A field holds a value the component cannot take.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} is newer than this build supports ({})",
+                version, VERSION
+            ),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::MissingSection(tag) => write!(
+                f,
+                "save state has no {} section",
+                String::from_utf8_lossy(tag).trim_end()
+            ),
+            StateError::WrongRom => write!(f, "save state is for a different ROM"),
+            StateError::WrongModel => write!(f, "save state is for a different model"),
+            StateError::Invalid(what) => write!(f, "save state has an invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Appends fields in little-endian order.
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// A fixed-size block, written as is.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// A variable-size block, preceded by its length.
+    pub fn block(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads fields back in the order a [`StateWriter`] wrote them.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Fills `out` from a fixed-size block.
+    pub fn bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    /// Fills `out` from a variable-size block, which must be exactly as long.
+    pub fn block(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        if self.u32()? as usize != out.len() {
+            return Err(StateError::Invalid("memory size"));
+        }
+        self.bytes(out)
+    }
+
+    /// Bytes left unread.
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Builds a complete state out of tagged sections.
+pub fn encode(sections: &[(Tag, Vec<u8>)]) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.bytes(&MAGIC);
+    w.u16(VERSION);
+    for (tag, payload) in sections {
+        w.bytes(tag);
+        w.block(payload);
+    }
+    w.into_inner()
+}
+
+/// Splits a state into its sections, migrated to the current version.
+pub fn decode(data: &[u8]) -> Result<Vec<(Tag, Vec<u8>)>, StateError> {
+    let mut r = StateReader::new(data);
+    let mut magic = [0; 8];
+    r.bytes(&mut magic).map_err(|_| StateError::BadMagic)?;
+    if magic != MAGIC {
+        return Err(StateError::BadMagic);
+    }
+    let version = r.u16()?;
+    if version > VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+
+    let mut sections = Vec::new();
+    while r.remaining() > 0 {
+        let mut tag = [0; 4];
+        r.bytes(&mut tag)?;
+        let len = r.u32()? as usize;
+        sections.push((tag, r.take(len)?.to_vec()));
+    }
+    migrate(version, &mut sections)?;
+    Ok(sections)
+}
+
+/// Rewrites sections written by `version` into the current layout.
+fn migrate(version: u16, sections: &mut Vec<(Tag, Vec<u8>)>) -> Result<(), StateError> {
+    // Version 0 was never written.
+    if version == 0 {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+    // Each layout change adds a step here, in order, of the form
+    // `if version < N { convert the sections to version N's layout }`.
+    if version < 2 {
+        split_lcd_from_bus(sections)?;
+    }
+    Ok(())
+}
+
+/// Version 2 moved VRAM, OAM and the LCD registers out of the bus into a
+/// PPU section of their own, and added the CGB hardware, OAM DMA timing
+/// and the boot ROM mapping. Version 1 had none of those, so they start as
+/// on power-up, and its PPU is rebuilt the way the registers were written:
+/// with the LCD on, it starts again from the top of the screen.
+fn split_lcd_from_bus(sections: &mut Vec<(Tag, Vec<u8>)>) -> Result<(), StateError> {
+    let old = match sections.iter_mut().find(|(tag, _)| tag == b"BUS ") {
+        Some((_, payload)) => payload,
+        None => return Ok(()),
+    };
+    let mut r = StateReader::new(old);
+    let (mut vram, mut wram, mut oam, mut hram, mut lcd) =
+        ([0; 0x2000], [0; 0x8000], [0; 0xA0], [0; 0x7F], [0; 0x0C]);
+    r.bytes(&mut vram)?;
+    r.bytes(&mut wram[..0x2000])?;
+    r.bytes(&mut oam)?;
+    r.bytes(&mut hram)?;
+    r.bytes(&mut lcd)?;
+    let (ie, int_flags) = (r.u8()?, r.u8()?);
+
+    // Version 1 only ever saved between instant OAM transfers, on a DMG.
+    let mut bus = StateWriter::new();
+    bus.bytes(&wram);
+    bus.bytes(&hram);
+    bus.u8(lcd[0x06]);
+    bus.bool(false);
+    bus.bool(false);
+    bus.u8(0xFF);
+    bus.u8(ie);
+    bus.u8(int_flags);
+    bus.u8(0);
+    Hdma::new().save_state(&mut bus);
+    bus.bool(false);
+    bus.bool(false);
+    bus.bool(false);
+    *old = bus.into_inner();
+
+    // With the LCD still off, nothing blocks VRAM and OAM writes.
+    let mut ppu = Ppu::new(Model::Dmg);
+    for (addr, &value) in (0x8000..).zip(&vram) {
+        ppu.write_vram(addr, value);
+    }
+    for (addr, &value) in (0xFE00..).zip(&oam) {
+        ppu.write_oam(addr, value);
+    }
+    // LCDC goes last, since it turns the LCD on.
+    for (addr, &value) in (0xFF40..).zip(&lcd).skip(1) {
+        ppu.write(addr, value);
+    }
+    ppu.write(0xFF40, lcd[0x00]);
+    let mut w = StateWriter::new();
+    ppu.save_state(&mut w);
+    sections.push((*b"PPU ", w.into_inner()));
+    Ok(())
+}
+
+/// The payload of the section tagged `tag`.
+pub fn section<'a>(sections: &'a [(Tag, Vec<u8>)], tag: &Tag) -> Option<&'a [u8]> {
+    sections
+        .iter()
+        .find(|(t, _)| t == tag)
+        .map(|(_, payload)| &payload[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::Cartridge;
+    use crate::test_rom;
+
+    /// Encodes `sections` as if `version` had written them.
+    fn encode_as(version: u16, sections: &[(Tag, Vec<u8>)]) -> Vec<u8> {
+        let mut data = encode(sections);
+        data[8..10].copy_from_slice(&version.to_le_bytes());
+        data
+    }
+
+    fn bus() -> Bus {
+        let rom = test_rom::build(0x00, 0x00, &[], &[]);
+        Bus::new(Model::Dmg, Cartridge::new(rom).unwrap())
+    }
+
+    /// Loads the BUS and PPU sections of `sections` into a fresh machine,
+    /// insisting that each is used up exactly.
+    fn load(sections: &[(Tag, Vec<u8>)]) -> Bus {
+        let mut bus = bus();
+        let mut r = StateReader::new(section(sections, b"BUS ").unwrap());
+        bus.load_state(&mut r).unwrap();
+        assert_eq!(r.remaining(), 0);
+        let mut r = StateReader::new(section(sections, b"PPU ").unwrap());
+        bus.ppu.load_state(&mut r).unwrap();
+        assert_eq!(r.remaining(), 0);
+        bus
+    }
+
+    #[test]
+    fn version_1_splits_the_lcd_out_of_the_bus() {
+        let mut old = vec![0; 0x2000 + 0x2000 + 0xA0 + 0x7F];
+        old[0x0010] = 0x11; // 0x8010
+        old[0x2000 + 0x0020] = 0x22; // 0xC020
+        old[0x4000 + 0x0003] = 0x33; // 0xFE03
+        old[0x40A0 + 0x0004] = 0x44; // 0xFF84
+
+        // LCDC through WX with the LCD off, then IE and IF.
+        old.extend(&[0x00, 0x40, 1, 2, 0, 0, 0xC1, 0xE4, 0xD0, 0xE0, 5, 6]);
+        old.extend(&[0x05, 0x01]);
+
+        let sections = decode(&encode_as(1, &[(*b"BUS ", old)])).unwrap();
+        let bus = load(&sections);
+        for (addr, value) in [
+            (0x8010, 0x11),
+            (0xC020, 0x22),
+            (0xFE03, 0x33),
+            (0xFF84, 0x44),
+            (0xFF42, 1),
+            (0xFF43, 2),
+            (0xFF46, 0xC1),
+            (0xFF47, 0xE4),
+            (0xFF4B, 6),
+            (0xFFFF, 0x05),
+            (0xFF0F, 0xE1),
+        ] {
+            assert_eq!(bus.read_byte(addr), value, "{:#06x}", addr);
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Builds small cartridge images for tests.
+
+use crate::cartridge::header::{global_checksum, header_checksum, NINTENDO_LOGO};
+
+/// Where [`build`] places the program.
+pub const CODE_START: usize = 0x0150;
+
+/// A 32 KiB image of `cartridge_type` with `ram_size_code` RAM and valid
+/// checksums. The entry point jumps to `code` at [`CODE_START`]; `patches`
+/// are written at their addresses afterwards, for interrupt handlers.
+pub fn build(
+    cartridge_type: u8,
+    ram_size_code: u8,
+    code: &[u8],
+    patches: &[(usize, &[u8])],
+) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    // NOP; JP 0x0150
+    rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x0134..0x0138].copy_from_slice(b"TEST");
+    rom[0x0147] = cartridge_type;
+    rom[0x0149] = ram_size_code;
+    rom[CODE_START..CODE_START + code.len()].copy_from_slice(code);
+    for (addr, bytes) in patches {
+        rom[*addr..*addr + bytes.len()].copy_from_slice(bytes);
+    }
+    rom[0x014D] = header_checksum(&rom);
+    let [high, low] = global_checksum(&rom).to_be_bytes();
+    rom[0x014E] = high;
+    rom[0x014F] = low;
+    rom
+}
@@ -0,0 +1,144 @@
+//! DIV, TIMA, TMA and TAC.
+//!
+//! DIV is the upper byte of a free-running 16-bit counter. TIMA counts the
+//! falling edges of one of the counter's bits, so resetting DIV or changing
+//! TAC can produce an extra increment, just as on hardware.
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Counter bit watched by TIMA for each TAC clock select.
+const TAC_BITS: [u16; 4] = [9, 3, 5, 7];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Reload {
+    Idle,
+    /// TIMA overflowed during the last cycle and reads as 0.
+    Pending,
+    /// TMA was copied into TIMA this cycle; writes to TIMA are ignored.
+    Done,
+}
+
+#[derive(Clone, Debug)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    reload: Reload,
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            reload: Reload::Idle,
+        }
+    }
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer::default()
+    }
+
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    /// Advances one machine cycle. Returns true when the timer interrupt
+    /// should be requested.
+    pub fn tick(&mut self) -> bool {
+        let mut interrupt = false;
+        match self.reload {
+            Reload::Pending => {
+                self.tima = self.tma;
+                self.reload = Reload::Done;
+                interrupt = true;
+            }
+            Reload::Done => self.reload = Reload::Idle,
+            Reload::Idle => {}
+        }
+
+        let before = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        if before && !self.signal() {
+            self.increment();
+        }
+        interrupt
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            _ => 0xF8 | self.tac,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        let before = self.signal();
+        match addr {
+            0xFF04 => self.counter = 0,
+            0xFF05 => {
+                if self.reload != Reload::Done {
+                    self.tima = value;
+                    self.reload = Reload::Idle;
+                }
+            }
+            0xFF06 => {
+                self.tma = value;
+                if self.reload == Reload::Done {
+                    self.tima = value;
+                }
+            }
+            _ => self.tac = value & 0x07,
+        }
+        if before && !self.signal() {
+            self.increment();
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.counter);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.u8(self.reload as u8);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.counter = r.u16()?;
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()? & 0x07;
+        self.reload = match r.u8()? {
+            0 => Reload::Idle,
+            1 => Reload::Pending,
+            2 => Reload::Done,
+            _ => return Err(StateError::Invalid("timer reload state")),
+        };
+        Ok(())
+    }
+
+    /// The AND of the enable bit and the selected counter bit.
+    fn signal(&self) -> bool {
+        let bit = TAC_BITS[(self.tac & 0x03) as usize];
+        self.tac & 0x04 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn increment(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.reload = Reload::Pending;
+        }
+    }
+}